@@ -0,0 +1,76 @@
+//Compare sendfile(2) against the buffered userspace copy over loopback TCP
+//Run with: cargo bench --bench sendfile
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hs::sendfile::{copy_file, send_file};
+
+const FILE_SIZE: u64 = 64 * 1024 * 1024;
+const ITERATIONS: u32 = 20;
+
+fn main() -> io::Result<()> {
+    let path = std::env::temp_dir().join(format!("hs-sendfile-bench-{}", std::process::id()));
+    {
+        let mut file = File::create(&path)?;
+        let chunk = vec![0xABu8; 1024 * 1024];
+        for _ in 0..FILE_SIZE / chunk.len() as u64 {
+            file.write_all(&chunk)?;
+        }
+    }
+    let file = File::open(&path)?;
+
+    let sendfile_time = run("sendfile", &file, |stream, file| send_file(stream, file, FILE_SIZE))?;
+    let copy_time = run("buffered copy", &file, |stream, file| copy_file(stream, file, 0, FILE_SIZE))?;
+
+    println!(
+        "sendfile is {:.2}x the throughput of buffered copy",
+        copy_time.as_secs_f64() / sendfile_time.as_secs_f64()
+    );
+
+    std::fs::remove_file(&path)
+}
+
+fn run<F>(name: &str, file: &File, send: F) -> io::Result<Duration>
+where
+    F: Fn(&mut TcpStream, &File) -> io::Result<()>,
+{
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+
+    // Drain everything the sender writes so the socket never backs up
+    let sink = thread::spawn(move || -> io::Result<u64> {
+        let (mut stream, _) = listener.accept()?;
+        let mut buf = vec![0u8; 256 * 1024];
+        let mut total = 0u64;
+        loop {
+            let n = stream.read(&mut buf)?;
+            if n == 0 {
+                return Ok(total);
+            }
+            total += n as u64;
+        }
+    });
+
+    let mut stream = TcpStream::connect(addr)?;
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        send(&mut stream, file)?;
+    }
+    drop(stream);
+    let received = sink.join().expect("sink thread panicked")?;
+    let elapsed = start.elapsed();
+
+    let megabytes = received as f64 / (1024.0 * 1024.0);
+    println!(
+        "{:>14}: {:.0} MiB in {:.3}s ({:.0} MiB/s)",
+        name,
+        megabytes,
+        elapsed.as_secs_f64(),
+        megabytes / elapsed.as_secs_f64()
+    );
+
+    Ok(elapsed)
+}
@@ -0,0 +1,112 @@
+//Compare the work-stealing ThreadPool against the old design where every worker
+//pulls from one `Arc<Mutex<mpsc::Receiver<Job>>>`
+//Run with: cargo bench --bench thread_pool
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hs::thread_pool::{PoolConfig, ThreadPool};
+
+const TASKS: usize = 200_000;
+const ROUNDS: u32 = 5;
+
+// The pool as it was before work stealing, kept here as the baseline
+mod mutex_pool {
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+
+    type Job = Box<dyn FnOnce() + Send + 'static>;
+
+    pub struct MutexPool {
+        workers: Vec<thread::JoinHandle<()>>,
+        sender: Option<mpsc::Sender<Job>>,
+    }
+
+    impl MutexPool {
+        pub fn new(size: usize) -> Self {
+            let (sender, receiver) = mpsc::channel::<Job>();
+            let receiver = Arc::new(Mutex::new(receiver));
+            let workers = (0..size)
+                .map(|_| {
+                    let receiver = Arc::clone(&receiver);
+                    thread::spawn(move || loop {
+                        let message = receiver.lock().unwrap().recv();
+                        match message {
+                            Ok(job) => job(),
+                            Err(_) => break,
+                        }
+                    })
+                })
+                .collect();
+            MutexPool { workers, sender: Some(sender) }
+        }
+
+        pub fn execute<F: FnOnce() + Send + 'static>(&self, f: F) {
+            self.sender.as_ref().unwrap().send(Box::new(f)).unwrap();
+        }
+    }
+
+    impl Drop for MutexPool {
+        fn drop(&mut self) {
+            drop(self.sender.take());
+            for worker in self.workers.drain(..) {
+                let _ = worker.join();
+            }
+        }
+    }
+}
+
+// A short CPU-bound job, roughly the size of routing a small request
+fn job(done: &AtomicUsize) {
+    let mut x = 0u64;
+    for i in 0..200 {
+        x = black_box(x.wrapping_mul(31).wrapping_add(i));
+    }
+    black_box(x);
+    done.fetch_add(1, Ordering::Release);
+}
+
+fn wait_for(done: &AtomicUsize, target: usize) {
+    while done.load(Ordering::Acquire) < target {
+        thread::yield_now();
+    }
+}
+
+fn run<S: Fn(Arc<AtomicUsize>)>(submit: S) -> Duration {
+    let mut best = Duration::MAX;
+    for _ in 0..ROUNDS {
+        let done = Arc::new(AtomicUsize::new(0));
+        let started = Instant::now();
+        for _ in 0..TASKS {
+            submit(Arc::clone(&done));
+        }
+        wait_for(&done, TASKS);
+        best = best.min(started.elapsed());
+    }
+    best
+}
+
+fn main() {
+    println!("{} tasks, best of {} rounds", TASKS, ROUNDS);
+    for workers in [4, 16, 64] {
+        let pool = ThreadPool::with_config(PoolConfig::new().with_workers(workers).with_queue_capacity(TASKS));
+        let stealing = run(|done| {
+            pool.execute(move || job(&done)).unwrap();
+        });
+        drop(pool);
+
+        let pool = mutex_pool::MutexPool::new(workers);
+        let mutex = run(|done| pool.execute(move || job(&done)));
+        drop(pool);
+
+        println!(
+            "{:>3} workers: work-stealing {:>8.0} tasks/s, mutex {:>8.0} tasks/s ({:.2}x)",
+            workers,
+            TASKS as f64 / stealing.as_secs_f64(),
+            TASKS as f64 / mutex.as_secs_f64(),
+            mutex.as_secs_f64() / stealing.as_secs_f64()
+        );
+    }
+}
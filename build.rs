@@ -0,0 +1,66 @@
+//Embed a directory of static assets into the binary
+//Walks HS_EMBED_DIR (default `public/`) and generates a sorted table of
+//`include_bytes!` entries that `hs::embed::ASSETS` includes at compile time.
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=HS_EMBED_DIR");
+
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let embed_dir = manifest_dir.join(env::var("HS_EMBED_DIR").unwrap_or_else(|_| "public".to_string()));
+    println!("cargo:rerun-if-changed={}", embed_dir.display());
+
+    let mut files = Vec::new();
+    if embed_dir.is_dir() {
+        collect_files(&embed_dir, &embed_dir, &mut files).expect("failed to read embed directory");
+    }
+    files.sort();
+
+    let mut generated = String::from("&[\n");
+    for (relative, absolute) in &files {
+        let modified = fs::metadata(absolute)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+        generated.push_str(&format!(
+            "    EmbeddedFile {{ path: {:?}, contents: include_bytes!({:?}), modified: {} }},\n",
+            relative,
+            absolute.display().to_string(),
+            modified
+        ));
+    }
+    generated.push_str("]\n");
+
+    let out_path = PathBuf::from(env::var("OUT_DIR").unwrap()).join("embedded_assets.rs");
+    fs::write(out_path, generated).expect("failed to write embedded asset table");
+}
+
+// Regular files below `dir`, skipping dotfiles, as (URL path, absolute path)
+fn collect_files(root: &Path, dir: &Path, files: &mut Vec<(String, PathBuf)>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        println!("cargo:rerun-if-changed={}", path.display());
+        if path.is_dir() {
+            collect_files(root, &path, files)?;
+        } else if path.is_file() {
+            let relative = path
+                .strip_prefix(root)
+                .unwrap()
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().to_string())
+                .collect::<Vec<_>>()
+                .join("/");
+            files.push((relative, path.canonicalize()?));
+        }
+    }
+    Ok(())
+}
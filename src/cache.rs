@@ -0,0 +1,374 @@
+//In-memory static asset cache
+//Hot static files are kept in memory together with their precomputed headers,
+//so a hit serves the body without reading the file; the request path is still
+//resolved and checked on disk first. Bodies are shared with the responses rather
+//than copied into them. Entries are revalidated against the file's mtime and size,
+//and the least recently used ones are evicted past a byte budget.
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::compression::Encoding;
+
+pub struct CachedAsset {
+    pub body: Arc<[u8]>,
+    pub content_type: String,
+    pub etag: String,
+    pub last_modified: String,
+    modified: Option<SystemTime>,
+    len: u64,
+    checked_at: Mutex<Instant>,
+    last_used: AtomicU64,
+    // None records that compressing didn't pay off, so it isn't retried
+    variants: Mutex<HashMap<Encoding, Option<Arc<[u8]>>>>,
+}
+
+impl CachedAsset {
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+struct CacheInner {
+    entries: HashMap<PathBuf, Arc<CachedAsset>>,
+    total_bytes: u64,
+}
+
+pub struct StaticCache {
+    max_bytes: u64,
+    max_entry_bytes: u64,
+    revalidate_after: Duration,
+    clock: AtomicU64,
+    inner: Mutex<CacheInner>,
+}
+
+impl StaticCache {
+    pub fn new(max_bytes: u64) -> Self {
+        StaticCache {
+            max_bytes,
+            max_entry_bytes: (max_bytes / 8).max(1),
+            revalidate_after: Duration::from_secs(1),
+            clock: AtomicU64::new(0),
+            inner: Mutex::new(CacheInner {
+                entries: HashMap::new(),
+                total_bytes: 0,
+            }),
+        }
+    }
+
+    // Files larger than this are streamed from disk instead
+    pub fn with_max_entry_size(mut self, bytes: u64) -> Self {
+        self.max_entry_bytes = bytes;
+        self
+    }
+
+    // How long an entry is trusted before its mtime is checked again
+    pub fn with_revalidate_after(mut self, interval: Duration) -> Self {
+        self.revalidate_after = interval;
+        self
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.inner.lock().unwrap().total_bytes
+    }
+
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.entries.clear();
+        inner.total_bytes = 0;
+    }
+
+    // Cached copy of `path`, loading it on a miss; None if it's too large or unreadable
+    pub fn get<F>(&self, path: &Path, content_type: F) -> Option<Arc<CachedAsset>>
+    where
+        F: FnOnce(&Path, &[u8]) -> String,
+    {
+        let tick = self.clock.fetch_add(1, Ordering::Relaxed);
+
+        let cached = self.inner.lock().unwrap().entries.get(path).cloned();
+        if let Some(asset) = cached {
+            if self.is_fresh(path, &asset) {
+                asset.last_used.store(tick, Ordering::Relaxed);
+                return Some(asset);
+            }
+            self.remove(path);
+        }
+
+        let metadata = fs::metadata(path).ok()?;
+        if !metadata.is_file() || metadata.len() > self.max_entry_bytes {
+            return None;
+        }
+        let body = fs::read(path).ok()?;
+        let modified = metadata.modified().ok();
+        let asset = Arc::new(CachedAsset {
+            content_type: content_type(path, &body),
+            etag: entity_tag(modified, body.len() as u64),
+            last_modified: modified.map(format_http_date).unwrap_or_default(),
+            modified,
+            len: body.len() as u64,
+            body: Arc::from(body),
+            checked_at: Mutex::new(Instant::now()),
+            last_used: AtomicU64::new(tick),
+            variants: Mutex::new(HashMap::new()),
+        });
+
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(previous) = inner.entries.insert(path.to_path_buf(), Arc::clone(&asset)) {
+            inner.total_bytes = inner.total_bytes.saturating_sub(footprint(&previous));
+        }
+        inner.total_bytes += asset.len;
+        self.evict(&mut inner, path);
+        Some(asset)
+    }
+
+    // Compressed representation of `asset`, produced once by `load` and then kept
+    pub fn variant<F>(&self, path: &Path, asset: &CachedAsset, encoding: Encoding, load: F) -> Option<Arc<[u8]>>
+    where
+        F: FnOnce() -> Option<Vec<u8>>,
+    {
+        if let Some(variant) = asset.variants.lock().unwrap().get(&encoding) {
+            return variant.clone();
+        }
+
+        let variant = load().filter(|bytes| bytes.len() < asset.body.len()).map(Arc::from);
+        let added = match asset.variants.lock().unwrap().entry(encoding) {
+            Entry::Occupied(_) => 0,
+            Entry::Vacant(slot) => {
+                slot.insert(variant.clone());
+                variant.as_ref().map_or(0, |v| v.len() as u64)
+            }
+        };
+
+        let mut inner = self.inner.lock().unwrap();
+        // Only account for it if the asset is still the cached one
+        if inner.entries.get(path).is_some_and(|cached| std::ptr::eq(cached.as_ref(), asset)) {
+            inner.total_bytes += added;
+            self.evict(&mut inner, path);
+        }
+        variant
+    }
+
+    fn is_fresh(&self, path: &Path, asset: &CachedAsset) -> bool {
+        let mut checked_at = asset.checked_at.lock().unwrap();
+        if checked_at.elapsed() < self.revalidate_after {
+            return true;
+        }
+        match fs::metadata(path) {
+            Ok(metadata) if metadata.len() == asset.len && metadata.modified().ok() == asset.modified => {
+                *checked_at = Instant::now();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn remove(&self, path: &Path) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(asset) = inner.entries.remove(path) {
+            inner.total_bytes = inner.total_bytes.saturating_sub(footprint(&asset));
+        }
+    }
+
+    // Drop least recently used entries until the budget holds, keeping `keep`
+    fn evict(&self, inner: &mut CacheInner, keep: &Path) {
+        while inner.total_bytes > self.max_bytes {
+            let victim = inner
+                .entries
+                .iter()
+                .filter(|(path, _)| path.as_path() != keep)
+                .min_by_key(|(_, asset)| asset.last_used.load(Ordering::Relaxed))
+                .map(|(path, _)| path.clone());
+            match victim {
+                Some(path) => {
+                    if let Some(asset) = inner.entries.remove(&path) {
+                        inner.total_bytes = inner.total_bytes.saturating_sub(footprint(&asset));
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+fn footprint(asset: &CachedAsset) -> u64 {
+    let variants: u64 = asset
+        .variants
+        .lock()
+        .unwrap()
+        .values()
+        .flatten()
+        .map(|v| v.len() as u64)
+        .sum();
+    asset.len + variants
+}
+
+// Validator derived from mtime and size, the same scheme used for uncached files
+pub fn entity_tag(modified: Option<SystemTime>, len: u64) -> String {
+    let secs = modified
+        .and_then(|m| m.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs());
+    format!("\"{:x}-{:x}\"", secs, len)
+}
+
+// IMF-fixdate as used by Last-Modified, e.g. Sun, 06 Nov 1994 08:49:37 GMT
+pub fn format_http_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let days = secs / 86_400;
+    let (year, month, day) = crate::static_files::civil_from_days(days as i64);
+    let rem = secs % 86_400;
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::sync::atomic::AtomicUsize;
+
+    // A scratch directory of files, removed on drop
+    struct Dir(PathBuf);
+
+    impl Dir {
+        fn new() -> Dir {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!(
+                "hs-cache-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ));
+            fs::create_dir_all(&dir).unwrap();
+            Dir(dir)
+        }
+
+        fn write(&self, name: &str, contents: &[u8]) -> PathBuf {
+            let path = self.0.join(name);
+            fs::write(&path, contents).unwrap();
+            path
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn get(cache: &StaticCache, path: &Path) -> Option<Arc<CachedAsset>> {
+        cache.get(path, |_, _| "text/plain".to_string())
+    }
+
+    fn cached(cache: &StaticCache, path: &Path) -> bool {
+        cache.inner.lock().unwrap().entries.contains_key(path)
+    }
+
+    #[test]
+    fn hits_share_the_cached_body() {
+        let dir = Dir::new();
+        let path = dir.write("a.txt", b"hello");
+        let cache = StaticCache::new(1024);
+        let first = get(&cache, &path).unwrap();
+        let second = get(&cache, &path).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(&*second.body, b"hello");
+        assert_eq!(cache.total_bytes(), 5);
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted_past_the_budget() {
+        let dir = Dir::new();
+        let (a, b, c) = (dir.write("a", &[b'a'; 40]), dir.write("b", &[b'b'; 40]), dir.write("c", &[b'c'; 40]));
+        let cache = StaticCache::new(100).with_max_entry_size(100);
+        get(&cache, &a).unwrap();
+        get(&cache, &b).unwrap();
+        // a is now more recent than b
+        get(&cache, &a).unwrap();
+        get(&cache, &c).unwrap();
+        assert!(cached(&cache, &a) && !cached(&cache, &b) && cached(&cache, &c));
+        assert_eq!(cache.total_bytes(), 80);
+    }
+
+    #[test]
+    fn variants_count_against_the_budget() {
+        let dir = Dir::new();
+        let (a, b) = (dir.write("a", &[b'a'; 40]), dir.write("b", &[b'b'; 40]));
+        let cache = StaticCache::new(100).with_max_entry_size(100);
+        get(&cache, &a).unwrap();
+        let asset = get(&cache, &b).unwrap();
+        let variant = cache.variant(&b, &asset, Encoding::Gzip, || Some(vec![0; 30])).unwrap();
+        assert_eq!(variant.len(), 30);
+        // 40 + 40 + 30 is over budget, so a goes
+        assert!(!cached(&cache, &a) && cached(&cache, &b));
+        assert_eq!(cache.total_bytes(), 70);
+        // Produced once, then served from memory
+        let again = cache.variant(&b, &asset, Encoding::Gzip, || panic!("compressed twice")).unwrap();
+        assert!(Arc::ptr_eq(&variant, &again));
+        // A variant that doesn't save anything isn't kept
+        assert!(cache.variant(&b, &asset, Encoding::Deflate, || Some(vec![0; 40])).is_none());
+        assert_eq!(cache.total_bytes(), 70);
+    }
+
+    #[test]
+    fn oversized_files_are_not_cached() {
+        let dir = Dir::new();
+        let path = dir.write("big", &[0; 64]);
+        let cache = StaticCache::new(1024).with_max_entry_size(63);
+        assert!(get(&cache, &path).is_none());
+        assert_eq!(cache.total_bytes(), 0);
+    }
+
+    #[test]
+    fn changed_files_are_reloaded() {
+        let dir = Dir::new();
+        let path = dir.write("a.txt", b"one");
+        let cache = StaticCache::new(1024).with_revalidate_after(Duration::ZERO);
+        let first = get(&cache, &path).unwrap();
+
+        // A new size
+        fs::write(&path, b"three").unwrap();
+        let second = get(&cache, &path).unwrap();
+        assert_eq!(&*second.body, b"three");
+        assert_eq!(cache.total_bytes(), 5);
+        assert_ne!(first.etag, second.etag);
+
+        // The same size, but a new mtime
+        fs::write(&path, b"eerht").unwrap();
+        let modified = second.modified.unwrap() + Duration::from_secs(10);
+        File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+        let third = get(&cache, &path).unwrap();
+        assert_eq!(&*third.body, b"eerht");
+        assert_eq!(cache.total_bytes(), 5);
+    }
+
+    #[test]
+    fn entries_are_trusted_until_revalidation_is_due() {
+        let dir = Dir::new();
+        let path = dir.write("a.txt", b"one");
+        let cache = StaticCache::new(1024).with_revalidate_after(Duration::from_secs(60));
+        get(&cache, &path).unwrap();
+        fs::write(&path, b"three").unwrap();
+        assert_eq!(&*get(&cache, &path).unwrap().body, b"one");
+    }
+}
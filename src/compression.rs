@@ -0,0 +1,322 @@
+//Response compression
+//Negotiates Accept-Encoding and compresses textual responses on the fly.
+//Static mounts additionally look for precompressed `.br` / `.gz` siblings of the requested file.
+use std::io::{self, Write};
+
+use crate::log_warn;
+use crate::types::{if_none_match, not_modified, Request, Response};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Brotli,
+    #[cfg(feature = "zstd")]
+    Zstd,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    // Server preference when the client rates several encodings equally
+    pub const ALL: &'static [Encoding] = &[
+        Encoding::Brotli,
+        #[cfg(feature = "zstd")]
+        Encoding::Zstd,
+        Encoding::Gzip,
+        Encoding::Deflate,
+    ];
+
+    pub fn token(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    // File suffix used for precompressed static assets
+    pub fn file_extension(self) -> Option<&'static str> {
+        match self {
+            Encoding::Brotli => Some("br"),
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => Some("zst"),
+            Encoding::Gzip => Some("gz"),
+            Encoding::Deflate => None,
+        }
+    }
+
+    pub fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut output = Vec::new();
+                {
+                    let mut writer = brotli::CompressorWriter::new(&mut output, 4096, 5, 22);
+                    writer.write_all(data)?;
+                }
+                Ok(output)
+            }
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => zstd::encode_all(data, 3),
+            Encoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Encoding::Deflate => {
+                // HTTP "deflate" is the zlib format, not raw deflate
+                let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CompressionConfig {
+    min_size: u64,
+    max_size: u64,
+    encodings: Vec<Encoding>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CompressionConfig {
+    pub fn new() -> Self {
+        CompressionConfig {
+            min_size: 1024,
+            max_size: 8 * 1024 * 1024,
+            encodings: Encoding::ALL.to_vec(),
+        }
+    }
+
+    // Bodies smaller than this aren't worth the CPU or the header overhead
+    pub fn with_min_size(mut self, bytes: u64) -> Self {
+        self.min_size = bytes;
+        self
+    }
+
+    // Larger file bodies keep streaming uncompressed rather than being read into memory
+    pub fn with_max_size(mut self, bytes: u64) -> Self {
+        self.max_size = bytes;
+        self
+    }
+
+    pub fn with_encodings(mut self, encodings: &[Encoding]) -> Self {
+        self.encodings = encodings.to_vec();
+        self
+    }
+
+    pub fn min_size(&self) -> u64 {
+        self.min_size
+    }
+
+    pub fn encodings(&self) -> &[Encoding] {
+        &self.encodings
+    }
+}
+
+// Pick the encoding the client rates highest among `available`, if any is acceptable
+pub fn negotiate(accept_encoding: &str, available: &[Encoding]) -> Option<Encoding> {
+    let mut wildcard = None;
+    let mut ratings: Vec<(&str, f32)> = Vec::new();
+    for part in accept_encoding.split(',') {
+        let mut pieces = part.split(';');
+        let token = pieces.next().unwrap_or("").trim();
+        if token.is_empty() {
+            continue;
+        }
+        let mut quality = 1.0;
+        for param in pieces {
+            if let Some(value) = param.trim().strip_prefix("q=") {
+                quality = value.trim().parse().unwrap_or(0.0);
+            }
+        }
+        if token == "*" {
+            wildcard = Some(quality);
+        } else {
+            ratings.push((token, quality));
+        }
+    }
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for &encoding in available {
+        let quality = ratings
+            .iter()
+            .find(|(token, _)| token.eq_ignore_ascii_case(encoding.token())
+                || (encoding == Encoding::Gzip && token.eq_ignore_ascii_case("x-gzip")))
+            .map(|&(_, q)| q)
+            .or(wildcard)
+            .unwrap_or(0.0);
+        if quality > 0.0 && best.is_none_or(|(_, q)| quality > q) {
+            best = Some((encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+pub fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/javascript"
+                | "application/json"
+                | "application/xml"
+                | "application/wasm"
+                | "image/svg+xml"
+                | "image/x-icon"
+        )
+}
+
+// Note on the response that its representation depends on Accept-Encoding
+pub fn add_vary(response: &mut Response) {
+    match response.headers.get_mut("Vary") {
+        Some(vary) => {
+            if !vary.split(',').any(|v| v.trim().eq_ignore_ascii_case("accept-encoding")) {
+                vary.push_str(", Accept-Encoding");
+            }
+        }
+        None => {
+            response.headers.insert("Vary".to_string(), "Accept-Encoding".to_string());
+        }
+    }
+}
+
+// Each encoded representation needs its own validator
+pub fn variant_tag(etag: &str, encoding: Encoding) -> String {
+    format!("{}-{}\"", etag.trim_end_matches('"'), encoding.token())
+}
+
+// Compress the response body in place when the client and content type allow it
+pub fn compress_response(request: &Request, response: &mut Response, config: &CompressionConfig) {
+    if response.headers.contains_key("Content-Encoding")
+        || matches!(response.status, 204 | 206 | 304)
+        || request.method == "HEAD"
+    {
+        return;
+    }
+
+    let compressible = response
+        .headers
+        .get("Content-Type")
+        .is_some_and(|content_type| is_compressible(content_type));
+    if !compressible {
+        return;
+    }
+    add_vary(response);
+
+    let len = response.content_length();
+    if len < config.min_size || (response.file.is_some() && len > config.max_size) {
+        return;
+    }
+
+    let encoding = match request
+        .header("Accept-Encoding")
+        .and_then(|accept| negotiate(accept, &config.encodings))
+    {
+        Some(encoding) => encoding,
+        None => return,
+    };
+
+    // The client may already hold this compressed variant; the handler only
+    // compared If-None-Match against the uncompressed tag
+    if response.status == 200 {
+        if let Some(tag) = response.headers.get("ETag").map(|etag| variant_tag(etag, encoding)) {
+            if if_none_match(request, &tag) {
+                response.headers.insert("ETag".to_string(), tag);
+                *response = not_modified(request, std::mem::take(response));
+                return;
+            }
+        }
+    }
+
+    let body = match response.file.take() {
+        Some(file) => {
+            let mut body = Vec::with_capacity(file.len as usize);
+            if let Err(e) = crate::sendfile::copy_file(&mut body, &file.file, 0, file.len) {
+                log_warn!("Error reading file for compression: {}", e);
+                response.file = Some(file);
+                return;
+            }
+            body
+        }
+        None => match response.shared.take() {
+            Some(shared) => shared.to_vec(),
+            None => std::mem::take(&mut response.body),
+        },
+    };
+
+    match encoding.compress(&body) {
+        // Only switch representations when it actually saves bytes
+        Ok(compressed) if compressed.len() < body.len() => {
+            response.body = compressed;
+            response.headers.remove("Content-Length");
+            if let Some(etag) = response.headers.get_mut("ETag") {
+                *etag = variant_tag(etag, encoding);
+            }
+            response.headers.insert("Content-Encoding".to_string(), encoding.token().to_string());
+        }
+        Ok(_) => response.body = body,
+        Err(e) => {
+            log_warn!("Error compressing response with {}: {}", encoding.token(), e);
+            response.body = body;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&str, &str)]) -> Request {
+        Request {
+            method: "GET".to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers: headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            ..Request::default()
+        }
+    }
+
+    fn page() -> Response {
+        Response::new()
+            .with_header("Content-Type", "text/html")
+            .with_header("ETag", "\"abc\"")
+            .with_body(&"<p>hello</p>".repeat(200))
+    }
+
+    #[test]
+    fn compressed_variant_gets_its_own_tag() {
+        let mut response = page();
+        compress_response(&request(&[("Accept-Encoding", "gzip")]), &mut response, &CompressionConfig::new());
+        assert_eq!(response.headers.get("Content-Encoding").map(String::as_str), Some("gzip"));
+        assert_eq!(response.headers.get("ETag").map(String::as_str), Some("\"abc-gzip\""));
+    }
+
+    #[test]
+    fn revalidating_the_compressed_variant_is_not_modified() {
+        let request = request(&[("Accept-Encoding", "gzip"), ("If-None-Match", "W/\"abc-gzip\"")]);
+        let mut response = page();
+        compress_response(&request, &mut response, &CompressionConfig::new());
+        assert_eq!(response.status, 304);
+        assert!(response.body.is_empty());
+        assert_eq!(response.headers.get("ETag").map(String::as_str), Some("\"abc-gzip\""));
+        assert_eq!(response.headers.get("Vary").map(String::as_str), Some("Accept-Encoding"));
+    }
+
+    #[test]
+    fn a_tag_for_another_encoding_still_gets_the_body() {
+        let request = request(&[("Accept-Encoding", "gzip"), ("If-None-Match", "\"abc-br\"")]);
+        let mut response = page();
+        compress_response(&request, &mut response, &CompressionConfig::new());
+        assert_eq!(response.status, 200);
+        assert_eq!(response.headers.get("ETag").map(String::as_str), Some("\"abc-gzip\""));
+    }
+}
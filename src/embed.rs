@@ -0,0 +1,160 @@
+//Static assets compiled into the binary
+//build.rs embeds HS_EMBED_DIR (default `public/`) as `ASSETS`, which
+//`Router::serve_embedded` serves like a `serve_static` mount, minus the disk.
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::cache::{entity_tag, format_http_date};
+use crate::compression::{self, variant_tag, Encoding};
+use crate::error::{HandlerResult, HsError};
+use crate::mime::MimeRegistry;
+use crate::static_files::percent_decode;
+use crate::types::{not_modified, Request, Response};
+
+pub struct EmbeddedFile {
+    // Path relative to the embedded directory, `/`-separated
+    pub path: &'static str,
+    pub contents: &'static [u8],
+    // Seconds since the Unix epoch when the file was embedded
+    pub modified: u64,
+}
+
+// Sorted by path
+pub static ASSETS: &[EmbeddedFile] = include!(concat!(env!("OUT_DIR"), "/embedded_assets.rs"));
+
+pub fn find(files: &'static [EmbeddedFile], path: &str) -> Option<&'static EmbeddedFile> {
+    files
+        .binary_search_by(|file| file.path.cmp(path))
+        .ok()
+        .map(|index| &files[index])
+}
+
+fn is_dir(files: &'static [EmbeddedFile], path: &str) -> bool {
+    let prefix = format!("{}/", path);
+    let start = files.partition_point(|file| file.path < prefix.as_str());
+    files.get(start).is_some_and(|file| file.path.starts_with(&prefix))
+}
+
+pub(crate) struct EmbeddedMount {
+    url_prefix: String,
+    files: &'static [EmbeddedFile],
+}
+
+impl EmbeddedMount {
+    pub(crate) fn new(url_prefix: &str, files: &'static [EmbeddedFile]) -> Self {
+        EmbeddedMount {
+            url_prefix: url_prefix.trim_end_matches('/').to_string(),
+            files,
+        }
+    }
+
+    pub(crate) fn url_prefix(&self) -> &str {
+        &self.url_prefix
+    }
+
+    pub(crate) fn serve(&self, request: &Request, mime: &MimeRegistry) -> Option<HandlerResult> {
+        let path = request.path.split('?').next().unwrap_or("");
+        let rest = path.strip_prefix(self.url_prefix.as_str())?;
+        if !rest.is_empty() && !rest.starts_with('/') {
+            return None;
+        }
+
+        let decoded = match percent_decode(rest) {
+            Some(decoded) => decoded,
+            None => return Some(Err(HsError::BadRequest("Malformed path.".to_string()))),
+        };
+        let relative = decoded
+            .split('/')
+            .filter(|segment| !segment.is_empty() && *segment != ".")
+            .collect::<Vec<_>>()
+            .join("/");
+
+        if let Some(file) = find(self.files, &relative) {
+            return Some(Ok(not_modified(request, self.respond(request, &relative, file, mime))));
+        }
+
+        if relative.is_empty() || is_dir(self.files, &relative) {
+            // Same trailing-slash and index.html handling as disk mounts
+            if !path.ends_with('/') {
+                let location = match request.path.split_once('?') {
+                    Some((_, query)) => format!("{}/?{}", path, query),
+                    None => format!("{}/", path),
+                };
+                return Some(Ok(Response::new().with_status(301).with_header("Location", &location)));
+            }
+            let index = if relative.is_empty() {
+                "index.html".to_string()
+            } else {
+                format!("{}/index.html", relative)
+            };
+            let file = find(self.files, &index)?;
+            return Some(Ok(not_modified(request, self.respond(request, &index, file, mime))));
+        }
+
+        None
+    }
+
+    fn respond(&self, request: &Request, relative: &str, file: &EmbeddedFile, mime: &MimeRegistry) -> Response {
+        let name = std::path::Path::new(relative);
+        let modified = UNIX_EPOCH + Duration::from_secs(file.modified);
+        let etag = entity_tag(Some(modified), file.contents.len() as u64);
+
+        let mut response = Response::new()
+            .with_header("Content-Type", &mime.content_type_for_bytes(name, file.contents))
+            .with_header("Last-Modified", &format_http_date(modified));
+
+        // Prefer precompressed siblings that were embedded alongside the file
+        let siblings: Vec<(Encoding, &EmbeddedFile)> = Encoding::ALL
+            .iter()
+            .filter_map(|&encoding| {
+                let sibling = find(self.files, &format!("{}.{}", relative, encoding.file_extension()?))?;
+                Some((encoding, sibling))
+            })
+            .collect();
+        let available: Vec<Encoding> = siblings.iter().map(|(encoding, _)| *encoding).collect();
+        let chosen = request
+            .header("Accept-Encoding")
+            .and_then(|accept| compression::negotiate(accept, &available))
+            .and_then(|encoding| siblings.iter().find(|(e, _)| *e == encoding));
+
+        match chosen {
+            Some((encoding, sibling)) => {
+                response = response
+                    .with_header("Content-Encoding", encoding.token())
+                    .with_header("ETag", &variant_tag(&etag, *encoding))
+                    .with_body_bytes(sibling.contents.to_vec());
+            }
+            // Anything else is left to the router's compression layer
+            None => response = response.with_header("ETag", &etag).with_body_bytes(file.contents.to_vec()),
+        }
+        if !siblings.is_empty() {
+            compression::add_vary(&mut response);
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static FILES: &[EmbeddedFile] = &[
+        EmbeddedFile { path: "docs/index.html", contents: b"<p>docs</p>", modified: 0 },
+        EmbeddedFile { path: "index.html", contents: b"<p>home</p>", modified: 0 },
+    ];
+
+    fn get(mount: &EmbeddedMount, path: &str) -> Response {
+        let request = Request { method: "GET".to_string(), path: path.to_string(), ..Request::default() };
+        mount.serve(&request, &MimeRegistry::new()).expect("matched").expect("served")
+    }
+
+    #[test]
+    fn directory_redirect_keeps_the_query() {
+        let mount = EmbeddedMount::new("/assets", FILES);
+        let response = get(&mount, "/assets/docs?x=1");
+        assert_eq!(response.status, 301);
+        assert_eq!(response.headers["Location"], "/assets/docs/?x=1");
+
+        assert_eq!(get(&mount, "/assets/docs").headers["Location"], "/assets/docs/");
+        assert_eq!(get(&mount, "/assets/docs/?x=1").bytes(), b"<p>docs</p>");
+    }
+}
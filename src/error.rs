@@ -0,0 +1,158 @@
+//Handler errors
+//Handlers return `HandlerResult` (or anything `IntoResponse`) and bail out with `?`;
+//the router turns the error into an HTML or JSON error response depending on `Accept`.
+use std::fmt;
+use std::io;
+
+use crate::logging::json_string;
+use crate::static_files::escape_html;
+use crate::types::{reason_phrase, Request, Response};
+
+#[derive(Debug)]
+pub enum HsError {
+    NotFound,
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    // The message is logged, never shown to the client
+    Internal(String),
+    // Any other error status, e.g. 405 or 503
+    Status(u16, String),
+}
+
+pub type HandlerResult = Result<Response, HsError>;
+
+impl HsError {
+    pub fn status(&self) -> u16 {
+        match self {
+            HsError::NotFound => 404,
+            HsError::BadRequest(_) => 400,
+            HsError::Unauthorized(_) => 401,
+            HsError::Forbidden(_) => 403,
+            HsError::Internal(_) => 500,
+            HsError::Status(status, _) => *status,
+        }
+    }
+
+    // Text that is safe to show to the client
+    pub fn public_message(&self) -> &str {
+        match self {
+            HsError::NotFound => "The requested resource could not be found.",
+            HsError::Internal(_) => "The server encountered an internal error.",
+            HsError::BadRequest(message)
+            | HsError::Unauthorized(message)
+            | HsError::Forbidden(message)
+            | HsError::Status(_, message) => message,
+        }
+    }
+}
+
+impl fmt::Display for HsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HsError::Internal(message) => write!(f, "500 {}: {}", reason_phrase(500), message),
+            _ => write!(f, "{} {}: {}", self.status(), reason_phrase(self.status()), self.public_message()),
+        }
+    }
+}
+
+impl std::error::Error for HsError {}
+
+impl From<io::Error> for HsError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => HsError::NotFound,
+            io::ErrorKind::PermissionDenied => HsError::Forbidden("Access to this resource is denied.".to_string()),
+            _ => HsError::Internal(e.to_string()),
+        }
+    }
+}
+
+// Anything a handler may return; plain `Response` handlers keep working unchanged
+pub trait IntoResponse {
+    fn into_response(self) -> HandlerResult;
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> HandlerResult {
+        Ok(self)
+    }
+}
+
+impl IntoResponse for HsError {
+    fn into_response(self) -> HandlerResult {
+        Err(self)
+    }
+}
+
+impl IntoResponse for &str {
+    fn into_response(self) -> HandlerResult {
+        Ok(Response::text(self))
+    }
+}
+
+impl IntoResponse for String {
+    fn into_response(self) -> HandlerResult {
+        Ok(Response::text(&self))
+    }
+}
+
+impl<T: IntoResponse, E: Into<HsError>> IntoResponse for Result<T, E> {
+    fn into_response(self) -> HandlerResult {
+        self.map_err(Into::into).and_then(IntoResponse::into_response)
+    }
+}
+
+// Turns an error into the response sent to the client
+pub type ErrorRenderer = std::sync::Arc<dyn Fn(&Request, &HsError) -> Response + Send + Sync>;
+
+// Default renderer: JSON for API clients, the HTML error page for everyone else
+pub fn render_error(request: &Request, error: &HsError) -> Response {
+    let status = error.status();
+    if request.header("Accept").is_some_and(prefers_json) {
+        Response::json(format!(
+            "{{\"status\":{},\"error\":{},\"message\":{},\"request_id\":{}}}",
+            status,
+            json_string(reason_phrase(status)),
+            json_string(error.public_message()),
+            json_string(&request.id)
+        ))
+        .with_status(status)
+    } else {
+        Response::error_page(status, error.public_message(), &request.id)
+    }
+}
+
+// Fill an HTML error template; substituted values are escaped
+pub fn render_template(template: &str, request: &Request, error: &HsError) -> Response {
+    let status = error.status();
+    let body = template
+        .replace("{{status}}", &status.to_string())
+        .replace("{{reason}}", reason_phrase(status))
+        .replace("{{message}}", &escape_html(error.public_message()))
+        .replace("{{request_id}}", &escape_html(&request.id));
+    Response::new()
+        .with_status(status)
+        .with_header("Content-Type", "text/html; charset=utf-8")
+        .with_body(&body)
+}
+
+// Whether the Accept header ranks JSON above HTML; ties go to HTML
+pub fn prefers_json(accept: &str) -> bool {
+    let mut json = 0.0;
+    let mut html = 0.0;
+    for range in accept.split(',') {
+        let mut parts = range.split(';');
+        let media = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+        let q = parts
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        match media.as_str() {
+            "application/json" => json = q,
+            "text/html" => html = q,
+            _ => {}
+        }
+    }
+    json > html
+}
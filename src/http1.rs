@@ -0,0 +1,699 @@
+//HTTP/1 connections
+//The request loop both engines run on a connection: read a request head and body
+//within their timeouts, route it, write the response, then wait for the next request
+//for as long as the client keeps the connection alive.
+use std::future::Future;
+use std::io::{self, BufRead, BufReader};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt};
+
+use crate::error::HsError;
+#[cfg(feature = "http2")]
+use crate::http2;
+use crate::logging::{self, AccessLogEntry};
+use crate::metrics::{metrics, ConnectionGuard};
+use crate::route::{body_length, parse_request, response_head, Router};
+use crate::stream::{AsyncTransport, Transport};
+use crate::timeouts::Timeouts;
+use crate::types::{Request, Response};
+use crate::{log_debug, log_warn};
+
+// Largest request head accepted, so a client can't grow one until the timeout
+const MAX_HEAD_BYTES: usize = 64 * 1024;
+
+// Largest request body read into memory
+const MAX_BODY_BYTES: u64 = 16 * 1024 * 1024;
+
+// Interim response for clients that wait before sending a body
+const CONTINUE: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n";
+
+// Why no request came off a connection
+enum ReadError {
+    // The client hung up or went quiet between requests; close without a word
+    Closed,
+    // Answer with this error, then close
+    Respond(HsError),
+}
+
+impl ReadError {
+    fn timed_out() -> Self {
+        ReadError::Respond(HsError::Status(408, "The server timed out waiting for the request.".to_string()))
+    }
+}
+
+// When a timeout started now runs out; a zero timeout never does
+fn deadline(timeout: Duration) -> Option<Instant> {
+    (!timeout.is_zero()).then(|| Instant::now() + timeout)
+}
+
+// Serve requests on a threaded-engine connection until either side closes it
+pub fn serve<S: Transport>(stream: S, router: &Router, timeouts: Timeouts) {
+    let _connection = ConnectionGuard::new();
+    let peer_addr = stream.peer_addr().ok();
+    let _ = stream.set_write_timeout(Some(timeouts.write()).filter(|t| !t.is_zero()));
+    let mut reader = BufReader::new(stream);
+    let mut served = 0;
+
+    loop {
+        // Between requests only the keep-alive timeout applies; the header timeout
+        // starts with the next request's first byte (or the accept, for the first)
+        if served > 0 && !await_request(&mut reader, timeouts.keep_alive_idle()) {
+            break;
+        }
+        served += 1;
+        let request_lines = match read_head(&mut reader, deadline(timeouts.header_read())) {
+            Ok(lines) => lines,
+            Err(ReadError::Closed) => break,
+            Err(ReadError::Respond(error)) => {
+                finish_unparsed(&mut reader, router, error, peer_addr);
+                break;
+            }
+        };
+
+        let started = Instant::now();
+        let mut request = parse_request(&request_lines);
+        let mut keep_alive = false;
+        let response = match request.as_mut() {
+            Some(request) => {
+                request.peer_addr = peer_addr;
+                log_debug!("Request: {} {}", request.method, request.path);
+                for (key, value) in &request.headers {
+                    log_debug!("  {}: {}", key, value);
+                }
+
+                match read_body(&mut reader, request, deadline(timeouts.body_read())) {
+                    Ok(body) => {
+                        request.body = body;
+                        keep_alive = request.keep_alive() && !timeouts.keep_alive_idle().is_zero();
+                        // Route the request; unmatched paths come back as 404 Not Found
+                        let response = router.handle(request);
+                        if response.upgrade.is_some() {
+                            // Handing a worker thread to a WebSocket would pin it for the connection's life
+                            let error = HsError::Status(501, "WebSockets are only served by the async engine.".to_string());
+                            router.render_error(request, &error).with_header(router.request_id_header(), &request.id)
+                        } else {
+                            response
+                        }
+                    }
+                    Err(ReadError::Closed) => break,
+                    Err(ReadError::Respond(error)) => router.reject(error),
+                }
+            },
+            None => router.reject(HsError::BadRequest("The request could not be parsed.".to_string())) // Bad Request if parsing fails
+        };
+        let response = response.with_header("Connection", if keep_alive { "keep-alive" } else { "close" });
+
+        let written = write_response(reader.get_mut(), &response);
+        if let Err(e) = &written {
+            log_warn!("Error writing response for {}: {}", response_id(router, &response), e);
+        }
+        let bytes_in = head_size(&request_lines) + request.as_ref().map_or(0, |r| r.body.len() as u64);
+        metrics().observe_request(request.as_ref(), &response, started.elapsed(), bytes_in);
+        logging::access(&AccessLogEntry::new(request.as_ref(), &response, router.request_id_header(), peer_addr, started));
+        if !keep_alive || written.is_err() {
+            break;
+        }
+    }
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+// Bound the next read by what is left before `deadline`, so a client trickling
+// bytes can't stretch a read past it
+fn read_timeout_until<S: Transport>(stream: &S, deadline: Option<Instant>) -> Result<(), ReadError> {
+    let left = match deadline {
+        Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+        None => return stream.set_read_timeout(None).map_err(|_| ReadError::Closed),
+    };
+    if left.is_zero() {
+        return Err(ReadError::timed_out());
+    }
+    stream.set_read_timeout(Some(left)).map_err(|_| ReadError::Closed)
+}
+
+// Buffered bytes, reading more once the buffer is empty
+fn fill<S: Transport>(reader: &mut BufReader<S>, deadline: Option<Instant>) -> Result<&[u8], ReadError> {
+    loop {
+        read_timeout_until(reader.get_ref(), deadline)?;
+        match reader.fill_buf() {
+            Ok([]) => return Err(ReadError::Closed),
+            // Reborrowed so the loop can retry after an interrupt
+            Ok(_) => return Ok(reader.buffer()),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) if is_timeout(&e) => return Err(ReadError::timed_out()),
+            Err(_) => return Err(ReadError::Closed),
+        }
+    }
+}
+
+// Wait up to `idle` for the next request to start; false when the client is done
+fn await_request<S: Transport>(reader: &mut BufReader<S>, idle: Duration) -> bool {
+    !reader.buffer().is_empty() || fill(reader, deadline(idle)).is_ok()
+}
+
+// Request line and headers, without CRLFs, up to the blank line
+fn read_head<S: Transport>(reader: &mut BufReader<S>, deadline: Option<Instant>) -> Result<Vec<String>, ReadError> {
+    let mut head = Head::default();
+    while !head.done {
+        let available = fill(reader, deadline)?;
+        let used = head.push(available)?;
+        reader.consume(used);
+    }
+    Ok(head.lines)
+}
+
+fn read_body<S: Transport>(reader: &mut BufReader<S>, request: &Request, deadline: Option<Instant>) -> Result<Vec<u8>, ReadError> {
+    let len = check_body_length(request)?;
+    if len > 0 && expects_continue(request) {
+        reader.get_mut().write_all(CONTINUE).map_err(|_| ReadError::Closed)?;
+    }
+    let mut body = Vec::with_capacity(len.min(64 * 1024));
+    while body.len() < len {
+        let available = fill(reader, deadline)?;
+        let used = available.len().min(len - body.len());
+        body.extend_from_slice(&available[..used]);
+        reader.consume(used);
+    }
+    Ok(body)
+}
+
+// A request head as it comes in, shared by both engines' readers
+#[derive(Default)]
+struct Head {
+    lines: Vec<String>,
+    line: Vec<u8>,
+    size: usize,
+    // The blank line after the headers has been read
+    done: bool,
+}
+
+impl Head {
+    // Take what is needed of `available`, up to the end of a line, and say how much
+    // that was
+    fn push(&mut self, available: &[u8]) -> Result<usize, ReadError> {
+        let end = available.iter().position(|&b| b == b'\n');
+        let used = end.map_or(available.len(), |i| i + 1);
+        self.line.extend_from_slice(&available[..used]);
+        self.size += used;
+        if self.size > MAX_HEAD_BYTES {
+            return Err(ReadError::Respond(HsError::Status(431, "The request head is too large.".to_string())));
+        }
+        if end.is_none() {
+            return Ok(used);
+        }
+        let text = String::from_utf8(std::mem::take(&mut self.line))
+            .map_err(|_| ReadError::Respond(HsError::BadRequest("The request could not be parsed.".to_string())))?;
+        let text = text.trim_end_matches(['\r', '\n']);
+        if !text.is_empty() {
+            self.lines.push(text.to_string());
+        }
+        // Blank lines before a request line are skipped
+        self.done = text.is_empty() && !self.lines.is_empty();
+        Ok(used)
+    }
+}
+
+fn expects_continue(request: &Request) -> bool {
+    request.header("Expect").is_some_and(|e| e.eq_ignore_ascii_case("100-continue"))
+}
+
+fn check_body_length(request: &Request) -> Result<usize, ReadError> {
+    let len = body_length(request).map_err(ReadError::Respond)?;
+    if len > MAX_BODY_BYTES {
+        return Err(ReadError::Respond(HsError::Status(413, "The request body is too large.".to_string())));
+    }
+    Ok(len as usize)
+}
+
+// Answer a request whose head never arrived in one piece
+fn finish_unparsed<S: Transport>(reader: &mut BufReader<S>, router: &Router, error: HsError, peer_addr: Option<SocketAddr>) {
+    let started = Instant::now();
+    let response = router.reject(error).with_header("Connection", "close");
+    if let Err(e) = write_response(reader.get_mut(), &response) {
+        log_debug!("Error writing {} for {}: {}", response.status, response_id(router, &response), e);
+    }
+    metrics().observe_request(None, &response, started.elapsed(), 0);
+    logging::access(&AccessLogEntry::new(None, &response, router.request_id_header(), peer_addr, started));
+}
+
+// Bytes in the request line and headers, including CRLFs
+fn head_size(request_lines: &[String]) -> u64 {
+    request_lines.iter().map(|line| line.len() as u64 + 2).sum::<u64>() + 2
+}
+
+// The request ID a response went out with, for log lines
+pub fn response_id<'a>(router: &Router, response: &'a Response) -> &'a str {
+    response.headers.get(router.request_id_header()).map_or("-", String::as_str)
+}
+
+pub fn write_response<S: Transport>(stream: &mut S, response: &Response) -> io::Result<()> {
+    stream.write_all(response_head(response).as_bytes())?;
+    match &response.file {
+        // Unmodified static files go straight from the page cache to the socket
+        Some(body) => stream.send_file(&body.file, body.len)?,
+        None => stream.write_all(response.bytes())?,
+    }
+    stream.flush()
+}
+
+// `serve` for the tokio engine
+pub async fn serve_async<S: AsyncTransport>(stream: S, router: Arc<Router>, timeouts: Timeouts) {
+    let _connection = ConnectionGuard::new();
+    let peer_addr = stream.peer_addr().ok();
+    let mut reader = tokio::io::BufReader::new(stream);
+    let mut served = 0;
+
+    loop {
+        if served > 0 && !await_request_async(&mut reader, timeouts.keep_alive_idle()).await {
+            break;
+        }
+        served += 1;
+        let head_deadline = deadline(timeouts.header_read());
+        #[cfg_attr(not(feature = "http2"), allow(unused_mut))]
+        let mut head = Head::default();
+        // Cleartext HTTP/2 clients with prior knowledge open with the preface
+        #[cfg(feature = "http2")]
+        if served == 1 {
+            let opening = until(head_deadline, read_opening(&mut reader)).await.and_then(|opening| {
+                if http2::is_preface(&opening) {
+                    return Ok(Some(opening));
+                }
+                let mut rest = &opening[..];
+                while !rest.is_empty() && !head.done {
+                    rest = &rest[head.push(rest)?..];
+                }
+                Ok(None)
+            });
+            match opening {
+                Ok(Some(preface)) => return http2::serve_after(preface, reader, router, peer_addr, timeouts).await,
+                Ok(None) => {}
+                Err(ReadError::Closed) => break,
+                Err(ReadError::Respond(error)) => {
+                    finish_unparsed_async(&mut reader, &router, error, peer_addr, timeouts).await;
+                    break;
+                }
+            }
+        }
+        let request_lines = match until(head_deadline, read_head_async(&mut reader, head)).await {
+            Ok(lines) => lines,
+            Err(ReadError::Closed) => break,
+            Err(ReadError::Respond(error)) => {
+                finish_unparsed_async(&mut reader, &router, error, peer_addr, timeouts).await;
+                break;
+            }
+        };
+
+        let started = Instant::now();
+        let mut request = parse_request(&request_lines);
+        let mut keep_alive = false;
+        let response = match request.as_mut() {
+            Some(request) => {
+                request.peer_addr = peer_addr;
+                log_debug!("Async Request: {} {}", request.method, request.path);
+
+                let body = read_body_async(&mut reader, request, timeouts.body_read()).await;
+                match body {
+                    Ok(body) => {
+                        request.body = body;
+                        #[cfg(feature = "http2")]
+                        if let Some(upgrade) = http2::upgrade(request).filter(|_| !reader.get_ref().is_tls()) {
+                            if let Err(e) = reader.get_mut().write_all(http2::SWITCHING_PROTOCOLS).await {
+                                log_debug!("Error switching {} to HTTP/2: {}", request.path, e);
+                                break;
+                            }
+                            return upgrade.serve(reader, router, peer_addr, timeouts).await;
+                        }
+                        keep_alive = request.keep_alive() && !timeouts.keep_alive_idle().is_zero();
+                        // Route the request; unmatched paths come back as 404 Not Found
+                        router.handle(request)
+                    }
+                    Err(ReadError::Closed) => break,
+                    Err(ReadError::Respond(error)) => router.reject(error),
+                }
+            },
+            None => router.reject(HsError::BadRequest("The request could not be parsed.".to_string())) // Bad Request if parsing fails
+        };
+        let mut response = response;
+        // A WebSocket handshake keeps its own Connection: Upgrade
+        let upgrade = response.upgrade.take();
+        if upgrade.is_none() {
+            response = response.with_header("Connection", if keep_alive { "keep-alive" } else { "close" });
+        }
+
+        let written = write_response_timeout(reader.get_mut(), &response, timeouts).await;
+        if let Err(e) = &written {
+            log_warn!("Error writing response for {}: {}", response_id(&router, &response), e);
+        }
+        let bytes_in = head_size(&request_lines) + request.as_ref().map_or(0, |r| r.body.len() as u64);
+        metrics().observe_request(request.as_ref(), &response, started.elapsed(), bytes_in);
+        logging::access(&AccessLogEntry::new(request.as_ref(), &response, router.request_id_header(), peer_addr, started));
+        if let (Some(websocket), Ok(())) = (upgrade, &written) {
+            // The connection now belongs to the handler, with no HTTP timeouts
+            websocket.run(Box::new(reader)).await;
+            break;
+        }
+        if !keep_alive || written.is_err() {
+            break;
+        }
+    }
+}
+
+// Run a read until `deadline`, or for as long as it takes without one
+async fn until<T>(deadline: Option<Instant>, read: impl Future<Output = Result<T, ReadError>>) -> Result<T, ReadError> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), read)
+            .await
+            .unwrap_or_else(|_| Err(ReadError::timed_out())),
+        None => read.await,
+    }
+}
+
+// Buffered bytes, reading more once the buffer is empty
+async fn fill_async<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<&[u8], ReadError> {
+    match reader.fill_buf().await {
+        Ok([]) | Err(_) => Err(ReadError::Closed),
+        Ok(available) => Ok(available),
+    }
+}
+
+async fn await_request_async<R: AsyncBufRead + Unpin>(reader: &mut R, idle: Duration) -> bool {
+    until(deadline(idle), fill_async(reader)).await.is_ok()
+}
+
+// `read_head` for the async engine, carrying on from `head`; the caller applies the deadline
+async fn read_head_async<R: AsyncBufRead + Unpin>(reader: &mut R, mut head: Head) -> Result<Vec<String>, ReadError> {
+    while !head.done {
+        let available = fill_async(reader).await?;
+        let used = head.push(available)?;
+        reader.consume(used);
+    }
+    Ok(head.lines)
+}
+
+// The first bytes of a connection, enough to tell the HTTP/2 preface from an
+// HTTP/1 request line: four of them, or a whole line
+#[cfg(feature = "http2")]
+async fn read_opening<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Vec<u8>, ReadError> {
+    let mut opening = Vec::new();
+    while opening.len() < 4 && !opening.contains(&b'\n') {
+        let available = fill_async(reader).await?;
+        let used = available.len().min(4 - opening.len());
+        opening.extend_from_slice(&available[..used]);
+        reader.consume(used);
+    }
+    Ok(opening)
+}
+
+async fn read_body_async<S: AsyncTransport>(reader: &mut tokio::io::BufReader<S>, request: &Request, timeout: Duration) -> Result<Vec<u8>, ReadError> {
+    let len = check_body_length(request)?;
+    if len > 0 && expects_continue(request) {
+        reader.get_mut().write_all(CONTINUE).await.map_err(|_| ReadError::Closed)?;
+    }
+    until(deadline(timeout), async {
+        let mut body = Vec::with_capacity(len.min(64 * 1024));
+        while body.len() < len {
+            let available = fill_async(reader).await?;
+            let used = available.len().min(len - body.len());
+            body.extend_from_slice(&available[..used]);
+            reader.consume(used);
+        }
+        Ok(body)
+    })
+    .await
+}
+
+async fn finish_unparsed_async<S: AsyncTransport>(reader: &mut tokio::io::BufReader<S>, router: &Router, error: HsError, peer_addr: Option<SocketAddr>, timeouts: Timeouts) {
+    let started = Instant::now();
+    let response = router.reject(error).with_header("Connection", "close");
+    if let Err(e) = write_response_timeout(reader.get_mut(), &response, timeouts).await {
+        log_debug!("Error writing {} for {}: {}", response.status, response_id(router, &response), e);
+    }
+    metrics().observe_request(None, &response, started.elapsed(), 0);
+    logging::access(&AccessLogEntry::new(None, &response, router.request_id_header(), peer_addr, started));
+}
+
+async fn write_response_timeout<S: AsyncTransport>(stream: &mut S, response: &Response, timeouts: Timeouts) -> io::Result<()> {
+    if timeouts.write().is_zero() {
+        return write_response_async(stream, response).await;
+    }
+    tokio::time::timeout(timeouts.write(), write_response_async(stream, response))
+        .await
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "write timed out")))
+}
+
+pub async fn write_response_async<S: AsyncTransport>(stream: &mut S, response: &Response) -> io::Result<()> {
+    stream.write_all(response_head(response).as_bytes()).await?;
+    match &response.file {
+        Some(body) => stream.send_file(&body.file, body.len).await?,
+        None => stream.write_all(response.bytes()).await?,
+    }
+    stream.flush().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use tokio::io::{AsyncReadExt, DuplexStream};
+
+    impl AsyncTransport for DuplexStream {
+        fn peer_addr(&self) -> io::Result<SocketAddr> {
+            Err(io::ErrorKind::NotConnected.into())
+        }
+
+        fn is_tls(&self) -> bool {
+            false
+        }
+
+        async fn send_file(&mut self, file: &File, len: u64) -> io::Result<()> {
+            crate::sendfile::copy_file_async(self, file, 0, len).await
+        }
+    }
+
+    fn router() -> Arc<Router> {
+        let mut router = Router::new();
+        router.get("/a", |_req| Response::text("alpha"));
+        router.get("/b", |_req| Response::text("bravo"));
+        router.post("/echo", |req| Response::new().with_body_bytes(req.body.clone()));
+        Arc::new(router)
+    }
+
+    fn fast() -> Timeouts {
+        Timeouts::new()
+            .with_header_read(Duration::from_millis(200))
+            .with_body_read(Duration::from_millis(200))
+            .with_keep_alive_idle(Duration::from_millis(200))
+    }
+
+    // Everything the async engine writes back before closing, after `input`; the
+    // client's side stays open, so only the server ends the exchange
+    async fn exchange(timeouts: Timeouts, input: &[u8]) -> String {
+        let (mut client, server) = tokio::io::duplex(256 * 1024);
+        let served = tokio::spawn(serve_async(server, router(), timeouts));
+        client.write_all(input).await.unwrap();
+        let mut output = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut output))
+            .await
+            .expect("the connection wasn't closed")
+            .unwrap();
+        served.await.unwrap();
+        String::from_utf8_lossy(&output).into_owned()
+    }
+
+    // A threaded-engine connection over a real socket, so reads time out as in production
+    fn threaded(timeouts: Timeouts) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        std::thread::spawn(move || serve(stream, &router(), timeouts));
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client
+    }
+
+    fn read_all(client: &mut TcpStream) -> String {
+        let mut output = Vec::new();
+        // Bytes read before an error are kept
+        let _ = client.read_to_end(&mut output);
+        String::from_utf8_lossy(&output).into_owned()
+    }
+
+    fn statuses(output: &str) -> Vec<&str> {
+        output.match_indices("HTTP/1.1 ").map(|(i, _)| &output[i + 9..i + 12]).collect()
+    }
+
+    #[test]
+    fn threaded_silent_client_gets_408() {
+        let mut client = threaded(fast());
+        let started = Instant::now();
+        let output = read_all(&mut client);
+        assert_eq!(statuses(&output), ["408"]);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn threaded_trickling_client_gets_408() {
+        let mut client = threaded(fast());
+        let mut writer = client.try_clone().unwrap();
+        let started = Instant::now();
+        std::thread::spawn(move || {
+            let _ = writer.write_all(b"GET /a HTTP/1.1\r\n");
+            // A byte at a time, each well inside the timeout, never finishing the head
+            while started.elapsed() < Duration::from_secs(3) && writer.write_all(b"x").is_ok() {
+                std::thread::sleep(Duration::from_millis(20));
+            }
+        });
+        let output = read_all(&mut client);
+        assert_eq!(statuses(&output), ["408"]);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn threaded_pipelined_requests_are_answered_in_order() {
+        let mut client = threaded(fast());
+        client
+            .write_all(b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let output = read_all(&mut client);
+        assert_eq!(statuses(&output), ["200", "200"]);
+        assert!(output.find("alpha").unwrap() < output.find("bravo").unwrap());
+    }
+
+    #[tokio::test]
+    async fn silent_client_gets_408() {
+        let output = exchange(fast(), b"").await;
+        assert_eq!(statuses(&output), ["408"]);
+        assert!(output.contains("Connection: close"));
+    }
+
+    #[tokio::test]
+    async fn trickling_client_gets_408() {
+        let (client, server) = tokio::io::duplex(1024);
+        let served = tokio::spawn(serve_async(server, router(), fast()));
+        let started = Instant::now();
+        let (mut reader, mut writer) = tokio::io::split(client);
+        tokio::spawn(async move {
+            let _ = writer.write_all(b"GET /a HTTP/1.1\r\n").await;
+            while started.elapsed() < Duration::from_secs(3) && writer.write_all(b"x").await.is_ok() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        });
+        let mut output = Vec::new();
+        tokio::time::timeout(Duration::from_secs(2), reader.read_to_end(&mut output)).await.unwrap().unwrap();
+        served.await.unwrap();
+        assert_eq!(statuses(&String::from_utf8_lossy(&output)), ["408"]);
+    }
+
+    #[tokio::test]
+    async fn idle_keep_alive_connection_closes_quietly() {
+        let output = exchange(fast(), b"GET /a HTTP/1.1\r\n\r\n").await;
+        assert_eq!(statuses(&output), ["200"]);
+        assert!(output.contains("Connection: keep-alive"));
+    }
+
+    #[tokio::test]
+    async fn connection_close_ends_the_connection() {
+        let output = exchange(fast(), b"GET /a HTTP/1.1\r\nConnection: close\r\n\r\nGET /b HTTP/1.1\r\n\r\n").await;
+        assert_eq!(statuses(&output), ["200"]);
+        assert!(output.contains("Connection: close"));
+        assert!(!output.contains("bravo"));
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_are_answered_in_order() {
+        let input = b"GET /a HTTP/1.1\r\n\r\nPOST /echo HTTP/1.1\r\nContent-Length: 7\r\n\r\ncharlieGET /b HTTP/1.0\r\n\r\n";
+        let output = exchange(fast(), input).await;
+        assert_eq!(statuses(&output), ["200", "200", "200"]);
+        let (a, echo, b) = (output.find("alpha").unwrap(), output.find("charlie").unwrap(), output.find("bravo").unwrap());
+        assert!(a < echo && echo < b);
+        // HTTP/1.0 closes by default
+        assert!(output.ends_with("bravo"));
+    }
+
+    #[tokio::test]
+    async fn slow_body_gets_408() {
+        let output = exchange(fast(), b"POST /echo HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc").await;
+        assert_eq!(statuses(&output), ["408"]);
+    }
+
+    #[tokio::test]
+    async fn oversized_body_gets_413() {
+        let output = exchange(fast(), b"POST /echo HTTP/1.1\r\nContent-Length: 999999999\r\n\r\n").await;
+        assert_eq!(statuses(&output), ["413"]);
+    }
+
+    #[tokio::test]
+    async fn oversized_head_gets_431() {
+        let mut input = b"GET /a HTTP/1.1\r\nX-Padding: ".to_vec();
+        input.resize(MAX_HEAD_BYTES + 1024, b'a');
+        let output = exchange(fast(), &input).await;
+        assert_eq!(statuses(&output), ["431"]);
+    }
+
+    #[tokio::test]
+    async fn chunked_body_gets_501() {
+        let output = exchange(fast(), b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n").await;
+        assert_eq!(statuses(&output), ["501"]);
+    }
+
+    #[tokio::test]
+    async fn zero_read_timeouts_wait_as_long_as_it_takes() {
+        let timeouts = Timeouts::new()
+            .with_header_read(Duration::ZERO)
+            .with_body_read(Duration::ZERO)
+            .with_write(Duration::ZERO);
+        let (client, server) = tokio::io::duplex(1024);
+        let served = tokio::spawn(serve_async(server, router(), timeouts));
+        let (mut reader, mut writer) = tokio::io::split(client);
+        writer.write_all(b"POST /echo HTTP/1.1\r\nConnection: close\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        writer.write_all(b"Content-Length: 5\r\n\r\nde").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        writer.write_all(b"lta").await.unwrap();
+        let mut output = Vec::new();
+        tokio::time::timeout(Duration::from_secs(2), reader.read_to_end(&mut output)).await.unwrap().unwrap();
+        served.await.unwrap();
+        let output = String::from_utf8_lossy(&output);
+        assert_eq!(statuses(&output), ["200"]);
+        assert!(output.ends_with("delta"));
+    }
+
+    #[tokio::test]
+    async fn zero_keep_alive_closes_after_each_response() {
+        let timeouts = fast().with_keep_alive_idle(Duration::ZERO);
+        let output = exchange(timeouts, b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n").await;
+        assert_eq!(statuses(&output), ["200"]);
+        assert!(output.contains("Connection: close"));
+    }
+
+    #[cfg(feature = "http2")]
+    #[tokio::test]
+    async fn preface_split_across_reads_is_http2() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(serve_async(server, router(), fast()));
+        client.write_all(&http2::PREFACE[..2]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        client.write_all(&http2::PREFACE[2..]).await.unwrap();
+        // An empty SETTINGS frame
+        client.write_all(&[0, 0, 0, 0x4, 0, 0, 0, 0, 0]).await.unwrap();
+        // The server's SETTINGS comes back, not an HTTP/1 error
+        let mut header = [0; 9];
+        tokio::time::timeout(Duration::from_secs(2), client.read_exact(&mut header)).await.unwrap().unwrap();
+        assert_eq!(header[3], 0x4);
+    }
+
+    #[cfg(feature = "http2")]
+    #[tokio::test]
+    async fn short_first_line_is_http1() {
+        let output = exchange(fast(), b"\r\nGET /a HTTP/1.1\r\nConnection: close\r\n\r\n").await;
+        assert_eq!(statuses(&output), ["200"]);
+        let output = exchange(fast(), b"A\n\n").await;
+        assert_eq!(statuses(&output), ["400"]);
+    }
+}
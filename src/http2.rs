@@ -0,0 +1,763 @@
+//HTTP/2 for the tokio engine
+//Connections that negotiate `h2` through TLS ALPN, open with the HTTP/2 preface in
+//cleartext (prior knowledge), or ask for `Upgrade: h2c` are served with the h2 crate.
+//Each stream becomes a `Request` for the router and its `Response` goes back as
+//HEADERS and DATA frames, so handlers can't tell the protocols apart.
+use std::future::poll_fn;
+use std::io::{self, SeekFrom};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL;
+use base64::Engine;
+use bytes::Bytes;
+use h2::server::{self, SendResponse};
+use h2::{RecvStream, SendStream};
+use http::header::{HeaderName, HeaderValue};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, ReadBuf};
+use tokio::sync::Notify;
+
+use crate::error::HsError;
+use crate::log_debug;
+use crate::logging::{self, AccessLogEntry};
+use crate::metrics::metrics;
+use crate::route::Router;
+use crate::timeouts::Timeouts;
+use crate::types::{Request, Response};
+
+// The ALPN protocol ID for HTTP/2 over TLS
+pub const ALPN: &str = "h2";
+
+// What every HTTP/2 client sends first
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// The answer to an `Upgrade: h2c` request, after which both sides speak HTTP/2
+pub const SWITCHING_PROTOCOLS: &[u8] = b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n";
+
+// Largest request body collected for a handler, as on HTTP/1
+const MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+const MAX_CONCURRENT_STREAMS: u32 = 128;
+
+// File bodies are read and sent this much at a time
+const FILE_CHUNK_BYTES: usize = 64 * 1024;
+
+// The initial SETTINGS_MAX_FRAME_SIZE; the synthetic upgrade request must fit in one frame
+const MAX_FRAME_BYTES: usize = 16 * 1024;
+
+// SETTINGS parameters (RFC 7540 §6.5.2) that `HTTP2-Settings` is checked for
+const SETTINGS_HEADER_TABLE_SIZE: u16 = 0x1;
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+// The protocol defaults for the two settings that would constrain what we send
+const DEFAULT_HEADER_TABLE_SIZE: u32 = 4096;
+const DEFAULT_WINDOW_SIZE: u32 = 65_535;
+
+// Headers that only mean something to one HTTP/1 connection and are forbidden in HTTP/2
+const CONNECTION_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+    "http2-settings",
+    "host",
+];
+
+// Whether the first bytes from a cleartext client are the start of the HTTP/2 preface.
+// "PRI " is enough to tell; no HTTP/1 request starts that way. Fewer than 4 bytes
+// never count, so callers should gather that many first (or a whole line).
+pub fn is_preface(bytes: &[u8]) -> bool {
+    let len = bytes.len().min(PREFACE.len());
+    len >= 4 && bytes[..len] == PREFACE[..len]
+}
+
+// Serve a connection whose client speaks HTTP/2 from the first byte
+pub async fn serve<S>(io: S, router: Arc<Router>, peer_addr: Option<SocketAddr>, timeouts: Timeouts)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let handshake = server::Builder::new()
+        .max_concurrent_streams(MAX_CONCURRENT_STREAMS)
+        .handshake::<_, Bytes>(io);
+    let mut connection = match tokio::time::timeout(timeouts.header_read(), handshake).await {
+        Ok(Ok(connection)) => connection,
+        Ok(Err(e)) => {
+            log_debug!("HTTP/2 handshake failed: {}", e);
+            return;
+        }
+        Err(_) => {
+            log_debug!("HTTP/2 handshake timed out");
+            return;
+        }
+    };
+
+    let active = Arc::new(AtomicUsize::new(0));
+    let finished = Arc::new(Notify::new());
+    let mut closing = false;
+    let mut served = 0;
+    loop {
+        // The connection only makes progress while accept is polled, so keep polling
+        // it while streams run; once none do, the keep-alive timeout applies (the
+        // header timeout, before the first request)
+        let idle = !closing && active.load(Ordering::Acquire) == 0;
+        let wait = if served == 0 { timeouts.header_read() } else { timeouts.keep_alive_idle() };
+        let next = tokio::select! {
+            next = connection.accept() => next,
+            _ = finished.notified(), if !idle => continue,
+            _ = tokio::time::sleep(wait), if idle => {
+                // GOAWAY, then keep going until the client has read it
+                connection.graceful_shutdown();
+                closing = true;
+                continue;
+            }
+        };
+        let (request, respond) = match next {
+            Some(Ok(stream)) => stream,
+            Some(Err(e)) => {
+                log_debug!("HTTP/2 connection error: {}", e);
+                break;
+            }
+            None => break,
+        };
+        served += 1;
+        active.fetch_add(1, Ordering::AcqRel);
+        let (router, active, finished) = (Arc::clone(&router), Arc::clone(&active), Arc::clone(&finished));
+        tokio::spawn(async move {
+            serve_stream(request, respond, &router, peer_addr, timeouts).await;
+            active.fetch_sub(1, Ordering::AcqRel);
+            finished.notify_one();
+        });
+    }
+}
+
+// `serve` for a connection whose first bytes, `read`, were already taken off `io`
+// while telling HTTP/2 from HTTP/1
+pub async fn serve_after<S>(read: Vec<u8>, io: S, router: Arc<Router>, peer_addr: Option<SocketAddr>, timeouts: Timeouts)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let io = Upgraded {
+        io,
+        head: Vec::new(),
+        frames: None,
+        pending: read,
+    };
+    serve(io, router, peer_addr, timeouts).await;
+}
+
+// An `Upgrade: h2c` request, ready to be answered as stream 1 of an HTTP/2 connection
+pub struct Upgrade {
+    // HEADERS frame replaying the request, fed to the server after the client's SETTINGS
+    frames: Vec<u8>,
+}
+
+// Whether `request` asks to switch to HTTP/2 in a way we can take up. Requests with a
+// body, whose headers don't fit one frame, or whose HTTP2-Settings we can't honour
+// are answered over HTTP/1.1 instead, which the client has to accept.
+pub fn upgrade(request: &Request) -> Option<Upgrade> {
+    let has_token = |value: &str, token: &str| value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token));
+    if !request.version.eq_ignore_ascii_case("HTTP/1.1")
+        || !request.header("Upgrade").is_some_and(|value| has_token(value, "h2c"))
+        || !request.body.is_empty()
+    {
+        return None;
+    }
+    if let Err(reason) = check_upgrade_settings(request.header("HTTP2-Settings")?) {
+        log_debug!("Declining h2c upgrade: {}", reason);
+        return None;
+    }
+
+    let mut block = Vec::new();
+    let authority = request.header("Host").unwrap_or("");
+    for (name, value) in [(":method", request.method.as_str()), (":scheme", "http"), (":path", request.path.as_str()), (":authority", authority)] {
+        hpack_literal(&mut block, name, value);
+    }
+    for (name, value) in &request.headers {
+        let name = name.to_ascii_lowercase();
+        // TE may only say "trailers" in HTTP/2
+        let forbidden = CONNECTION_HEADERS.contains(&name.as_str()) || (name == "te" && value != "trailers");
+        if !forbidden {
+            hpack_literal(&mut block, &name, value);
+        }
+    }
+    if block.len() > MAX_FRAME_BYTES {
+        return None;
+    }
+
+    // HEADERS on stream 1 with END_STREAM and END_HEADERS
+    let len = (block.len() as u32).to_be_bytes();
+    let mut frames = vec![len[1], len[2], len[3], 0x1, 0x1 | 0x4, 0, 0, 0, 1];
+    frames.extend_from_slice(&block);
+    Some(Upgrade { frames })
+}
+
+// HTTP2-Settings carries the payload of a SETTINGS frame, base64url encoded, which
+// applies from before the client's own SETTINGS frame (RFC 7540 §3.2.1). That frame
+// may leave them out, and h2 never sees these, so settings stricter than the
+// defaults h2 starts from can't be honoured. The rest either only widen what the
+// server may do or govern things it never does, such as server push.
+fn check_upgrade_settings(value: &str) -> Result<(), &'static str> {
+    let payload = BASE64URL
+        .decode(value.trim().trim_end_matches('='))
+        .map_err(|_| "HTTP2-Settings isn't base64url")?;
+    if payload.len() % 6 != 0 {
+        return Err("HTTP2-Settings isn't a whole number of settings");
+    }
+    for setting in payload.chunks_exact(6) {
+        let id = u16::from_be_bytes([setting[0], setting[1]]);
+        let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+        match id {
+            SETTINGS_ENABLE_PUSH if value > 1 => return Err("invalid SETTINGS_ENABLE_PUSH"),
+            SETTINGS_INITIAL_WINDOW_SIZE if value > i32::MAX as u32 => return Err("invalid SETTINGS_INITIAL_WINDOW_SIZE"),
+            SETTINGS_MAX_FRAME_SIZE if !(MAX_FRAME_BYTES as u32..=0xFF_FFFF).contains(&value) => {
+                return Err("invalid SETTINGS_MAX_FRAME_SIZE")
+            }
+            SETTINGS_HEADER_TABLE_SIZE if value < DEFAULT_HEADER_TABLE_SIZE => {
+                return Err("SETTINGS_HEADER_TABLE_SIZE below the default")
+            }
+            SETTINGS_INITIAL_WINDOW_SIZE if value < DEFAULT_WINDOW_SIZE => {
+                return Err("SETTINGS_INITIAL_WINDOW_SIZE below the default")
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+impl Upgrade {
+    // Serve the connection once `SWITCHING_PROTOCOLS` has been written; the upgraded
+    // request comes back out of the h2 server as stream 1
+    pub async fn serve<S>(self, io: S, router: Arc<Router>, peer_addr: Option<SocketAddr>, timeouts: Timeouts)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let io = Upgraded {
+            io,
+            head: Vec::new(),
+            frames: Some(self.frames),
+            pending: Vec::new(),
+        };
+        serve(io, router, peer_addr, timeouts).await;
+    }
+}
+
+// HPACK "literal header field without indexing — new name", without Huffman coding.
+// It leaves the decoder's dynamic table alone, so nothing else has to know about it.
+fn hpack_literal(block: &mut Vec<u8>, name: &str, value: &str) {
+    block.push(0);
+    for text in [name, value] {
+        hpack_int(block, text.len(), 7);
+        block.extend_from_slice(text.as_bytes());
+    }
+}
+
+fn hpack_int(block: &mut Vec<u8>, mut value: usize, prefix_bits: u32) {
+    let max = (1 << prefix_bits) - 1;
+    if value < max {
+        block.push(value as u8);
+        return;
+    }
+    block.push(max as u8);
+    value -= max;
+    while value >= 128 {
+        block.push((value % 128 + 128) as u8);
+        value /= 128;
+    }
+    block.push(value as u8);
+}
+
+// The connection after a 101: the client's preface and SETTINGS pass through, then
+// the upgraded request is spliced in as if the client had sent it on stream 1.
+// Without `frames` it only replays `pending` before reading on.
+struct Upgraded<S> {
+    io: S,
+    // Client bytes read while looking for the end of its SETTINGS frame
+    head: Vec<u8>,
+    frames: Option<Vec<u8>>,
+    // Bytes to hand out before reading from `io` again
+    pending: Vec<u8>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Upgraded<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.pending.is_empty() {
+                let used = this.pending.len().min(buf.remaining());
+                buf.put_slice(&this.pending[..used]);
+                this.pending.drain(..used);
+                return Poll::Ready(Ok(()));
+            }
+            let Some(frames) = this.frames.as_ref() else {
+                return Pin::new(&mut this.io).poll_read(cx, buf);
+            };
+
+            // The preface is followed by a 9-byte frame header whose first 3 bytes
+            // give the payload length
+            let settings_end = (this.head.len() >= PREFACE.len() + 9).then(|| {
+                let h = &this.head[PREFACE.len()..];
+                PREFACE.len() + 9 + (usize::from(h[0]) << 16 | usize::from(h[1]) << 8 | usize::from(h[2]))
+            });
+            // SETTINGS can't be larger than the initial maximum frame size, so don't
+            // buffer more than that waiting for its end
+            if settings_end.is_some_and(|end| end > PREFACE.len() + 9 + MAX_FRAME_BYTES) {
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, "client SETTINGS frame too large")));
+            }
+            if let Some(end) = settings_end.filter(|&end| this.head.len() >= end) {
+                let rest = this.head.split_off(end);
+                this.pending = std::mem::take(&mut this.head);
+                this.pending.extend_from_slice(frames);
+                this.pending.extend_from_slice(&rest);
+                this.frames = None;
+                continue;
+            }
+
+            let mut chunk = [0; 1024];
+            let mut read = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut this.io).poll_read(cx, &mut read) {
+                Poll::Ready(Ok(())) if read.filled().is_empty() => {
+                    // Closed early; let the h2 server see whatever arrived
+                    this.pending = std::mem::take(&mut this.head);
+                    this.frames = None;
+                    if this.pending.is_empty() {
+                        return Poll::Ready(Ok(()));
+                    }
+                }
+                Poll::Ready(Ok(())) => this.head.extend_from_slice(read.filled()),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Upgraded<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+// Why a stream's body couldn't be collected
+enum BodyError {
+    // The client reset the stream; there's no one to answer
+    Reset,
+    Respond(HsError),
+}
+
+async fn serve_stream(
+    request: http::Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    router: &Router,
+    peer_addr: Option<SocketAddr>,
+    timeouts: Timeouts,
+) {
+    let started = Instant::now();
+    let (parts, body) = request.into_parts();
+    let mut request = to_request(&parts, peer_addr);
+    log_debug!("HTTP/2 Request: {} {}", request.method, request.path);
+
+    let collected = tokio::time::timeout(timeouts.body_read(), read_body(&request, body)).await;
+    let response = match collected {
+        Ok(Ok(body)) => {
+            request.body = body;
+            router.handle(&mut request)
+        }
+        Ok(Err(BodyError::Reset)) => return,
+        Ok(Err(BodyError::Respond(error))) => router.reject(error),
+        Err(_) => router.reject(HsError::Status(408, "The server timed out waiting for the request.".to_string())),
+    };
+
+    let head_only = request.method.eq_ignore_ascii_case("HEAD");
+    let sent = if timeouts.write().is_zero() {
+        send_response(&mut respond, &response, head_only).await
+    } else {
+        tokio::time::timeout(timeouts.write(), send_response(&mut respond, &response, head_only))
+            .await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "write timed out")))
+    };
+    if let Err(e) = sent {
+        log_debug!("Error writing HTTP/2 response for {}: {}", request.id, e);
+        respond.send_reset(h2::Reason::CANCEL);
+    }
+
+    let head_bytes: usize = request.headers.iter().map(|(name, value)| name.len() + value.len() + 4).sum();
+    let bytes_in = (head_bytes + request.path.len() + request.body.len()) as u64;
+    metrics().observe_request(Some(&request), &response, started.elapsed(), bytes_in);
+    logging::access(&AccessLogEntry::new(Some(&request), &response, router.request_id_header(), peer_addr, started));
+}
+
+fn to_request(parts: &http::request::Parts, peer_addr: Option<SocketAddr>) -> Request {
+    let mut request = Request {
+        method: parts.method.to_string(),
+        path: parts.uri.path_and_query().map_or("/", |p| p.as_str()).to_string(),
+        version: "HTTP/2.0".to_string(),
+        peer_addr,
+        ..Request::default()
+    };
+    // Handlers look for Host; HTTP/2 carries it as :authority
+    if let Some(authority) = parts.uri.authority() {
+        request.headers.insert("Host".to_string(), authority.to_string());
+    }
+    for (name, value) in &parts.headers {
+        let Ok(value) = value.to_str() else { continue };
+        // Repeated fields fold into one, as an HTTP/1 client would have sent them
+        let separator = if name == http::header::COOKIE { "; " } else { ", " };
+        request
+            .headers
+            .entry(name.as_str().to_string())
+            .and_modify(|folded| {
+                folded.push_str(separator);
+                folded.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+    request
+}
+
+async fn read_body(request: &Request, mut body: RecvStream) -> Result<Vec<u8>, BodyError> {
+    let too_large = || BodyError::Respond(HsError::Status(413, "The request body is too large.".to_string()));
+    let declared = request.header("Content-Length").and_then(|len| len.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > MAX_BODY_BYTES as u64) {
+        return Err(too_large());
+    }
+
+    let mut data = Vec::with_capacity(declared.unwrap_or(0) as usize);
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| BodyError::Reset)?;
+        // Let the client send more
+        let _ = body.flow_control().release_capacity(chunk.len());
+        if data.len() + chunk.len() > MAX_BODY_BYTES {
+            return Err(too_large());
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+async fn send_response(respond: &mut SendResponse<Bytes>, response: &Response, head_only: bool) -> io::Result<()> {
+    let mut head = http::Response::builder().status(response.status);
+    let has_body = !head_only && response.content_length() > 0;
+    if !response.headers.keys().any(|name| name.eq_ignore_ascii_case("Content-Length")) && !matches!(response.status, 204 | 304) {
+        head = head.header(http::header::CONTENT_LENGTH, response.content_length());
+    }
+    for (name, value) in &response.headers {
+        let name = name.to_ascii_lowercase();
+        if CONNECTION_HEADERS.contains(&name.as_str()) {
+            continue;
+        }
+        match (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+            (Ok(name), Ok(value)) => head = head.header(name, value),
+            _ => log_debug!("Dropping header not valid in HTTP/2: {}", name),
+        }
+    }
+    let head = head.body(()).map_err(io::Error::other)?;
+    let mut send = respond.send_response(head, !has_body).map_err(io::Error::other)?;
+    if !has_body {
+        return Ok(());
+    }
+
+    match &response.file {
+        Some(body) => {
+            let mut file = tokio::fs::File::from_std(body.file.try_clone()?);
+            file.seek(SeekFrom::Start(0)).await?;
+            let mut remaining = body.len;
+            while remaining > 0 {
+                let mut chunk = vec![0; (remaining as usize).min(FILE_CHUNK_BYTES)];
+                let read = file.read(&mut chunk).await?;
+                if read == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file shrank while being sent"));
+                }
+                chunk.truncate(read);
+                remaining -= read as u64;
+                send_data(&mut send, Bytes::from(chunk), remaining == 0).await?;
+            }
+            Ok(())
+        }
+        // A cached body goes out without being copied
+        None => match &response.shared {
+            Some(shared) => send_data(&mut send, Bytes::from_owner(Arc::clone(shared)), true).await,
+            None => send_data(&mut send, Bytes::copy_from_slice(&response.body), true).await,
+        },
+    }
+}
+
+// Send `data` as the peer's flow-control window allows, rather than buffering it all in h2
+async fn send_data(send: &mut SendStream<Bytes>, mut data: Bytes, end_of_stream: bool) -> io::Result<()> {
+    while !data.is_empty() {
+        send.reserve_capacity(data.len());
+        let granted = match poll_fn(|cx| send.poll_capacity(cx)).await {
+            Some(granted) => granted.map_err(io::Error::other)?,
+            None => return Err(io::Error::new(io::ErrorKind::ConnectionReset, "stream closed by the client")),
+        };
+        if granted == 0 {
+            continue;
+        }
+        let chunk = data.split_to(granted.min(data.len()));
+        send.send_data(chunk, end_of_stream && data.is_empty()).map_err(io::Error::other)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upgrade_request(settings: Option<&str>) -> Request {
+        let mut headers = vec![("Host", "localhost"), ("Connection", "Upgrade, HTTP2-Settings"), ("Upgrade", "h2c")];
+        headers.extend(settings.map(|value| ("HTTP2-Settings", value)));
+        Request {
+            method: "GET".to_string(),
+            path: "/".to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers: headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            ..Request::default()
+        }
+    }
+
+    fn settings(entries: &[(u16, u32)]) -> String {
+        let payload: Vec<u8> = entries
+            .iter()
+            .flat_map(|(id, value)| id.to_be_bytes().into_iter().chain(value.to_be_bytes()))
+            .collect();
+        BASE64URL.encode(payload)
+    }
+
+    #[test]
+    fn upgrade_accepts_settings_it_can_honour() {
+        // What curl sends: 100 streams, a 32 MiB window, no push
+        assert!(upgrade(&upgrade_request(Some("AAMAAABkAAQCAAAAAAIAAAAA"))).is_some());
+        assert!(upgrade(&upgrade_request(Some(""))).is_some());
+        let generous = settings(&[(SETTINGS_HEADER_TABLE_SIZE, 65_536), (SETTINGS_MAX_FRAME_SIZE, 1 << 20), (0xff, 7)]);
+        assert!(upgrade(&upgrade_request(Some(&generous))).is_some());
+    }
+
+    #[test]
+    fn upgrade_needs_http2_settings() {
+        assert!(upgrade(&upgrade_request(None)).is_none());
+    }
+
+    #[test]
+    fn upgrade_declines_malformed_settings() {
+        for value in ["not base64!", "AAMAAABkAA", "AAMAAABkAAQCAAAAAAIAAAAA, AAMAAABkAAQCAAAAAAIAAAAA"] {
+            assert!(upgrade(&upgrade_request(Some(value))).is_none(), "{}", value);
+        }
+        for invalid in [
+            (SETTINGS_ENABLE_PUSH, 2),
+            (SETTINGS_INITIAL_WINDOW_SIZE, 1 << 31),
+            (SETTINGS_MAX_FRAME_SIZE, 1024),
+            (SETTINGS_MAX_FRAME_SIZE, 1 << 24),
+        ] {
+            assert!(upgrade(&upgrade_request(Some(&settings(&[invalid])))).is_none(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn upgrade_declines_settings_stricter_than_the_defaults() {
+        let small_window = settings(&[(SETTINGS_INITIAL_WINDOW_SIZE, 1024)]);
+        assert!(upgrade(&upgrade_request(Some(&small_window))).is_none());
+        let no_table = settings(&[(SETTINGS_HEADER_TABLE_SIZE, 0)]);
+        assert!(upgrade(&upgrade_request(Some(&no_table))).is_none());
+    }
+
+    #[test]
+    fn upgrade_declines_requests_with_a_body() {
+        let mut request = upgrade_request(Some(""));
+        request.body = b"data".to_vec();
+        assert!(upgrade(&request).is_none());
+    }
+
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    // Counts the requests that reach it, so a test can tell an answer nobody read was sent
+    fn router(hits: &Arc<AtomicUsize>) -> Arc<Router> {
+        let mut router = Router::new();
+        let hits = Arc::clone(hits);
+        router.get("/", move |request| {
+            hits.fetch_add(1, Ordering::SeqCst);
+            Response::text(&format!("hello {}", request.version))
+        });
+        Arc::new(router)
+    }
+
+    // A GET over `client`, returning the status and body
+    async fn get(client: &mut h2::client::SendRequest<Bytes>, path: &str) -> (u16, String) {
+        let request = http::Request::get(format!("http://localhost{}", path)).body(()).unwrap();
+        let (response, _) = client.send_request(request, true).unwrap();
+        let response = response.await.unwrap();
+        let status = response.status().as_u16();
+        let mut body = response.into_body();
+        let mut text = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.unwrap();
+            body.flow_control().release_capacity(chunk.len()).unwrap();
+            text.extend_from_slice(&chunk);
+        }
+        (status, String::from_utf8(text).unwrap())
+    }
+
+    async fn connect(io: DuplexStream) -> h2::client::SendRequest<Bytes> {
+        let (client, connection) = h2::client::handshake(io).await.unwrap();
+        tokio::spawn(connection);
+        client
+    }
+
+    // The client side of an h2c upgrade. h2's client can't take one up, so it asks
+    // for the upgraded request again as stream 1; that HEADERS frame is dropped on
+    // the way out, and the answer the server sends on stream 1 goes back to it.
+    // Anything the frame added to the client's HPACK table is missing on the server.
+    struct UpgradeClient {
+        io: DuplexStream,
+        // Written by the client, not yet split into frames
+        unparsed: Vec<u8>,
+        // Ready to go to the server
+        out: Vec<u8>,
+        preface_left: usize,
+    }
+
+    impl UpgradeClient {
+        fn new(io: DuplexStream) -> Self {
+            UpgradeClient { io, unparsed: Vec::new(), out: Vec::new(), preface_left: PREFACE.len() }
+        }
+
+        fn split_frames(&mut self) {
+            let preface = self.preface_left.min(self.unparsed.len());
+            self.out.extend(self.unparsed.drain(..preface));
+            self.preface_left -= preface;
+            while self.preface_left == 0 && self.unparsed.len() >= 9 {
+                let h = &self.unparsed;
+                let end = 9 + (usize::from(h[0]) << 16 | usize::from(h[1]) << 8 | usize::from(h[2]));
+                if h.len() < end {
+                    break;
+                }
+                let stream = u32::from_be_bytes([h[5], h[6], h[7], h[8]]) & 0x7fff_ffff;
+                let frame: Vec<u8> = self.unparsed.drain(..end).collect();
+                if !(frame[3] == 0x1 && stream == 1) {
+                    self.out.extend(frame);
+                }
+            }
+        }
+
+        fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            while !self.out.is_empty() {
+                match Pin::new(&mut self.io).poll_write(cx, &self.out) {
+                    Poll::Ready(Ok(n)) => drop(self.out.drain(..n)),
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncRead for UpgradeClient {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().io).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for UpgradeClient {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            this.unparsed.extend_from_slice(buf);
+            this.split_frames();
+            // Whatever can't go now goes on the next flush
+            if let Poll::Ready(Err(e)) = this.poll_send(cx) {
+                return Poll::Ready(Err(e));
+            }
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            match this.poll_send(cx) {
+                Poll::Ready(Ok(())) => Pin::new(&mut this.io).poll_flush(cx),
+                other => other,
+            }
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn prior_knowledge_round_trip() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let served = tokio::spawn(serve(server_io, router(&hits), None, Timeouts::new()));
+
+        let mut client = connect(client_io).await;
+        for _ in 0..2 {
+            assert_eq!(get(&mut client, "/").await, (200, "hello HTTP/2.0".to_string()));
+        }
+        assert_eq!(get(&mut client, "/missing").await.0, 404);
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        drop(client);
+        tokio::time::timeout(Duration::from_secs(5), served).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn h2c_upgrade_round_trip() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let (client_io, server_io) = tokio::io::duplex(64 * 1024);
+        let upgrade = upgrade(&upgrade_request(Some(""))).unwrap();
+        let served = tokio::spawn(upgrade.serve(server_io, router(&hits), None, Timeouts::new()));
+
+        let (mut client, mut connection) = h2::client::handshake(UpgradeClient::new(client_io)).await.unwrap();
+        let mut ping = connection.ping_pong().unwrap();
+        tokio::spawn(connection);
+        assert_eq!(get(&mut client, "/").await, (200, "hello HTTP/2.0".to_string()));
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        // Further requests would need HPACK state the dropped frame left out of step,
+        // so just check the connection is still up
+        ping.ping(h2::Ping::opaque()).await.unwrap();
+
+        drop(client);
+        tokio::time::timeout(Duration::from_secs(5), served).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn upgraded_refuses_an_oversized_settings_frame() {
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let mut upgraded = Upgraded {
+            io: server,
+            head: Vec::new(),
+            frames: Some(Vec::new()),
+            pending: Vec::new(),
+        };
+        // A SETTINGS frame declaring a 1 MiB payload, which is never sent
+        client.write_all(PREFACE).await.unwrap();
+        client.write_all(&[0x10, 0, 0, 0x4, 0, 0, 0, 0, 0]).await.unwrap();
+        let mut buf = [0; 64];
+        let error = upgraded.read(&mut buf).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn preface_needs_four_bytes() {
+        assert!(is_preface(b"PRI "));
+        assert!(is_preface(PREFACE));
+        assert!(!is_preface(b"PRI"));
+        assert!(!is_preface(b"PRIX"));
+        assert!(!is_preface(b"GET / HTTP/1.1\r\n"));
+    }
+}
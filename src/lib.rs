@@ -0,0 +1,24 @@
+pub mod cache;
+pub mod compression;
+pub mod embed;
+pub mod error;
+pub mod http1;
+#[cfg(feature = "http2")]
+pub mod http2;
+pub mod limits;
+pub mod logging;
+pub mod metrics;
+pub mod middleware;
+pub mod mime;
+pub mod rate_limit;
+pub mod request_id;
+pub mod route;
+pub mod sendfile;
+pub mod static_files;
+pub mod stream;
+pub mod thread_pool;
+pub mod timeouts;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod types;
+pub mod websocket;
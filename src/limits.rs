@@ -0,0 +1,419 @@
+//Connection limits
+//A global cap on open connections and a per-client-IP cap on concurrent ones, so a
+//single misbehaving client can't tie up every worker. Connections over a limit are
+//either held until a slot frees up or refused (the server answers 503).
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+use tokio::sync::{oneshot, Notify};
+
+use crate::metrics;
+
+// What happens to a connection over a limit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    // Stop accepting while at the global limit, leaving clients in the listen
+    // backlog; park a client's extra connections until one of its own closes
+    Backlog,
+    // Accept and refuse straight away
+    Reject,
+}
+
+#[derive(Clone, Debug)]
+pub struct ConnectionLimits {
+    max_connections: Option<usize>,
+    max_per_ip: Option<usize>,
+    overflow: Overflow,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConnectionLimits {
+    // No limits
+    pub fn new() -> Self {
+        ConnectionLimits {
+            max_connections: None,
+            max_per_ip: None,
+            overflow: Overflow::Reject,
+        }
+    }
+
+    // Connections open at once, held ones included
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max.max(1));
+        self
+    }
+
+    // Connections one client IP may have served at once
+    pub fn with_max_per_ip(mut self, max: usize) -> Self {
+        self.max_per_ip = Some(max.max(1));
+        self
+    }
+
+    pub fn with_overflow(mut self, overflow: Overflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitExceeded {
+    Global,
+    PerIp,
+}
+
+impl LimitExceeded {
+    // Metrics label
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LimitExceeded::Global => "global",
+            LimitExceeded::PerIp => "per_ip",
+        }
+    }
+
+    // Text for the 503 page
+    pub fn public_message(&self) -> &'static str {
+        match self {
+            LimitExceeded::Global => "The server has too many open connections.",
+            LimitExceeded::PerIp => "Too many concurrent connections from your address.",
+        }
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitExceeded::Global => write!(f, "connection limit reached"),
+            LimitExceeded::PerIp => write!(f, "per-IP connection limit reached"),
+        }
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+// Called once a connection is admitted or refused
+type Waiter = Box<dyn FnOnce(Result<ConnectionPermit, LimitExceeded>) + Send>;
+
+struct State {
+    // Connections admitted or held
+    open: usize,
+    // Admitted connections by client
+    per_ip: HashMap<IpAddr, usize>,
+    // Connections parked behind a client's own, oldest first
+    held: HashMap<IpAddr, VecDeque<Waiter>>,
+}
+
+pub struct ConnectionLimiter {
+    limits: ConnectionLimits,
+    state: Mutex<State>,
+    // Signalled whenever a connection closes, for the threaded accept loop
+    released: Condvar,
+    // The same, for the async accept loop
+    released_async: Notify,
+}
+
+impl ConnectionLimiter {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        ConnectionLimiter {
+            limits,
+            state: Mutex::new(State {
+                open: 0,
+                per_ip: HashMap::new(),
+                held: HashMap::new(),
+            }),
+            released: Condvar::new(),
+            released_async: Notify::new(),
+        }
+    }
+
+    pub fn limits(&self) -> &ConnectionLimits {
+        &self.limits
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn at_capacity(&self, state: &State) -> bool {
+        self.limits.max_connections.is_some_and(|max| state.open >= max)
+    }
+
+    // With `Overflow::Backlog`, block the accept loop until there is room for
+    // another connection; returns straight away otherwise
+    pub fn wait_for_slot(&self) {
+        if self.limits.overflow != Overflow::Backlog {
+            return;
+        }
+        let mut state = self.lock();
+        while self.at_capacity(&state) {
+            state = self.released.wait(state).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    // `wait_for_slot` for the async accept loop
+    pub async fn wait_for_slot_async(&self) {
+        if self.limits.overflow != Overflow::Backlog {
+            return;
+        }
+        loop {
+            // Created before the check so a release in between isn't missed
+            let released = self.released_async.notified();
+            if !self.at_capacity(&self.lock()) {
+                return;
+            }
+            released.await;
+        }
+    }
+
+    // Admit a connection from `ip` (None when the peer address is unknown, which
+    // skips the per-IP cap). `on_admit` runs right away with a permit or the
+    // limit that refused it, or later, from whichever connection frees the slot,
+    // when the connection is held.
+    //
+    // That later call happens inside the `ConnectionPermit` drop of the finished
+    // connection, on whatever thread dropped it, so `on_admit` must not block. On
+    // the threaded engine that is a pool worker finishing its task: handing the
+    // connection to a pool with `QueuePolicy::Block` from there can deadlock once
+    // the queue is full, so `Overflow::Backlog` needs `Reject` or `DropOldest`.
+    pub fn admit<F>(self: &Arc<Self>, ip: Option<IpAddr>, on_admit: F)
+    where
+        F: FnOnce(Result<ConnectionPermit, LimitExceeded>) + Send + 'static,
+    {
+        let metrics = metrics::metrics();
+        let mut state = self.lock();
+        if self.at_capacity(&state) {
+            drop(state);
+            metrics.connections_limited.inc(&["global", "rejected"]);
+            return on_admit(Err(LimitExceeded::Global));
+        }
+
+        if let (Some(ip), Some(max)) = (ip, self.limits.max_per_ip) {
+            if state.per_ip.get(&ip).is_some_and(|&n| n >= max) {
+                if self.limits.overflow == Overflow::Backlog {
+                    let held = state.held.entry(ip).or_default();
+                    // A client gets to park as many connections as it may run
+                    if held.len() < max {
+                        held.push_back(Box::new(on_admit));
+                        state.open += 1;
+                        metrics.connections_limited.inc(&["per_ip", "held"]);
+                        metrics.connections_held.inc();
+                        return;
+                    }
+                }
+                drop(state);
+                metrics.connections_limited.inc(&["per_ip", "rejected"]);
+                return on_admit(Err(LimitExceeded::PerIp));
+            }
+        }
+
+        state.open += 1;
+        if let Some(ip) = ip {
+            *state.per_ip.entry(ip).or_insert(0) += 1;
+        }
+        drop(state);
+        on_admit(Ok(ConnectionPermit {
+            limiter: Arc::clone(self),
+            ip,
+        }))
+    }
+
+    // `admit` for async callers: the connection is admitted or refused now, and
+    // the future resolves once a held connection gets its permit
+    pub fn acquire(self: &Arc<Self>, ip: Option<IpAddr>) -> impl Future<Output = Result<ConnectionPermit, LimitExceeded>> {
+        let (sender, receiver) = oneshot::channel();
+        self.admit(ip, move |admission| {
+            // If the waiting task is gone, the permit drops here and is passed on
+            let _ = sender.send(admission);
+        });
+        async move { receiver.await.unwrap_or(Err(LimitExceeded::Global)) }
+    }
+
+    // Connections currently admitted or held
+    pub fn open(&self) -> usize {
+        self.lock().open
+    }
+
+    fn release(self: &Arc<Self>, ip: Option<IpAddr>) {
+        let mut state = self.lock();
+        state.open -= 1;
+        // The oldest connection held behind this one takes over its slot
+        let next = ip.and_then(|ip| {
+            let held = state.held.get_mut(&ip)?;
+            let next = held.pop_front();
+            if held.is_empty() {
+                state.held.remove(&ip);
+            }
+            next
+        });
+        if next.is_none() {
+            if let Some(ip) = ip {
+                if let Some(count) = state.per_ip.get_mut(&ip) {
+                    *count -= 1;
+                    if *count == 0 {
+                        state.per_ip.remove(&ip);
+                    }
+                }
+            }
+        }
+        self.released.notify_all();
+        self.released_async.notify_waiters();
+        drop(state);
+
+        if let Some(next) = next {
+            metrics::metrics().connections_held.dec();
+            next(Ok(ConnectionPermit {
+                limiter: Arc::clone(self),
+                ip,
+            }));
+        }
+    }
+}
+
+// A connection's slot; dropping it frees the slot, and may admit a held
+// connection then and there (see `admit`)
+pub struct ConnectionPermit {
+    limiter: Arc<ConnectionLimiter>,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.limiter.release(self.ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    const CLIENT: Option<IpAddr> = Some(IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1)));
+    const OTHER: Option<IpAddr> = Some(IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2)));
+
+    fn limiter(limits: ConnectionLimits) -> Arc<ConnectionLimiter> {
+        Arc::new(ConnectionLimiter::new(limits))
+    }
+
+    // What `on_admit` was called with, if it has been called yet
+    fn admit(limiter: &Arc<ConnectionLimiter>, ip: Option<IpAddr>) -> mpsc::Receiver<Result<ConnectionPermit, LimitExceeded>> {
+        let (sender, receiver) = mpsc::channel();
+        limiter.admit(ip, move |admission| sender.send(admission).unwrap());
+        receiver
+    }
+
+    fn admitted(receiver: &mpsc::Receiver<Result<ConnectionPermit, LimitExceeded>>) -> ConnectionPermit {
+        receiver.try_recv().expect("on_admit wasn't called").expect("connection was refused")
+    }
+
+    fn per_ip(limiter: &ConnectionLimiter, ip: Option<IpAddr>) -> usize {
+        limiter.lock().per_ip.get(&ip.unwrap()).copied().unwrap_or(0)
+    }
+
+    #[test]
+    fn global_cap_refuses() {
+        let limiter = limiter(ConnectionLimits::new().with_max_connections(2));
+        let first = admitted(&admit(&limiter, CLIENT));
+        let _second = admitted(&admit(&limiter, OTHER));
+        let refused = admit(&limiter, None).try_recv().unwrap();
+        assert_eq!(refused.err(), Some(LimitExceeded::Global));
+        assert_eq!(limiter.open(), 2);
+
+        drop(first);
+        assert_eq!(limiter.open(), 1);
+        assert!(admit(&limiter, None).try_recv().unwrap().is_ok());
+    }
+
+    #[test]
+    fn per_ip_cap_rejects() {
+        let limiter = limiter(ConnectionLimits::new().with_max_per_ip(1));
+        let _first = admitted(&admit(&limiter, CLIENT));
+        assert_eq!(admit(&limiter, CLIENT).try_recv().unwrap().err(), Some(LimitExceeded::PerIp));
+        // Other clients, and ones with no known address, aren't affected
+        let _other = admitted(&admit(&limiter, OTHER));
+        let _unknown = admitted(&admit(&limiter, None));
+        assert_eq!(limiter.open(), 3);
+        assert_eq!(per_ip(&limiter, CLIENT), 1);
+    }
+
+    #[test]
+    fn per_ip_cap_holds_with_backlog() {
+        let limiter = limiter(ConnectionLimits::new().with_max_per_ip(1).with_overflow(Overflow::Backlog));
+        let first = admitted(&admit(&limiter, CLIENT));
+        let held = admit(&limiter, CLIENT);
+        assert!(held.try_recv().is_err(), "held, so not answered yet");
+        // Only as many may wait as the client may run
+        assert_eq!(admit(&limiter, CLIENT).try_recv().unwrap().err(), Some(LimitExceeded::PerIp));
+        assert_eq!(limiter.open(), 2);
+        assert_eq!(per_ip(&limiter, CLIENT), 1);
+
+        // The held connection takes over the slot, keeping the counts as they were
+        drop(first);
+        let second = admitted(&held);
+        assert_eq!(limiter.open(), 1);
+        assert_eq!(per_ip(&limiter, CLIENT), 1);
+        assert!(limiter.lock().held.is_empty());
+
+        drop(second);
+        assert_eq!(limiter.open(), 0);
+        assert_eq!(per_ip(&limiter, CLIENT), 0);
+        assert!(limiter.lock().per_ip.is_empty());
+    }
+
+    #[tokio::test]
+    async fn abandoned_acquire_passes_its_permit_on() {
+        let limiter = limiter(ConnectionLimits::new().with_max_per_ip(2).with_overflow(Overflow::Backlog));
+        let first = limiter.acquire(CLIENT).await.unwrap();
+        let _second = limiter.acquire(CLIENT).await.unwrap();
+        let abandoned = limiter.acquire(CLIENT);
+        let waiting = tokio::spawn(limiter.acquire(CLIENT));
+        assert_eq!(limiter.open(), 4);
+
+        drop(abandoned);
+        drop(first);
+        let fourth = tokio::time::timeout(Duration::from_secs(1), waiting).await.unwrap().unwrap();
+        assert!(fourth.is_ok());
+        assert_eq!(limiter.open(), 2);
+        assert_eq!(per_ip(&limiter, CLIENT), 2);
+    }
+
+    #[tokio::test]
+    async fn wait_for_slot_async_wakes_on_release() {
+        let limiter = limiter(ConnectionLimits::new().with_max_connections(1).with_overflow(Overflow::Backlog));
+        let permit = limiter.acquire(CLIENT).await.unwrap();
+        let waiter = {
+            let limiter = Arc::clone(&limiter);
+            tokio::spawn(async move { limiter.wait_for_slot_async().await })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiter.is_finished());
+
+        drop(permit);
+        tokio::time::timeout(Duration::from_secs(1), waiter).await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn wait_for_slot_wakes_on_release() {
+        let limiter = limiter(ConnectionLimits::new().with_max_connections(1).with_overflow(Overflow::Backlog));
+        let permit = admitted(&admit(&limiter, CLIENT));
+        let (done, finished) = mpsc::channel();
+        let waiter = {
+            let limiter = Arc::clone(&limiter);
+            std::thread::spawn(move || {
+                limiter.wait_for_slot();
+                done.send(()).unwrap();
+            })
+        };
+        assert!(finished.recv_timeout(Duration::from_millis(50)).is_err());
+        drop(permit);
+        finished.recv_timeout(Duration::from_secs(1)).unwrap();
+        waiter.join().unwrap();
+    }
+}
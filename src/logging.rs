@@ -0,0 +1,443 @@
+//Structured logging
+//Access log entries (one per request) and server events go through a global `Logger`,
+//rendered as Common/Combined Log Format or JSON lines and written to pluggable sinks.
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::static_files::{civil_from_days, format_timestamp};
+use crate::types::{Request, Response};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    // host ident user [time] "request" status bytes
+    Common,
+    // Common plus "referer" "user-agent"
+    Combined,
+    JsonLines,
+}
+
+pub struct AccessLogEntry {
+    pub time: SystemTime,
+    pub method: String,
+    pub path: String,
+    pub protocol: String,
+    pub status: u16,
+    pub bytes: u64,
+    pub latency: Duration,
+    pub peer_addr: Option<SocketAddr>,
+    pub user_agent: Option<String>,
+    pub referer: Option<String>,
+    pub request_id: Option<String>,
+}
+
+impl AccessLogEntry {
+    // Entry for a finished exchange; `request` is None when the request couldn't be
+    // parsed, and the ID is then read from the response's `id_header`
+    pub fn new(
+        request: Option<&Request>,
+        response: &Response,
+        id_header: &str,
+        peer_addr: Option<SocketAddr>,
+        started: Instant,
+    ) -> Self {
+        AccessLogEntry {
+            time: SystemTime::now(),
+            method: request.map_or("-".to_string(), |r| r.method.clone()),
+            path: request.map_or("-".to_string(), |r| r.path.clone()),
+            protocol: request.map_or("-".to_string(), |r| r.version.clone()),
+            status: response.status,
+            bytes: response.content_length(),
+            latency: started.elapsed(),
+            peer_addr,
+            user_agent: request.and_then(|r| r.header("User-Agent")).map(str::to_string),
+            referer: request.and_then(|r| r.header("Referer")).map(str::to_string),
+            request_id: request
+                .map(|r| r.id.clone())
+                .filter(|id| !id.is_empty())
+                .or_else(|| response.headers.get(id_header).cloned()),
+        }
+    }
+
+    pub fn format(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Common | LogFormat::Combined => {
+                let mut line = format!(
+                    "{} - - [{}] \"{} {} {}\" {} {}",
+                    self.peer_addr.map_or("-".to_string(), |addr| addr.ip().to_string()),
+                    format_clf_time(self.time),
+                    self.method,
+                    self.path,
+                    self.protocol,
+                    self.status,
+                    if self.bytes == 0 { "-".to_string() } else { self.bytes.to_string() }
+                );
+                if format == LogFormat::Combined {
+                    line.push_str(&format!(
+                        " \"{}\" \"{}\"",
+                        clf_escape(self.referer.as_deref().unwrap_or("-")),
+                        clf_escape(self.user_agent.as_deref().unwrap_or("-"))
+                    ));
+                }
+                // Not part of CLF proper, but needed to correlate with other logs
+                if let Some(id) = &self.request_id {
+                    line.push_str(&format!(" {}", id));
+                }
+                line
+            }
+            LogFormat::JsonLines => format!(
+                "{{\"time\":\"{}\",\"type\":\"access\",\"method\":{},\"path\":{},\"protocol\":{},\"status\":{},\"bytes\":{},\"latency_ms\":{:.3},\"peer\":{},\"user_agent\":{},\"referer\":{},\"request_id\":{}}}",
+                format_timestamp(self.time),
+                json_string(&self.method),
+                json_string(&self.path),
+                json_string(&self.protocol),
+                self.status,
+                self.bytes,
+                self.latency.as_secs_f64() * 1000.0,
+                json_option(self.peer_addr.map(|addr| addr.to_string()).as_deref()),
+                json_option(self.user_agent.as_deref()),
+                json_option(self.referer.as_deref()),
+                json_option(self.request_id.as_deref())
+            ),
+        }
+    }
+}
+
+pub trait LogSink: Send + Sync {
+    fn write_line(&self, line: &str);
+}
+
+pub struct StdoutSink;
+
+impl LogSink for StdoutSink {
+    fn write_line(&self, line: &str) {
+        let _ = writeln!(io::stdout().lock(), "{}", line);
+    }
+}
+
+pub struct StderrSink;
+
+impl LogSink for StderrSink {
+    fn write_line(&self, line: &str) {
+        let _ = writeln!(io::stderr().lock(), "{}", line);
+    }
+}
+
+struct FileSinkState {
+    file: File,
+    size: u64,
+}
+
+// Appends to a file, rotating `access.log` -> `access.log.1` -> ... once it grows past a size
+pub struct FileSink {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    max_files: usize,
+    state: Mutex<FileSinkState>,
+}
+
+impl FileSink {
+    pub fn new<P: Into<PathBuf>>(path: P) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(FileSink {
+            path,
+            max_bytes: None,
+            max_files: 0,
+            state: Mutex::new(FileSinkState { file, size }),
+        })
+    }
+
+    // Keep at most `max_files` rotated files of roughly `max_bytes` each
+    pub fn with_rotation(mut self, max_bytes: u64, max_files: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self.max_files = max_files;
+        self
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&self, state: &mut FileSinkState) -> io::Result<()> {
+        if self.max_files == 0 {
+            // No history kept, just start over
+            state.file = File::create(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.rotated_path(self.max_files));
+            for index in (1..self.max_files).rev() {
+                let _ = fs::rename(self.rotated_path(index), self.rotated_path(index + 1));
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+            state.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+        state.size = 0;
+        Ok(())
+    }
+}
+
+impl LogSink for FileSink {
+    fn write_line(&self, line: &str) {
+        let mut state = self.state.lock().unwrap();
+        let len = line.len() as u64 + 1;
+        if let Some(max_bytes) = self.max_bytes {
+            if state.size > 0 && state.size + len > max_bytes {
+                if let Err(e) = self.rotate(&mut state) {
+                    let _ = writeln!(io::stderr(), "Error rotating log file {:?}: {}", self.path, e);
+                }
+            }
+        }
+        if writeln!(state.file, "{}", line).is_ok() {
+            state.size += len;
+        }
+    }
+}
+
+pub struct Logger {
+    level: Level,
+    format: LogFormat,
+    access_sinks: Vec<Box<dyn LogSink>>,
+    event_sinks: Vec<Box<dyn LogSink>>,
+}
+
+impl Default for Logger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Logger {
+    // Access lines to stdout and events to stderr until sinks are added
+    pub fn new() -> Self {
+        Logger {
+            level: Level::Info,
+            format: LogFormat::Common,
+            access_sinks: Vec::new(),
+            event_sinks: Vec::new(),
+        }
+    }
+
+    // Events less severe than this are dropped; access lines count as Info
+    pub fn with_level(mut self, level: Level) -> Self {
+        self.level = level;
+        self
+    }
+
+    pub fn with_format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn with_access_sink<S: LogSink + 'static>(mut self, sink: S) -> Self {
+        self.access_sinks.push(Box::new(sink));
+        self
+    }
+
+    pub fn with_event_sink<S: LogSink + 'static>(mut self, sink: S) -> Self {
+        self.event_sinks.push(Box::new(sink));
+        self
+    }
+
+    pub fn enabled(&self, level: Level) -> bool {
+        level <= self.level
+    }
+
+    pub fn access(&self, entry: &AccessLogEntry) {
+        if !self.enabled(Level::Info) {
+            return;
+        }
+        let line = entry.format(self.format);
+        if self.access_sinks.is_empty() {
+            StdoutSink.write_line(&line);
+        }
+        for sink in &self.access_sinks {
+            sink.write_line(&line);
+        }
+    }
+
+    pub fn log(&self, level: Level, message: fmt::Arguments) {
+        if !self.enabled(level) {
+            return;
+        }
+        let now = SystemTime::now();
+        let request_id = current_request_id();
+        let line = match self.format {
+            LogFormat::JsonLines => format!(
+                "{{\"time\":\"{}\",\"type\":\"event\",\"level\":\"{}\",\"request_id\":{},\"message\":{}}}",
+                format_timestamp(now),
+                level.as_str(),
+                json_option(request_id.as_deref()),
+                json_string(&message.to_string())
+            ),
+            _ => match request_id {
+                Some(id) => format!("[{}] {} [{}] {}", format_timestamp(now), level.as_str(), id, message),
+                None => format!("[{}] {} {}", format_timestamp(now), level.as_str(), message),
+            },
+        };
+        if self.event_sinks.is_empty() {
+            StderrSink.write_line(&line);
+        }
+        for sink in &self.event_sinks {
+            sink.write_line(&line);
+        }
+    }
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+thread_local! {
+    static CURRENT_REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// Tags event lines logged on this thread with a request ID until dropped
+pub struct RequestIdScope {
+    previous: Option<String>,
+}
+
+impl RequestIdScope {
+    pub fn enter(id: &str) -> Self {
+        let previous = CURRENT_REQUEST_ID.with(|current| current.replace(Some(id.to_string())));
+        RequestIdScope { previous }
+    }
+}
+
+impl Drop for RequestIdScope {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT_REQUEST_ID.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+pub fn current_request_id() -> Option<String> {
+    CURRENT_REQUEST_ID.with(|current| current.borrow().clone())
+}
+
+// Install the process-wide logger; fails if one is already installed
+pub fn init(logger: Logger) -> Result<(), Logger> {
+    LOGGER.set(logger)
+}
+
+pub fn logger() -> &'static Logger {
+    LOGGER.get_or_init(Logger::new)
+}
+
+pub fn access(entry: &AccessLogEntry) {
+    logger().access(entry);
+}
+
+pub fn log(level: Level, message: fmt::Arguments) {
+    logger().log(level, message);
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => { $crate::logging::log($crate::logging::Level::Error, format_args!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)*) => { $crate::logging::log($crate::logging::Level::Warn, format_args!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => { $crate::logging::log($crate::logging::Level::Info, format_args!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => { $crate::logging::log($crate::logging::Level::Debug, format_args!($($arg)*)) };
+}
+
+// e.g. 10/Oct/2000:13:55:36 +0000
+fn format_clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let rem = secs % 86_400;
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+fn clf_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_option(value: Option<&str>) -> String {
+    value.map_or("null".to_string(), json_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unparsed_requests_take_the_id_from_the_configured_header() {
+        let response = Response::new().with_status(400).with_header("X-Trace-Id", "abc123");
+        let entry = AccessLogEntry::new(None, &response, "X-Trace-Id", None, Instant::now());
+        assert_eq!(entry.request_id.as_deref(), Some("abc123"));
+        let entry = AccessLogEntry::new(None, &response, "X-Request-Id", None, Instant::now());
+        assert_eq!(entry.request_id, None);
+    }
+
+    #[test]
+    fn parsed_requests_use_their_own_id() {
+        let request = Request { id: "from-request".to_string(), ..Request::default() };
+        let response = Response::new().with_header("X-Trace-Id", "from-response");
+        let entry = AccessLogEntry::new(Some(&request), &response, "X-Trace-Id", None, Instant::now());
+        assert_eq!(entry.request_id.as_deref(), Some("from-request"));
+    }
+}
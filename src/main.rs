@@ -1,78 +1,167 @@
-mod thread_pool;
-mod types;
-mod route;
-
-use std::io::{BufRead, BufReader, Write};
-use std::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener as TokioTcpListener, TcpStream as TokioTcpStream};
-use thread_pool::ThreadPool;
-use route::{Router, parse_request, response_to_string};
-use types::Response;
+use hs::thread_pool::{PoolConfig, QueuePolicy, Task, ThreadPool};
+use hs::route::Router;
+use hs::cache::StaticCache;
+use hs::compression::CompressionConfig;
+use hs::error::{HandlerResult, HsError};
+use hs::http1::{self, response_id, write_response, write_response_async};
+#[cfg(feature = "http2")]
+use hs::http2;
+use hs::limits::{ConnectionLimiter, ConnectionLimits, ConnectionPermit, Overflow};
+use hs::static_files::{ListingFormat, StaticConfig};
+use hs::logging::{self, AccessLogEntry, LogFormat, Logger};
+use hs::metrics::{metrics, ConnectionGuard};
+use hs::rate_limit::RateLimiter;
+use hs::stream::{AsyncStream, Stream};
+use hs::timeouts::Timeouts;
+#[cfg(feature = "tls")]
+use hs::tls::{Tls, TlsConfig};
+use hs::types::Response;
+use hs::websocket::Message;
+use hs::{log_debug, log_error, log_info, log_warn};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// A threaded-engine connection waiting for a pool worker
+struct Connection {
+    stream: Stream,
+    router: Arc<Router>,
+    timeouts: Timeouts,
+    // Frees the connection's slot once it is served or shed
+    _permit: ConnectionPermit,
+}
 
-fn handle_client(mut stream: TcpStream, router: Arc<Router>) {
-    let buf_reader = BufReader::new(&stream);
-    let request_lines: Vec<String> = buf_reader
-        .lines()
-        .map(|result| result.unwrap())
-        .take_while(|line| !line.is_empty())
-        .collect();
-
-    let response = match parse_request(&request_lines) {
-        Some(mut request) => {
-            println!("Request: {} {}", request.method, request.path);
-            println!("Headers:");
-            for (key, value) in &request.headers {
-                println!("  {}: {}", key, value);
-            }
+impl Task for Connection {
+    fn run(self: Box<Self>) {
+        http1::serve(self.stream, &self.router, self.timeouts);
+    }
 
-            // Route the request
-            router.route(&mut request).unwrap_or_else(|| {
-                // Return 404 Not Found if no route matches
-                Response::not_found()
-            })
-        },
-        None => Response::new().with_status(400) // Bad Request if parsing fails
-    };
+    // Shed by the pool: tell the client to come back later rather than just closing
+    fn reject(self: Box<Self>) {
+        reject_connection(self.stream, &self.router, "The server is too busy to handle this request.");
+    }
+}
+
+// Answer 503 without reading the request
+fn reject_connection(mut stream: Stream, router: &Router, message: &str) {
+    // This runs on the accept thread, and over TLS a 503 needs a handshake first,
+    // which a slow client can drag out; close the socket before TLS gets involved
+    #[cfg(feature = "tls")]
+    if let Stream::Tls(tls) = &stream {
+        let _ = tls.sock.shutdown(Shutdown::Both);
+        return refuse_unanswered(router, message, stream.peer_addr().ok());
+    }
+    let _connection = ConnectionGuard::new();
+    let started = Instant::now();
+    let response = rejection(router, message);
+    // Don't let a slow client stall the accept loop
+    let deadline = started + Duration::from_secs(1);
+    let _ = stream.tcp().set_write_timeout(Some(Duration::from_secs(1)));
+    match write_response(&mut stream, &response) {
+        Ok(()) => close_unread(stream.tcp(), deadline),
+        Err(e) => log_debug!("Error writing 503 for {}: {}", response_id(router, &response), e),
+    }
+    record_rejection(router, &response, stream.peer_addr().ok(), started);
+}
 
-    // Convert response to HTTP string and send
-    let response_string = response_to_string(&response);
-    stream.write_all(response_string.as_bytes()).unwrap();
+// The 503 for a connection refused before its request was read
+fn rejection(router: &Router, message: &str) -> Response {
+    router
+        .reject(HsError::Status(503, message.to_string()))
+        .with_header("Retry-After", RETRY_AFTER_SECS)
+        .with_header("Connection", "close")
 }
 
-async fn handle_client_async(mut stream: TokioTcpStream, router: Arc<Router>) {
-    let (reader, mut writer) = stream.split();
-    let mut buf_reader = tokio::io::BufReader::new(reader);
-    let mut request_lines = Vec::new();
-    let mut line = String::new();
+// Log and count a refused connection like any other exchange
+fn record_rejection(router: &Router, response: &Response, peer_addr: Option<SocketAddr>, started: Instant) {
+    metrics().observe_request(None, response, started.elapsed(), 0);
+    logging::access(&AccessLogEntry::new(None, response, router.request_id_header(), peer_addr, started));
+}
 
-    loop {
-        line.clear();
-        if buf_reader.read_line(&mut line).await.unwrap() == 0 {
+// Record a TLS connection closed without a handshake, as a 503 that sent nothing
+#[cfg(feature = "tls")]
+fn refuse_unanswered(router: &Router, message: &str, peer_addr: Option<SocketAddr>) {
+    log_debug!("Closed TLS connection from {:?} without a response", peer_addr);
+    let response = rejection(router, message).with_body_bytes(Vec::new());
+    record_rejection(router, &response, peer_addr, Instant::now());
+}
+
+// Seconds clients are asked to wait after a 503
+const RETRY_AFTER_SECS: &str = "1";
+
+// Most of an unread request thrown away while closing after a 503
+const MAX_DRAIN_BYTES: usize = 64 * 1024;
+
+// Closing with the request still unread makes Linux send an RST, which can cost the
+// client the response it hasn't read yet; end our side and read until the client
+// closes too, or until `deadline`
+fn close_unread(stream: &TcpStream, deadline: Instant) {
+    let _ = stream.shutdown(Shutdown::Write);
+    let mut buf = [0; 4096];
+    let mut drained = 0;
+    while drained < MAX_DRAIN_BYTES {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() || stream.set_read_timeout(Some(left)).is_err() {
             break;
         }
-        if line == "\r\n" {
-            break;
+        match (&*stream).read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => drained += n,
         }
-        request_lines.push(line.trim().to_string());
     }
+}
 
-    let response = match parse_request(&request_lines) {
-        Some(mut request) => {
-            println!("Async Request: {} {}", request.method, request.path);
-
-            // Route the request
-            router.route(&mut request).unwrap_or_else(|| {
-                // Return 404 Not Found if no route matches
-                Response::not_found()
-            })
-        },
-        None => Response::new().with_status(400) // Bad Request if parsing fails
+// `close_unread` for the async engine
+async fn close_unread_async(stream: &mut AsyncStream, deadline: Instant) {
+    let drain = async {
+        stream.shutdown().await?;
+        let mut buf = [0; 4096];
+        let mut drained = 0;
+        while drained < MAX_DRAIN_BYTES {
+            match stream.read(&mut buf).await? {
+                0 => break,
+                n => drained += n,
+            }
+        }
+        io::Result::Ok(())
     };
+    let _ = tokio::time::timeout_at(deadline.into(), drain).await;
+}
 
-    let response_string = response_to_string(&response);
-    writer.write_all(response_string.as_bytes()).await.unwrap();
+async fn handle_client_async(stream: AsyncStream, router: Arc<Router>, timeouts: Timeouts) {
+    #[cfg(feature = "http2")]
+    if stream.alpn_protocol() == Some(http2::ALPN.as_bytes()) {
+        let _connection = ConnectionGuard::new();
+        let peer_addr = stream.peer_addr().ok();
+        return http2::serve(stream, router, peer_addr, timeouts).await;
+    }
+    http1::serve_async(stream, router, timeouts).await
+}
+
+// `reject_connection` for the async engine
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+async fn reject_connection_async(stream: TokioTcpStream, tls: bool, router: &Router, message: &str) {
+    // As on the threaded engine, only admitted connections get a TLS handshake
+    #[cfg(feature = "tls")]
+    if tls {
+        let peer_addr = stream.peer_addr().ok();
+        drop(stream);
+        return refuse_unanswered(router, message, peer_addr);
+    }
+    let mut stream = AsyncStream::Plain(stream);
+    let _connection = ConnectionGuard::new();
+    let started = Instant::now();
+    let response = rejection(router, message);
+    let deadline = started + Duration::from_secs(1);
+    match tokio::time::timeout_at(deadline.into(), write_response_async(&mut stream, &response)).await {
+        Ok(Ok(())) => close_unread_async(&mut stream, deadline).await,
+        Ok(Err(e)) => log_debug!("Error writing 503 for {}: {}", response_id(router, &response), e),
+        Err(_) => {}
+    }
+    record_rejection(router, &response, stream.peer_addr().ok(), started);
 }
 
 fn create_router() -> Router {
@@ -80,29 +169,79 @@ fn create_router() -> Router {
 
     router.set_static_dir("./public");
 
+    // Errors render public/<status>.html when present, e.g. public/404.html
+    router.error_pages("./public");
+
+    router.enable_compression(CompressionConfig::new());
+
+    // Serve hot assets from memory, up to 64 MiB
+    router.set_static_cache(StaticCache::new(64 * 1024 * 1024));
+
     router.serve_static("/static", "./public");
 
+    // Single-page app: client-side routes get index.html, /app/api misses stay 404
+    router.serve_static_with("/app", "./public", StaticConfig::new().with_spa_fallback("index.html").with_spa_exclude("/app/api"));
+
+    // Copy of ./public compiled into the binary
+    router.serve_embedded("/assets", hs::embed::ASSETS);
+
+    // Browsable view of the same directory
+    router.serve_static_with("/files", "./public", StaticConfig::new().without_index_files().with_listing(ListingFormat::Html));
+
     // Add a route for the home page
     router.get("/", |_req| {
         Response::html("<html><body><h1>Welcome to Rust HTTP Server!</h1><p>Home page</p></body></html>")
     });
 
-    // Route with dynamic parameter
-    router.get("/user/:id", |req| {
-        let user_id = req.params.get("id").unwrap_or(&"unknown".to_string()).clone();
-        Response::html(&format!(
+    // Route with dynamic parameter; bad IDs become a 400 from the error renderer
+    router.get("/user/:id", |req| -> HandlerResult {
+        let user_id: u64 = req
+            .params
+            .get("id")
+            .and_then(|id| id.parse().ok())
+            .ok_or_else(|| HsError::BadRequest("User IDs are numeric.".to_string()))?;
+        Ok(Response::html(&format!(
             "<html><body><h1>User Profile</h1><p>User ID: {}</p></body></html>",
             user_id
-        ))
+        )))
     });
 
+    // Prometheus scrape target
+    router.metrics_endpoint("/metrics");
+
+    // Public API: 60 requests a minute per API key, or per IP without one. Keys
+    // aren't checked against anything, so the per-IP cap in front keeps a client
+    // from minting a fresh key for every request
+    router.middleware("/api", RateLimiter::new(120, Duration::from_secs(60)));
+    router.middleware("/api", RateLimiter::new(60, Duration::from_secs(60)).with_header_key("X-Api-Key"));
+
     // API route example
     router.get("/api/status", |_req| {
         Response::json("{\"status\":\"online\",\"version\":\"1.0\"}".to_string())
     });
 
+    // WebSocket example: echoes messages back to the client
+    router.websocket("/ws/:room", |request, mut socket| async move {
+        let room = request.params.get("room").cloned().unwrap_or_default();
+        log_debug!("WebSocket joined room {}", room);
+        while let Some(message) = socket.recv().await {
+            let reply = match message {
+                Ok(Message::Text(text)) => Message::Text(format!("[{}] {}", room, text)),
+                Ok(Message::Binary(data)) => Message::Binary(data),
+                Ok(_) => continue,
+                Err(e) => {
+                    log_debug!("WebSocket error in room {}: {}", room, e);
+                    break;
+                }
+            };
+            if socket.send(reply).await.is_err() {
+                break;
+            }
+        }
+    });
+
     // Post example
-    router.post("/api/data", |req| {
+    router.post("/api/data", |_req| {
         // In a real application, you would parse the body here
         Response::json("{\"success\":true,\"message\":\"Data received\"}".to_string())
     });
@@ -110,42 +249,139 @@ fn create_router() -> Router {
     router
 }
 
-fn run_threaded_server(router: Arc<Router>) {
-    let listener = TcpListener::bind("127.0.0.1:8080").unwrap();
-    let pool = ThreadPool::new(4);
-    println!("Threaded server listening on port 8080");
+// Without the `tls` feature there is no TLS to configure; the servers keep one signature
+#[cfg(not(feature = "tls"))]
+enum Tls {}
+
+// Wrap an accepted connection for the threaded engine; the TLS handshake happens
+// on the worker, within the header timeout
+fn threaded_stream(stream: TcpStream, tls: Option<&Tls>) -> io::Result<Stream> {
+    match tls {
+        None => Ok(Stream::Plain(stream)),
+        #[cfg(feature = "tls")]
+        Some(tls) => Ok(Stream::Tls(Box::new(tls.accept(stream)?))),
+        #[cfg(not(feature = "tls"))]
+        Some(tls) => match *tls {},
+    }
+}
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                println!("New connection: {}", stream.peer_addr().unwrap());
-                let router_clone = Arc::clone(&router);
-                pool.execute(move || {
-                    handle_client(stream, router_clone);
+// Wrap an accepted connection for the tokio engine, running the TLS handshake
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+async fn async_stream(stream: TokioTcpStream, tls: Option<&Tls>, timeout: Duration) -> io::Result<AsyncStream> {
+    match tls {
+        None => Ok(AsyncStream::Plain(stream)),
+        #[cfg(feature = "tls")]
+        Some(tls) => match tokio::time::timeout(timeout, tls.accept_async(stream)).await {
+            Ok(stream) => Ok(AsyncStream::Tls(Box::new(stream?))),
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out")),
+        },
+        #[cfg(not(feature = "tls"))]
+        Some(tls) => match *tls {},
+    }
+}
+
+fn run_threaded_server(router: Arc<Router>, addr: &str, tls: Option<Arc<Tls>>) {
+    let listener = TcpListener::bind(addr).unwrap();
+    // 4 workers, growing to 16 under load; past 256 waiting connections,
+    // new ones get a 503 instead of queueing forever
+    let pool = Arc::new(ThreadPool::with_config(
+        PoolConfig::new()
+            .with_min_workers(4)
+            .with_max_workers(16)
+            .with_keep_alive(Duration::from_secs(30))
+            .with_queue_capacity(256)
+            .with_policy(QueuePolicy::Reject),
+    ));
+    // Every connection holds a worker, so keep one client from taking them all;
+    // past either limit the client gets a 503
+    let limiter = Arc::new(ConnectionLimiter::new(
+        ConnectionLimits::new()
+            .with_max_connections(512)
+            .with_max_per_ip(8)
+            .with_overflow(Overflow::Reject),
+    ));
+    // Idle keep-alive connections hold a worker too, so drop them quickly
+    let timeouts = Timeouts::new()
+        .with_header_read(Duration::from_secs(5))
+        .with_keep_alive_idle(Duration::from_secs(2));
+    log_info!("Threaded server listening on {}{}", addr, if tls.is_some() { " (TLS)" } else { "" });
+
+    loop {
+        limiter.wait_for_slot();
+        match listener.accept() {
+            Ok((stream, addr)) => {
+                log_debug!("New connection: {}", addr);
+                let stream = match threaded_stream(stream, tls.as_deref()) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        log_error!("Error setting up TLS for {}: {}", addr, e);
+                        continue;
+                    }
+                };
+                let router = Arc::clone(&router);
+                let pool = Arc::clone(&pool);
+                limiter.admit(Some(addr.ip()), move |admission| match admission {
+                    Ok(permit) => {
+                        let connection = Connection { stream, router, timeouts, _permit: permit };
+                        if let Err(e) = pool.execute_task(Box::new(connection)) {
+                            log_warn!("Shed connection: {}", e);
+                        }
+                    }
+                    Err(limit) => {
+                        log_warn!("Refused connection from {}: {}", addr, limit);
+                        reject_connection(stream, &router, limit.public_message());
+                    }
                 });
             }
             Err(e) => {
-                eprintln!("Error accepting connection: {}", e);
+                log_error!("Error accepting connection: {}", e);
             }
         }
     }
 }
 
-async fn run_async_server(router: Arc<Router>) {
-    let listener = TokioTcpListener::bind("127.0.0.1:8081").await.unwrap();
-    println!("Async server listening on port 8081");
+async fn run_async_server(router: Arc<Router>, addr: &str, tls: Option<Arc<Tls>>) {
+    let listener = TokioTcpListener::bind(addr).await.unwrap();
+    // Idle connections are cheap here, so over the limit they wait rather than fail
+    let limiter = Arc::new(ConnectionLimiter::new(
+        ConnectionLimits::new()
+            .with_max_connections(10_000)
+            .with_max_per_ip(64)
+            .with_overflow(Overflow::Backlog),
+    ));
+    let timeouts = Timeouts::new();
+    log_info!("Async server listening on {}{}", addr, if tls.is_some() { " (TLS)" } else { "" });
 
     loop {
+        limiter.wait_for_slot_async().await;
         match listener.accept().await {
             Ok((stream, addr)) => {
-                println!("New connection: {}", addr);
+                log_debug!("New connection: {}", addr);
                 let router_clone = Arc::clone(&router);
+                let tls = tls.clone();
+                // Admitted here, so the accept loop sees the slot taken
+                let admission = limiter.acquire(Some(addr.ip()));
                 tokio::spawn(async move {
-                    handle_client_async(stream, router_clone).await;
+                    // Held connections wait here, before any TLS handshake is spent on them
+                    let _permit = match admission.await {
+                        Ok(permit) => permit,
+                        Err(limit) => {
+                            log_warn!("Refused connection from {}: {}", addr, limit);
+                            return reject_connection_async(stream, tls.is_some(), &router_clone, limit.public_message()).await;
+                        }
+                    };
+                    let stream = match async_stream(stream, tls.as_deref(), timeouts.header_read()).await {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            log_debug!("TLS handshake with {} failed: {}", addr, e);
+                            return;
+                        }
+                    };
+                    handle_client_async(stream, router_clone, timeouts).await;
                 });
             }
             Err(e) => {
-                eprintln!("Error accepting connection: {}", e);
+                log_error!("Error accepting connection: {}", e);
             }
         }
     }
@@ -153,6 +389,9 @@ async fn run_async_server(router: Arc<Router>) {
 
 #[tokio::main]
 async fn main() {
+    // Combined Log Format access lines on stdout, events on stderr
+    let _ = logging::init(Logger::new().with_format(LogFormat::Combined));
+
     // Create a router with our routes
     let router = Arc::new(create_router());
 
@@ -161,11 +400,47 @@ async fn main() {
 
     // Spawn the threaded server in a separate thread
     std::thread::spawn(move || {
-        run_threaded_server(threaded_router);
+        run_threaded_server(threaded_router, "127.0.0.1:8080", None);
     });
 
+    // HTTPS on both engines, plus plain HTTP on 8082 redirecting to it
+    #[cfg(feature = "tls")]
+    if let Some(config) = tls_config() {
+        // Only the tokio engine speaks HTTP/2, so only it offers h2 in ALPN
+        #[cfg(feature = "http2")]
+        let async_config = config.clone().with_alpn(&[http2::ALPN, "http/1.1"]);
+        #[cfg(not(feature = "http2"))]
+        let async_config = config.clone();
+        if let (Some(threaded_tls), Some(async_tls)) = (build_tls(config), build_tls(async_config)) {
+            let threaded_router = Arc::clone(&router);
+            std::thread::spawn(move || {
+                run_threaded_server(threaded_router, "127.0.0.1:8443", Some(Arc::new(threaded_tls)));
+            });
+            tokio::spawn(run_async_server(Arc::clone(&router), "127.0.0.1:8444", Some(Arc::new(async_tls))));
+            tokio::spawn(run_async_server(Arc::new(hs::tls::redirect_router(&["localhost", "127.0.0.1"], 8444)), "127.0.0.1:8082", None));
+        }
+    }
+
     // Run the async server in the main thread
-    run_async_server(router).await;
+    run_async_server(router, "127.0.0.1:8081", None).await;
+}
+
+// Certificate and key from HS_TLS_CERT and HS_TLS_KEY; None leaves HTTPS off
+#[cfg(feature = "tls")]
+fn tls_config() -> Option<TlsConfig> {
+    let (cert, key) = (std::env::var_os("HS_TLS_CERT")?, std::env::var_os("HS_TLS_KEY")?);
+    Some(TlsConfig::new(cert, key))
+}
+
+#[cfg(feature = "tls")]
+fn build_tls(config: TlsConfig) -> Option<Tls> {
+    match config.build() {
+        Ok(tls) => Some(tls),
+        Err(e) => {
+            log_error!("HTTPS disabled, could not load certificates: {}", e);
+            None
+        }
+    }
 }
 
 // Deprecated functions that we're replacing with our new routing system
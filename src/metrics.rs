@@ -0,0 +1,372 @@
+//Prometheus metrics
+//Process-wide counters, gauges and histograms rendered in the Prometheus text format.
+//Request series are labelled with the matched route pattern, never the raw path,
+//so `/user/1`, `/user/2`, ... share one series.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::types::{Request, Response};
+
+// Route label for requests that matched nothing
+pub const UNMATCHED_ROUTE: &str = "unmatched";
+
+const QUEUE_WAIT_BUCKETS: &[f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+const LATENCY_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Counter { name, help, value: AtomicU64::new(0) }
+    }
+
+    pub fn inc_by(&self, amount: u64) {
+        self.value.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "counter");
+        let _ = writeln!(out, "{} {}", self.name, self.get());
+    }
+}
+
+pub struct Gauge {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicI64,
+}
+
+impl Gauge {
+    const fn new(name: &'static str, help: &'static str) -> Self {
+        Gauge { name, help, value: AtomicI64::new(0) }
+    }
+
+    pub fn inc(&self) {
+        self.value.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.value.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, value: i64) {
+        self.value.store(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "gauge");
+        let _ = writeln!(out, "{} {}", self.name, self.get());
+    }
+}
+
+pub struct CounterVec {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl CounterVec {
+    const fn new(name: &'static str, help: &'static str, labels: &'static [&'static str]) -> Self {
+        CounterVec { name, help, labels, values: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn inc(&self, label_values: &[&str]) {
+        let key = label_values.iter().map(|v| v.to_string()).collect();
+        *self.values.lock().unwrap().entry(key).or_insert(0) += 1;
+    }
+
+    pub fn get(&self, label_values: &[&str]) -> u64 {
+        let key: Vec<String> = label_values.iter().map(|v| v.to_string()).collect();
+        self.values.lock().unwrap().get(&key).copied().unwrap_or(0)
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "counter");
+        for (values, count) in self.values.lock().unwrap().iter() {
+            let _ = writeln!(out, "{}{} {}", self.name, label_pairs(self.labels, values, None), count);
+        }
+    }
+}
+
+#[derive(Default)]
+struct HistogramSeries {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+pub struct HistogramVec {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    bounds: &'static [f64],
+    series: Mutex<BTreeMap<Vec<String>, HistogramSeries>>,
+}
+
+impl HistogramVec {
+    const fn new(name: &'static str, help: &'static str, labels: &'static [&'static str], bounds: &'static [f64]) -> Self {
+        HistogramVec { name, help, labels, bounds, series: Mutex::new(BTreeMap::new()) }
+    }
+
+    pub fn observe(&self, label_values: &[&str], value: f64) {
+        let key = label_values.iter().map(|v| v.to_string()).collect();
+        let mut series = self.series.lock().unwrap();
+        let series = series.entry(key).or_default();
+        if series.buckets.is_empty() {
+            series.buckets = vec![0; self.bounds.len()];
+        }
+        for (bucket, bound) in series.buckets.iter_mut().zip(self.bounds) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        series.sum += value;
+        series.count += 1;
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "histogram");
+        for (values, series) in self.series.lock().unwrap().iter() {
+            for (count, bound) in series.buckets.iter().zip(self.bounds) {
+                let le = bound.to_string();
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{} {}",
+                    self.name,
+                    label_pairs(self.labels, values, Some(&le)),
+                    count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_bucket{} {}",
+                self.name,
+                label_pairs(self.labels, values, Some("+Inf")),
+                series.count
+            );
+            let labels = label_pairs(self.labels, values, None);
+            let _ = writeln!(out, "{}_sum{} {}", self.name, labels, series.sum);
+            let _ = writeln!(out, "{}_count{} {}", self.name, labels, series.count);
+        }
+    }
+}
+
+// Most buckets an unlabelled `Histogram` supports
+const MAX_BUCKETS: usize = 16;
+
+// Unlabelled histogram on atomics, cheap enough for per-task hot paths
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    bounds: &'static [f64],
+    // Per-bucket counts, made cumulative when rendered; the last slot is +Inf
+    buckets: [AtomicU64; MAX_BUCKETS + 1],
+    // f64 bits
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    const fn new(name: &'static str, help: &'static str, bounds: &'static [f64]) -> Self {
+        assert!(bounds.len() <= MAX_BUCKETS);
+        Histogram {
+            name,
+            help,
+            bounds,
+            buckets: [const { AtomicU64::new(0) }; MAX_BUCKETS + 1],
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let index = self.bounds.partition_point(|bound| *bound < value);
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        let _ = self.sum.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+            Some((f64::from_bits(bits) + value).to_bits())
+        });
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String) {
+        header(out, self.name, self.help, "histogram");
+        let mut cumulative = 0;
+        for (bucket, bound) in self.buckets.iter().zip(self.bounds) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", self.name, bound, cumulative);
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", self.name, count);
+        let _ = writeln!(out, "{}_sum {}", self.name, f64::from_bits(self.sum.load(Ordering::Relaxed)));
+        let _ = writeln!(out, "{}_count {}", self.name, count);
+    }
+}
+
+pub struct Metrics {
+    pub requests: CounterVec,
+    pub request_duration: HistogramVec,
+    pub request_bytes: Counter,
+    pub response_bytes: Counter,
+    pub active_connections: Gauge,
+    pub connections_limited: CounterVec,
+    pub connections_held: Gauge,
+    pub rate_limited: Counter,
+    pub pool_queue_depth: Gauge,
+    pub pool_workers: Gauge,
+    pub pool_busy_workers: Gauge,
+    pub pool_queue_wait: Histogram,
+    pub pool_rejected: CounterVec,
+    pub panics: CounterVec,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            requests: CounterVec::new(
+                "hs_http_requests_total",
+                "HTTP requests by method, route pattern and status.",
+                &["method", "route", "status"],
+            ),
+            request_duration: HistogramVec::new(
+                "hs_http_request_duration_seconds",
+                "Time from parsed request to written response.",
+                &["method", "route"],
+                LATENCY_BUCKETS,
+            ),
+            request_bytes: Counter::new("hs_http_request_bytes_total", "Bytes received in request heads and bodies."),
+            response_bytes: Counter::new("hs_http_response_bytes_total", "Bytes sent in response bodies."),
+            active_connections: Gauge::new("hs_active_connections", "Connections currently open."),
+            connections_limited: CounterVec::new(
+                "hs_connections_limited_total",
+                "Connections over the global or per-IP limit, by what happened to them.",
+                &["limit", "action"],
+            ),
+            connections_held: Gauge::new("hs_connections_held", "Connections parked behind their client's per-IP limit."),
+            rate_limited: Counter::new("hs_rate_limited_total", "Requests answered 429 by a rate limiter."),
+            pool_queue_depth: Gauge::new("hs_threadpool_queue_depth", "Jobs waiting for a ThreadPool worker."),
+            pool_workers: Gauge::new("hs_threadpool_workers", "ThreadPool worker threads alive."),
+            pool_busy_workers: Gauge::new("hs_threadpool_busy_workers", "ThreadPool workers running a job."),
+            pool_queue_wait: Histogram::new(
+                "hs_threadpool_queue_wait_seconds",
+                "Time ThreadPool tasks spent queued before a worker picked them up.",
+                QUEUE_WAIT_BUCKETS,
+            ),
+            pool_rejected: CounterVec::new(
+                "hs_threadpool_rejected_total",
+                "Tasks shed by the ThreadPool queue policy.",
+                &["reason"],
+            ),
+            panics: CounterVec::new(
+                "hs_panics_total",
+                "Panics caught in handlers (by route) and ThreadPool jobs.",
+                &["source", "route"],
+            ),
+        }
+    }
+
+    // Record a finished exchange; `request` is None when it couldn't be parsed
+    pub fn observe_request(&self, request: Option<&Request>, response: &Response, latency: Duration, bytes_in: u64) {
+        // Arbitrary client-sent methods would blow up cardinality just like raw paths
+        let method = match request.map(|r| r.method.as_str()) {
+            Some(m @ ("GET" | "HEAD" | "POST" | "PUT" | "DELETE" | "PATCH" | "OPTIONS")) => m,
+            Some(_) => "OTHER",
+            None => "-",
+        };
+        let route = request.and_then(|r| r.route.as_deref()).unwrap_or(UNMATCHED_ROUTE);
+        let status = response.status.to_string();
+
+        self.requests.inc(&[method, route, &status]);
+        self.request_duration.observe(&[method, route], latency.as_secs_f64());
+        self.request_bytes.inc_by(bytes_in);
+        self.response_bytes.inc_by(response.content_length());
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.requests.render(&mut out);
+        self.request_duration.render(&mut out);
+        self.request_bytes.render(&mut out);
+        self.response_bytes.render(&mut out);
+        self.active_connections.render(&mut out);
+        self.connections_limited.render(&mut out);
+        self.connections_held.render(&mut out);
+        self.rate_limited.render(&mut out);
+        self.pool_queue_depth.render(&mut out);
+        self.pool_workers.render(&mut out);
+        self.pool_busy_workers.render(&mut out);
+        self.pool_queue_wait.render(&mut out);
+        self.pool_rejected.render(&mut out);
+        self.panics.render(&mut out);
+        out
+    }
+}
+
+static METRICS: Metrics = Metrics::new();
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+// Decrements the active connection gauge when the connection is dropped
+pub struct ConnectionGuard(());
+
+impl ConnectionGuard {
+    pub fn new() -> Self {
+        metrics().active_connections.inc();
+        ConnectionGuard(())
+    }
+}
+
+impl Default for ConnectionGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        metrics().active_connections.dec();
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+// `{name="value",...}`, or nothing for a series without labels
+fn label_pairs(names: &[&str], values: &[String], le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = names
+        .iter()
+        .zip(values)
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape_label(value)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        return String::new();
+    }
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
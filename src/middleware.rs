@@ -0,0 +1,45 @@
+//Middleware
+//Code that runs around the handlers of a route group. It can inspect or change the
+//request, answer it without calling the handler, or post-process the response.
+use std::sync::Arc;
+
+use crate::route::Router;
+use crate::types::{Request, Response};
+
+pub trait Middleware: Send + Sync {
+    // Call `next.run(request)` to pass the request on
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&mut Request, Next<'_>) -> Response + Send + Sync,
+{
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        self(request, next)
+    }
+}
+
+// The rest of the chain, ending with routing itself
+pub struct Next<'a> {
+    router: &'a Router,
+    chain: &'a [Arc<dyn Middleware>],
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(router: &'a Router, chain: &'a [Arc<dyn Middleware>]) -> Self {
+        Next { router, chain }
+    }
+
+    pub fn run(self, request: &mut Request) -> Response {
+        match self.chain.split_first() {
+            Some((middleware, rest)) => middleware.handle(request, Next::new(self.router, rest)),
+            None => self.router.dispatch(request),
+        }
+    }
+
+    // For rendering errors raised by middleware the way handler errors are
+    pub fn router(&self) -> &'a Router {
+        self.router
+    }
+}
@@ -0,0 +1,368 @@
+//MIME type registry
+//Maps file extensions to media types, mirroring the common entries of `mime.types`.
+//Routers can register overrides and optionally sniff the type of extensionless files.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+pub const DEFAULT_MIME_TYPE: &str = "application/octet-stream";
+
+// How many leading bytes content sniffing looks at
+const SNIFF_LEN: usize = 512;
+
+static MIME_TYPES: &[(&str, &str)] = &[
+    // Text
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("shtml", "text/html"),
+    ("css", "text/css"),
+    ("csv", "text/csv"),
+    ("tsv", "text/tab-separated-values"),
+    ("txt", "text/plain"),
+    ("text", "text/plain"),
+    ("log", "text/plain"),
+    ("conf", "text/plain"),
+    ("ini", "text/plain"),
+    ("md", "text/markdown"),
+    ("markdown", "text/markdown"),
+    ("ics", "text/calendar"),
+    ("vcf", "text/vcard"),
+    ("vtt", "text/vtt"),
+    ("rtx", "text/richtext"),
+    ("yaml", "text/yaml"),
+    ("yml", "text/yaml"),
+    ("toml", "application/toml"),
+    // Scripts and data
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("cjs", "text/javascript"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("jsonld", "application/ld+json"),
+    ("webmanifest", "application/manifest+json"),
+    ("geojson", "application/geo+json"),
+    ("xml", "application/xml"),
+    ("xsl", "application/xml"),
+    ("xslt", "application/xslt+xml"),
+    ("dtd", "application/xml-dtd"),
+    ("xhtml", "application/xhtml+xml"),
+    ("rss", "application/rss+xml"),
+    ("atom", "application/atom+xml"),
+    ("wasm", "application/wasm"),
+    ("graphql", "application/graphql"),
+    ("sql", "application/sql"),
+    ("rtf", "application/rtf"),
+    ("sh", "application/x-sh"),
+    ("csh", "application/x-csh"),
+    ("php", "application/x-httpd-php"),
+    ("py", "text/x-python"),
+    ("rs", "text/x-rust"),
+    ("c", "text/x-c"),
+    ("h", "text/x-c"),
+    ("java", "text/x-java-source"),
+    // Images
+    ("png", "image/png"),
+    ("apng", "image/apng"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("jpe", "image/jpeg"),
+    ("jfif", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("heic", "image/heic"),
+    ("heif", "image/heif"),
+    ("jxl", "image/jxl"),
+    ("svg", "image/svg+xml"),
+    ("svgz", "image/svg+xml"),
+    ("ico", "image/x-icon"),
+    ("cur", "image/x-icon"),
+    ("bmp", "image/bmp"),
+    ("tif", "image/tiff"),
+    ("tiff", "image/tiff"),
+    ("psd", "image/vnd.adobe.photoshop"),
+    // Fonts
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("ttc", "font/collection"),
+    ("eot", "application/vnd.ms-fontobject"),
+    // Audio
+    ("mp3", "audio/mpeg"),
+    ("m4a", "audio/mp4"),
+    ("aac", "audio/aac"),
+    ("oga", "audio/ogg"),
+    ("ogg", "audio/ogg"),
+    ("opus", "audio/opus"),
+    ("wav", "audio/wav"),
+    ("weba", "audio/webm"),
+    ("flac", "audio/flac"),
+    ("mid", "audio/midi"),
+    ("midi", "audio/midi"),
+    // Video
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("mpeg", "video/mpeg"),
+    ("mpg", "video/mpeg"),
+    ("webm", "video/webm"),
+    ("ogv", "video/ogg"),
+    ("mov", "video/quicktime"),
+    ("avi", "video/x-msvideo"),
+    ("mkv", "video/x-matroska"),
+    ("wmv", "video/x-ms-wmv"),
+    ("flv", "video/x-flv"),
+    ("3gp", "video/3gpp"),
+    ("ts", "video/mp2t"),
+    ("m3u8", "application/vnd.apple.mpegurl"),
+    ("mpd", "application/dash+xml"),
+    // Documents
+    ("pdf", "application/pdf"),
+    ("doc", "application/msword"),
+    ("docx", "application/vnd.openxmlformats-officedocument.wordprocessingml.document"),
+    ("xls", "application/vnd.ms-excel"),
+    ("xlsx", "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"),
+    ("ppt", "application/vnd.ms-powerpoint"),
+    ("pptx", "application/vnd.openxmlformats-officedocument.presentationml.presentation"),
+    ("odt", "application/vnd.oasis.opendocument.text"),
+    ("ods", "application/vnd.oasis.opendocument.spreadsheet"),
+    ("odp", "application/vnd.oasis.opendocument.presentation"),
+    ("epub", "application/epub+zip"),
+    // Archives
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tgz", "application/gzip"),
+    ("bz2", "application/x-bzip2"),
+    ("xz", "application/x-xz"),
+    ("zst", "application/zstd"),
+    ("br", "application/x-brotli"),
+    ("7z", "application/x-7z-compressed"),
+    ("rar", "application/vnd.rar"),
+    ("tar", "application/x-tar"),
+    ("jar", "application/java-archive"),
+    ("apk", "application/vnd.android.package-archive"),
+    ("deb", "application/vnd.debian.binary-package"),
+    ("rpm", "application/x-rpm"),
+    ("dmg", "application/x-apple-diskimage"),
+    ("iso", "application/x-iso9660-image"),
+    // Binaries
+    ("bin", "application/octet-stream"),
+    ("exe", "application/octet-stream"),
+    ("dll", "application/octet-stream"),
+    ("so", "application/octet-stream"),
+    ("img", "application/octet-stream"),
+    ("pem", "application/x-pem-file"),
+    ("crt", "application/x-x509-ca-cert"),
+    ("der", "application/x-x509-ca-cert"),
+];
+
+// Look up the built-in type for an extension, ignoring case
+pub fn lookup(extension: &str) -> Option<&'static str> {
+    MIME_TYPES
+        .iter()
+        .find(|(ext, _)| ext.eq_ignore_ascii_case(extension))
+        .map(|&(_, mime)| mime)
+}
+
+// Textual types are served as UTF-8
+pub fn needs_charset(mime: &str) -> bool {
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(mime, "application/json" | "application/xml" | "application/javascript" | "application/toml" | "application/sql")
+}
+
+pub fn with_charset(mime: &str) -> String {
+    if needs_charset(mime) && !mime.contains(';') {
+        format!("{}; charset=utf-8", mime)
+    } else {
+        mime.to_string()
+    }
+}
+
+// Guess a type from the leading bytes of a file
+pub fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"\0asm", "application/wasm"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"wOFF", "font/woff"),
+        (b"wOF2", "font/woff2"),
+        (b"OggS", "audio/ogg"),
+        (b"ID3", "audio/mpeg"),
+        (b"\x1aE\xdf\xa3", "video/webm"),
+    ];
+
+    for (signature, mime) in SIGNATURES {
+        if bytes.starts_with(signature) {
+            return Some(mime);
+        }
+    }
+    // RIFF containers carry their format at offset 8
+    if bytes.starts_with(b"RIFF") && bytes.len() >= 12 {
+        match &bytes[8..12] {
+            b"WEBP" => return Some("image/webp"),
+            b"WAVE" => return Some("audio/wav"),
+            _ => {}
+        }
+    }
+    // ISO base media files announce their brand in the `ftyp` box
+    if bytes.len() >= 12 && &bytes[4..8] == b"ftyp" {
+        return match &bytes[8..12] {
+            b"avif" | b"avis" => Some("image/avif"),
+            b"heic" | b"heix" => Some("image/heic"),
+            _ => Some("video/mp4"),
+        };
+    }
+
+    let text = match std::str::from_utf8(bytes) {
+        Ok(text) => text,
+        // A multi-byte character may have been cut off at the end of the sample
+        Err(e) if e.error_len().is_none() => std::str::from_utf8(&bytes[..e.valid_up_to()]).ok()?,
+        Err(_) => return None,
+    };
+    if text.contains('\0') {
+        return None;
+    }
+    let start = text.trim_start().to_ascii_lowercase();
+    if start.starts_with("<!doctype html") || start.starts_with("<html") {
+        Some("text/html")
+    } else if start.starts_with("<?xml") {
+        Some("application/xml")
+    } else if start.starts_with("<svg") {
+        Some("image/svg+xml")
+    } else {
+        Some("text/plain")
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MimeRegistry {
+    overrides: HashMap<String, String>,
+    sniff: bool,
+}
+
+impl MimeRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Overrides take precedence over the built-in table
+    pub fn register(&mut self, extension: &str, mime: &str) {
+        self.overrides
+            .insert(extension.trim_start_matches('.').to_ascii_lowercase(), mime.to_string());
+    }
+
+    pub fn set_sniffing(&mut self, enabled: bool) {
+        self.sniff = enabled;
+    }
+
+    // Media type for an extension without charset parameters
+    pub fn mime_for_extension(&self, extension: &str) -> Option<&str> {
+        self.overrides
+            .get(&extension.to_ascii_lowercase())
+            .map(String::as_str)
+            .or_else(|| lookup(extension))
+    }
+
+    // Content-Type header value for a path, based on its extension alone
+    pub fn content_type(&self, path: &Path) -> String {
+        let mime = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .and_then(|ext| self.mime_for_extension(ext))
+            .unwrap_or(DEFAULT_MIME_TYPE);
+        with_charset(mime)
+    }
+
+    // Content-Type for an open file, sniffing extensionless files when enabled
+    pub fn content_type_for_file(&self, path: &Path, file: &File) -> String {
+        if path.extension().is_some() || !self.sniff {
+            return self.content_type(path);
+        }
+
+        let mut sample = Vec::with_capacity(SNIFF_LEN);
+        let mut reader = file;
+        let _ = reader.take(SNIFF_LEN as u64).read_to_end(&mut sample);
+        // Leave the file positioned at the start for the buffered writers
+        let _ = reader.seek(SeekFrom::Start(0));
+        self.content_type_for_bytes(path, &sample)
+    }
+
+    // Same as content_type_for_file when the contents are already in memory
+    pub fn content_type_for_bytes(&self, path: &Path, contents: &[u8]) -> String {
+        if path.extension().is_some() || !self.sniff {
+            return self.content_type(path);
+        }
+        let sample = &contents[..contents.len().min(SNIFF_LEN)];
+        with_charset(sniff(sample).unwrap_or(DEFAULT_MIME_TYPE))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_lookup_ignores_case() {
+        assert_eq!(lookup("html"), Some("text/html"));
+        assert_eq!(lookup("PNG"), Some("image/png"));
+        assert_eq!(lookup("wasm"), Some("application/wasm"));
+        assert_eq!(lookup("nope"), None);
+        // Every extension appears once, so the first match is the only one
+        for (i, (ext, _)) in MIME_TYPES.iter().enumerate() {
+            assert!(!MIME_TYPES[i + 1..].iter().any(|(other, _)| other == ext), "duplicate {}", ext);
+        }
+    }
+
+    #[test]
+    fn charset_is_added_to_textual_types_only() {
+        assert_eq!(with_charset("text/css"), "text/css; charset=utf-8");
+        assert_eq!(with_charset("application/json"), "application/json; charset=utf-8");
+        assert_eq!(with_charset("image/svg+xml"), "image/svg+xml; charset=utf-8");
+        assert_eq!(with_charset("image/png"), "image/png");
+        assert_eq!(with_charset("text/plain; charset=latin1"), "text/plain; charset=latin1");
+    }
+
+    #[test]
+    fn sniff_recognises_signatures_and_text() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n...."), Some("image/png"));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff(b"\0\0\0\x1cftypavif"), Some("image/avif"));
+        assert_eq!(sniff(b"\0\0\0\x1cftypisom"), Some("video/mp4"));
+        assert_eq!(sniff(b"  <!DOCTYPE html><p>"), Some("text/html"));
+        assert_eq!(sniff(b"<svg xmlns="), Some("image/svg+xml"));
+        assert_eq!(sniff(b"plain words"), Some("text/plain"));
+        // A character cut off by the sample limit is still text
+        assert_eq!(sniff("caf\u{e9}".as_bytes().split_last().unwrap().1), Some("text/plain"));
+        assert_eq!(sniff(b"bin\0ary"), None);
+        assert_eq!(sniff(b"\xff\xfe\xfd"), None);
+    }
+
+    #[test]
+    fn registry_overrides_and_sniffing() {
+        let mut registry = MimeRegistry::new();
+        assert_eq!(registry.content_type(Path::new("a.js")), "text/javascript; charset=utf-8");
+        assert_eq!(registry.content_type(Path::new("a.unknown")), DEFAULT_MIME_TYPE);
+
+        registry.register(".JS", "application/javascript");
+        registry.register("unknown", "application/x-custom");
+        assert_eq!(registry.mime_for_extension("js"), Some("application/javascript"));
+        assert_eq!(registry.content_type(Path::new("a.JS")), "application/javascript; charset=utf-8");
+        assert_eq!(registry.content_type(Path::new("a.unknown")), "application/x-custom");
+
+        // Extensionless files are only sniffed when asked to
+        assert_eq!(registry.content_type_for_bytes(Path::new("README"), b"hello"), DEFAULT_MIME_TYPE);
+        registry.set_sniffing(true);
+        assert_eq!(registry.content_type_for_bytes(Path::new("README"), b"hello"), "text/plain; charset=utf-8");
+        assert_eq!(registry.content_type_for_bytes(Path::new("logo"), b"GIF89a"), "image/gif");
+        // An extension still wins over the contents
+        assert_eq!(registry.content_type_for_bytes(Path::new("a.css"), b"GIF89a"), "text/css; charset=utf-8");
+    }
+}
@@ -0,0 +1,410 @@
+//Rate limiting
+//Middleware that allows each client a quota of requests per window and answers
+//429 Too Many Requests past it. Clients are told where they stand through the
+//`RateLimit-*` headers (draft-ietf-httpapi-ratelimit-headers) on every response.
+//Quotas live in a `RateLimitStore`; `MemoryStore` keeps them in this process.
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::error::HsError;
+use crate::metrics;
+use crate::middleware::{Middleware, Next};
+use crate::types::{Request, Response};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    // Tokens refill continuously; allows bursts of up to `limit`
+    TokenBucket,
+    // Requests in the last `window`, estimated from the current and previous windows
+    SlidingWindow,
+}
+
+// `limit` requests per `window`
+#[derive(Clone, Copy, Debug)]
+pub struct Quota {
+    pub limit: u32,
+    pub window: Duration,
+    pub algorithm: Algorithm,
+}
+
+// The outcome of one request against a client's quota
+#[derive(Clone, Copy, Debug)]
+pub struct Decision {
+    pub allowed: bool,
+    pub remaining: u32,
+    // Until the quota is fully restored (token bucket) or the window ends
+    pub reset: Duration,
+    // Until the next request would be allowed; zero when this one was
+    pub retry_after: Duration,
+}
+
+// Where quotas are kept; implement this to share limits between processes.
+// A store serves one limiter, as keys aren't namespaced per limiter.
+pub trait RateLimitStore: Send + Sync {
+    // Count one request for `key`
+    fn acquire(&self, key: &str, quota: &Quota) -> Decision;
+}
+
+// Picks the client key out of a request
+pub type KeyFn = Arc<dyn Fn(&Request) -> Option<String> + Send + Sync>;
+
+// Which client a request is counted against
+#[derive(Clone)]
+pub enum RateKey {
+    Ip,
+    // e.g. an API key; requests without the header are counted by IP
+    Header(String),
+    // None falls back to the IP
+    Custom(KeyFn),
+}
+
+impl RateKey {
+    fn resolve(&self, request: &Request) -> String {
+        let key = match self {
+            RateKey::Ip => None,
+            RateKey::Header(name) => request.header(name).map(|value| format!("{}:{}", name.to_ascii_lowercase(), value)),
+            RateKey::Custom(key) => key(request).map(|value| format!("custom:{}", value)),
+        };
+        key.unwrap_or_else(|| match request.peer_addr {
+            Some(addr) => format!("ip:{}", addr.ip()),
+            None => "ip:unknown".to_string(),
+        })
+    }
+}
+
+pub struct RateLimiter {
+    quota: Quota,
+    key: RateKey,
+    store: Arc<dyn RateLimitStore>,
+}
+
+impl RateLimiter {
+    // `limit` requests per `window` per client IP, as a token bucket kept in memory
+    pub fn new(limit: u32, window: Duration) -> Self {
+        RateLimiter {
+            quota: Quota {
+                limit: limit.max(1),
+                window: window.max(Duration::from_millis(1)),
+                algorithm: Algorithm::TokenBucket,
+            },
+            key: RateKey::Ip,
+            store: Arc::new(MemoryStore::new()),
+        }
+    }
+
+    pub fn with_algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.quota.algorithm = algorithm;
+        self
+    }
+
+    pub fn with_key(mut self, key: RateKey) -> Self {
+        self.key = key;
+        self
+    }
+
+    // Count requests by the value of this header, e.g. `X-Api-Key`
+    pub fn with_header_key(self, header: &str) -> Self {
+        self.with_key(RateKey::Header(header.to_string()))
+    }
+
+    pub fn with_key_fn<F>(self, key: F) -> Self
+    where
+        F: Fn(&Request) -> Option<String> + Send + Sync + 'static,
+    {
+        self.with_key(RateKey::Custom(Arc::new(key)))
+    }
+
+    pub fn with_store<S: RateLimitStore + 'static>(mut self, store: S) -> Self {
+        self.store = Arc::new(store);
+        self
+    }
+
+    pub fn quota(&self) -> &Quota {
+        &self.quota
+    }
+}
+
+impl Middleware for RateLimiter {
+    fn handle(&self, request: &mut Request, next: Next<'_>) -> Response {
+        let decision = self.store.acquire(&self.key.resolve(request), &self.quota);
+        let response = if decision.allowed {
+            next.run(request)
+        } else {
+            metrics::metrics().rate_limited.inc_by(1);
+            next.router()
+                .render_error(request, &HsError::Status(429, "Too many requests; please slow down.".to_string()))
+                .with_header("Retry-After", &ceil_secs(decision.retry_after).max(1).to_string())
+        };
+        // With limiters stacked, report whichever quota is closer to running out
+        let inner = response.headers.get("RateLimit-Remaining").and_then(|r| r.parse::<u32>().ok());
+        if inner.is_some_and(|remaining| remaining <= decision.remaining) {
+            return response;
+        }
+        response
+            .with_header("RateLimit-Limit", &self.quota.limit.to_string())
+            .with_header("RateLimit-Remaining", &decision.remaining.to_string())
+            .with_header("RateLimit-Reset", &ceil_secs(decision.reset).to_string())
+            .with_header(
+                "RateLimit-Policy",
+                &format!("{};w={}", self.quota.limit, ceil_secs(self.quota.window)),
+            )
+    }
+}
+
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+enum Entry {
+    Bucket { tokens: f64, updated: Instant },
+    Window { start: Instant, current: u32, previous: u32 },
+}
+
+impl Entry {
+    fn new(quota: &Quota, now: Instant) -> Self {
+        match quota.algorithm {
+            Algorithm::TokenBucket => Entry::Bucket {
+                tokens: f64::from(quota.limit),
+                updated: now,
+            },
+            Algorithm::SlidingWindow => Entry::Window {
+                start: now,
+                current: 0,
+                previous: 0,
+            },
+        }
+    }
+
+    fn acquire(&mut self, quota: &Quota, now: Instant) -> Decision {
+        let limit = f64::from(quota.limit);
+        let window = quota.window.as_secs_f64();
+        match self {
+            Entry::Bucket { tokens, updated } => {
+                let rate = limit / window;
+                *tokens = (*tokens + now.duration_since(*updated).as_secs_f64() * rate).min(limit);
+                *updated = now;
+                let allowed = *tokens >= 1.0;
+                if allowed {
+                    *tokens -= 1.0;
+                }
+                Decision {
+                    allowed,
+                    remaining: tokens.floor() as u32,
+                    reset: Duration::from_secs_f64((limit - *tokens) / rate),
+                    retry_after: Duration::from_secs_f64(if allowed { 0.0 } else { (1.0 - *tokens) / rate }),
+                }
+            }
+            Entry::Window { start, current, previous } => {
+                let mut elapsed = now.duration_since(*start);
+                if elapsed >= quota.window {
+                    // Roll forward to the window `now` falls in
+                    let windows = (elapsed.as_nanos() / quota.window.as_nanos()) as u32;
+                    *previous = if windows == 1 { *current } else { 0 };
+                    *current = 0;
+                    *start += quota.window * windows;
+                    elapsed = now.duration_since(*start);
+                }
+                let into = elapsed.as_secs_f64() / window;
+                let used = f64::from(*previous) * (1.0 - into) + f64::from(*current);
+                let allowed = used + 1.0 <= limit;
+                if allowed {
+                    *current += 1;
+                }
+                let left = window - elapsed.as_secs_f64();
+                let retry_after = if allowed {
+                    0.0
+                } else if f64::from(*current) + 1.0 > limit {
+                    // This window alone is over; wait for the next one, and for this
+                    // window's count to decay enough once it becomes the previous
+                    left + window * (1.0 - (limit - 1.0) / f64::from(*current))
+                } else {
+                    // Wait for the previous window's share to decay
+                    window * (1.0 - (limit - 1.0 - f64::from(*current)) / f64::from(*previous)) - elapsed.as_secs_f64()
+                };
+                Decision {
+                    allowed,
+                    remaining: (limit - used - f64::from(u8::from(allowed))).max(0.0).floor() as u32,
+                    reset: Duration::from_secs_f64(left),
+                    retry_after: Duration::from_secs_f64(retry_after.max(0.0)),
+                }
+            }
+        }
+    }
+
+    // Whether the entry is back to a fresh one, so forgetting it changes nothing
+    fn is_stale(&self, quota: &Quota, now: Instant) -> bool {
+        match self {
+            Entry::Bucket { tokens, updated } => {
+                let refill = (f64::from(quota.limit) - tokens) / (f64::from(quota.limit) / quota.window.as_secs_f64());
+                now.duration_since(*updated).as_secs_f64() >= refill
+            }
+            Entry::Window { start, .. } => now.duration_since(*start) >= quota.window * 2,
+        }
+    }
+}
+
+// Quotas kept in this process
+pub struct MemoryStore {
+    entries: Mutex<HashMap<String, Entry>>,
+    calls: AtomicU64,
+}
+
+// Stale entries are swept every this many requests
+const SWEEP_INTERVAL: u64 = 4096;
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore {
+            entries: Mutex::new(HashMap::new()),
+            calls: AtomicU64::new(0),
+        }
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn acquire(&self, key: &str, quota: &Quota) -> Decision {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        // Clients that went quiet would otherwise pile up forever
+        if self.calls.fetch_add(1, Ordering::Relaxed) % SWEEP_INTERVAL == SWEEP_INTERVAL - 1 {
+            entries.retain(|_, entry| !entry.is_stale(quota, now));
+        }
+        match entries.get_mut(key) {
+            Some(entry) => entry.acquire(quota, now),
+            None => entries
+                .entry(key.to_string())
+                .or_insert_with(|| Entry::new(quota, now))
+                .acquire(quota, now),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::route::Router;
+
+    fn quota(limit: u32, window: Duration, algorithm: Algorithm) -> Quota {
+        Quota { limit, window, algorithm }
+    }
+
+    #[test]
+    fn token_bucket_allows_a_burst_then_refills() {
+        let quota = quota(3, Duration::from_secs(3), Algorithm::TokenBucket);
+        let start = Instant::now();
+        let mut entry = Entry::new(&quota, start);
+        for remaining in [2, 1, 0] {
+            let decision = entry.acquire(&quota, start);
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+        }
+        let refused = entry.acquire(&quota, start);
+        assert!(!refused.allowed);
+        assert_eq!(refused.retry_after, Duration::from_secs(1));
+        // One token a second
+        assert!(!entry.acquire(&quota, start + Duration::from_millis(900)).allowed);
+        assert!(entry.acquire(&quota, start + Duration::from_millis(1900)).allowed);
+    }
+
+    #[test]
+    fn sliding_window_counts_the_previous_window() {
+        let quota = quota(4, Duration::from_secs(10), Algorithm::SlidingWindow);
+        let start = Instant::now();
+        let mut entry = Entry::new(&quota, start);
+        for _ in 0..4 {
+            assert!(entry.acquire(&quota, start).allowed);
+        }
+        let refused = entry.acquire(&quota, start + Duration::from_secs(5));
+        assert!(!refused.allowed);
+        assert_eq!(refused.remaining, 0);
+        assert!(refused.retry_after > Duration::ZERO);
+
+        // Halfway into the next window the previous one still weighs 2 of 4
+        let later = start + Duration::from_secs(15);
+        assert!(entry.acquire(&quota, later).allowed);
+        assert!(entry.acquire(&quota, later).allowed);
+        assert!(!entry.acquire(&quota, later).allowed);
+        // Two windows on, the old requests no longer count
+        assert!(entry.acquire(&quota, start + Duration::from_secs(30)).allowed);
+    }
+
+    fn router(limiters: Vec<RateLimiter>) -> Router {
+        let mut router = Router::new();
+        for limiter in limiters {
+            router.middleware("/api", limiter);
+        }
+        router.get("/api/status", |_req| Response::new().with_body("ok"));
+        router
+    }
+
+    fn get(router: &Router, api_key: Option<&str>) -> Response {
+        let mut request = Request {
+            method: "GET".to_string(),
+            path: "/api/status".to_string(),
+            version: "HTTP/1.1".to_string(),
+            peer_addr: Some("192.0.2.1:5000".parse().unwrap()),
+            ..Request::default()
+        };
+        if let Some(key) = api_key {
+            request.headers.insert("X-Api-Key".to_string(), key.to_string());
+        }
+        router.handle(&mut request)
+    }
+
+    #[test]
+    fn over_the_limit_is_429_with_retry_after() {
+        let router = router(vec![RateLimiter::new(2, Duration::from_secs(60))]);
+        for remaining in ["1", "0"] {
+            let response = get(&router, None);
+            assert_eq!(response.status, 200);
+            assert_eq!(response.headers.get("RateLimit-Remaining").map(String::as_str), Some(remaining));
+        }
+        let response = get(&router, None);
+        assert_eq!(response.status, 429);
+        assert_eq!(response.headers.get("Retry-After").map(String::as_str), Some("30"));
+        assert_eq!(response.headers.get("RateLimit-Limit").map(String::as_str), Some("2"));
+        assert_eq!(response.headers.get("RateLimit-Policy").map(String::as_str), Some("2;w=60"));
+    }
+
+    #[test]
+    fn sliding_window_rejection_is_429() {
+        let limiter = RateLimiter::new(1, Duration::from_secs(60)).with_algorithm(Algorithm::SlidingWindow);
+        let router = router(vec![limiter]);
+        assert_eq!(get(&router, None).status, 200);
+        let response = get(&router, None);
+        assert_eq!(response.status, 429);
+        assert!(response.headers.get("Retry-After").is_some_and(|secs| secs.parse::<u64>().unwrap() >= 1));
+    }
+
+    #[test]
+    fn rotating_api_keys_still_hits_the_ip_limit() {
+        let router = router(vec![
+            RateLimiter::new(3, Duration::from_secs(60)),
+            RateLimiter::new(2, Duration::from_secs(60)).with_header_key("X-Api-Key"),
+        ]);
+        assert_eq!(get(&router, Some("a")).status, 200);
+        assert_eq!(get(&router, Some("a")).status, 200);
+        assert_eq!(get(&router, Some("a")).status, 429);
+        // A fresh key gets past the per-key limit, but not the per-IP one
+        assert_eq!(get(&router, Some("b")).status, 429);
+    }
+
+    #[test]
+    fn stacked_limiters_report_the_tighter_quota() {
+        let router = router(vec![
+            RateLimiter::new(10, Duration::from_secs(60)),
+            RateLimiter::new(2, Duration::from_secs(60)).with_header_key("X-Api-Key"),
+        ]);
+        let response = get(&router, Some("a"));
+        assert_eq!(response.headers.get("RateLimit-Limit").map(String::as_str), Some("2"));
+        assert_eq!(response.headers.get("RateLimit-Remaining").map(String::as_str), Some("1"));
+    }
+}
@@ -0,0 +1,98 @@
+//Request IDs
+//Every request gets an ID that is echoed in the response, written to every log line
+//and shown on error pages, so logs can be joined with downstream services.
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const DEFAULT_HEADER: &str = "X-Request-Id";
+
+// Incoming IDs longer than this are replaced rather than trusted
+const MAX_INCOMING_LEN: usize = 128;
+
+#[derive(Clone, Debug)]
+pub struct RequestIdConfig {
+    header: String,
+    trust_incoming: bool,
+}
+
+impl Default for RequestIdConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RequestIdConfig {
+    pub fn new() -> Self {
+        RequestIdConfig {
+            header: DEFAULT_HEADER.to_string(),
+            trust_incoming: false,
+        }
+    }
+
+    pub fn with_header(mut self, name: &str) -> Self {
+        self.header = name.to_string();
+        self
+    }
+
+    // Reuse the ID sent by the client (e.g. a trusted load balancer) instead of generating one
+    pub fn trust_incoming(mut self, trusted: bool) -> Self {
+        self.trust_incoming = trusted;
+        self
+    }
+
+    pub fn header(&self) -> &str {
+        &self.header
+    }
+
+    // The incoming ID if trusted and well-formed, otherwise a fresh one
+    pub fn resolve(&self, incoming: Option<&str>) -> String {
+        match incoming {
+            Some(id) if self.trust_incoming && is_valid(id) => id.to_string(),
+            _ => generate(),
+        }
+    }
+}
+
+// Only IDs that are safe to echo in headers, logs and HTML are accepted
+pub fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_INCOMING_LEN
+        && id.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+}
+
+// 128 random-looking bits formatted like a UUID v4
+pub fn generate() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    static KEYS: OnceLock<RandomState> = OnceLock::new();
+
+    // RandomState is seeded from the OS once per process, so hashing a counter
+    // together with the clock gives unpredictable, non-repeating IDs
+    let keys = KEYS.get_or_init(RandomState::new);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64);
+
+    let mut high = keys.build_hasher();
+    high.write_u64(count);
+    high.write_u64(nanos);
+    let high = high.finish();
+    let mut low = keys.build_hasher();
+    low.write_u64(high);
+    low.write_u64(count);
+    let low = low.finish();
+
+    let high = (high & 0xffff_ffff_ffff_0fff) | 0x4000;
+    let low = (low & 0x3fff_ffff_ffff_ffff) | 0x8000_0000_0000_0000;
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xffff,
+        high & 0xffff,
+        low >> 48,
+        low & 0xffff_ffff_ffff
+    )
+}
@@ -1,8 +1,23 @@
 use std::collections::HashMap;
-use std::path::{Path, PathBuf};
 use std::fs;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use crate::types::{Request, Response};
+use crate::cache::StaticCache;
+use crate::compression::{self, CompressionConfig};
+use crate::embed::{EmbeddedFile, EmbeddedMount};
+use crate::error::{self, ErrorRenderer, HandlerResult, HsError, IntoResponse};
+use crate::metrics;
+use crate::middleware::{Middleware, Next};
+use crate::mime::MimeRegistry;
+use crate::static_files::{ServeContext, StaticConfig, StaticMount};
+use crate::logging::RequestIdScope;
+use crate::request_id::RequestIdConfig;
+use crate::thread_pool::panic_message;
+use crate::types::{reason_phrase, Request, Response};
+use crate::websocket::{self, WebSocket, WebSocketConfig, WebSocketHandler};
+use crate::{log_error, log_warn};
 
 enum PathSegment {
     Static(String),
@@ -10,20 +25,23 @@ enum PathSegment {
 }
 
 struct Route {
+    pattern: String,
     segments: Vec<PathSegment>,
     handler: Handler,
 }
 
-pub type Handler = Arc<dyn Fn(&mut Request) -> Response + Send + Sync>;
+pub type Handler = Arc<dyn Fn(&mut Request) -> HandlerResult + Send + Sync>;
 
 impl Route {
-    fn new<F>(pattern: &str, handler: F) -> Self
+    fn new<F, R>(pattern: &str, handler: F) -> Self
     where
-        F: Fn(&mut Request) -> Response + Send + Sync + 'static,
+        F: Fn(&mut Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
     {
         Route {
+            pattern: pattern.to_string(),
             segments: parse_path_pattern(pattern),
-            handler: Arc::new(handler),
+            handler: into_handler(handler),
         }
     }
 
@@ -57,12 +75,20 @@ impl Route {
 }
 
 
+fn into_handler<F, R>(handler: F) -> Handler
+where
+    F: Fn(&mut Request) -> R + Send + Sync + 'static,
+    R: IntoResponse,
+{
+    Arc::new(move |request: &mut Request| handler(request).into_response())
+}
+
 fn parse_path_pattern(pattern: &str) -> Vec<PathSegment>{
     pattern.split("/")
     .filter(|s| !s.is_empty())
     .map(|segment| {
-        if segment.starts_with(":") {
-            PathSegment::Param(segment[1..].to_string())
+        if let Some(name) = segment.strip_prefix(':') {
+            PathSegment::Param(name.to_string())
         }else {
             PathSegment::Static(segment.to_string())
         }
@@ -70,169 +96,412 @@ fn parse_path_pattern(pattern: &str) -> Vec<PathSegment>{
     .collect()
 }
 
+// Read every `<status>.html` in `dir`; anything unreadable or misnamed is logged and skipped
+fn load_error_pages(dir: &Path) -> HashMap<u16, String> {
+    let mut pages = HashMap::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log_warn!("Error reading error pages from {}: {}", dir.display(), e);
+            return pages;
+        }
+    };
+    for path in entries.filter_map(|entry| entry.ok().map(|e| e.path())) {
+        if path.extension().and_then(|e| e.to_str()) != Some("html") {
+            continue;
+        }
+        // Other pages such as index.html live alongside them, so only numeric names count
+        let numeric = |stem: &&str| stem.bytes().all(|b| b.is_ascii_digit());
+        let Some(stem) = path.file_stem().and_then(|s| s.to_str()).filter(numeric) else {
+            continue;
+        };
+        let status = match stem.parse::<u16>() {
+            Ok(status @ 400..=599) => status,
+            _ => {
+                log_warn!("Ignoring error page {}: not a 4xx or 5xx status", path.display());
+                continue;
+            }
+        };
+        let template = match fs::read_to_string(&path) {
+            Ok(template) => template,
+            Err(e) => {
+                log_warn!("Error reading error page {}: {}", path.display(), e);
+                continue;
+            }
+        };
+        for placeholder in unknown_placeholders(&template) {
+            log_warn!("Unknown placeholder {{{{{}}}}} in error page {}", placeholder, path.display());
+        }
+        pages.insert(status, template);
+    }
+    pages
+}
+
+// `{{name}}` placeholders `render_template` won't fill in, e.g. typos
+fn unknown_placeholders(template: &str) -> Vec<&str> {
+    let mut unknown = Vec::new();
+    let mut rest = template;
+    while let Some((_, after)) = rest.split_once("{{") {
+        let Some((name, tail)) = after.split_once("}}") else { break };
+        if !["status", "reason", "message", "request_id"].contains(&name) {
+            unknown.push(name);
+        }
+        rest = tail;
+    }
+    unknown
+}
+
 pub struct Router {
     routes: HashMap<String, Vec<Route>>,
-    static_dir: Option<PathBuf>,
+    mounts: Vec<StaticMount>,
+    embedded: Vec<EmbeddedMount>,
+    static_dir: Option<StaticMount>,
+    compression: Option<CompressionConfig>,
+    mime: MimeRegistry,
+    cache: Option<StaticCache>,
+    request_ids: RequestIdConfig,
+    error_renderer: ErrorRenderer,
+    error_handlers: HashMap<u16, ErrorRenderer>,
+    // Error page templates by status, loaded by `error_pages`
+    error_pages: HashMap<u16, String>,
+    fallback: Option<Handler>,
+    // URL prefix and middleware, in the order added
+    middleware: Vec<(String, Arc<dyn Middleware>)>,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Router {
     pub fn new() -> Self {
         Router {
             routes: HashMap::new(),
+            mounts: Vec::new(),
+            embedded: Vec::new(),
             static_dir: None,
+            compression: None,
+            mime: MimeRegistry::new(),
+            cache: None,
+            request_ids: RequestIdConfig::new(),
+            error_renderer: Arc::new(error::render_error),
+            error_handlers: HashMap::new(),
+            error_pages: HashMap::new(),
+            fallback: None,
+            middleware: Vec::new(),
         }
     }
 
+    // Fallback directory served at the root when no route or mount matches
     pub fn set_static_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
-        self.static_dir = Some(PathBuf::from(dir.as_ref()));
+        self.static_dir = Some(StaticMount::new("/", PathBuf::from(dir.as_ref()), StaticConfig::default()));
         self
     }
 
-    pub fn add_route<F>(&mut self, method: &str, path: &str, handler: F)
+    // Compress eligible responses according to the client's Accept-Encoding
+    pub fn enable_compression(&mut self, config: CompressionConfig) -> &mut Self {
+        self.compression = Some(config);
+        self
+    }
+
+    // Map an extension to a media type, overriding the built-in table
+    pub fn register_mime(&mut self, extension: &str, mime: &str) -> &mut Self {
+        self.mime.register(extension, mime);
+        self
+    }
+
+    // Guess the type of extensionless static files from their contents
+    pub fn enable_mime_sniffing(&mut self, enabled: bool) -> &mut Self {
+        self.mime.set_sniffing(enabled);
+        self
+    }
+
+    // Keep hot static files in memory instead of reading them on every hit
+    pub fn set_static_cache(&mut self, cache: StaticCache) -> &mut Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    // Header name and trust policy for request IDs
+    pub fn set_request_ids(&mut self, config: RequestIdConfig) -> &mut Self {
+        self.request_ids = config;
+        self
+    }
+
+    // Replace how handler errors and routing misses are turned into responses
+    pub fn set_error_renderer<F>(&mut self, renderer: F) -> &mut Self
+    where
+        F: Fn(&Request, &HsError) -> Response + Send + Sync + 'static,
+    {
+        self.error_renderer = Arc::new(renderer);
+        self
+    }
+
+    // Custom response for one status, taking precedence over templates and the renderer
+    pub fn on_error<F>(&mut self, status: u16, handler: F) -> &mut Self
+    where
+        F: Fn(&Request, &HsError) -> Response + Send + Sync + 'static,
+    {
+        self.error_handlers.insert(status, Arc::new(handler));
+        self
+    }
+
+    // Serve `<dir>/<status>.html` (e.g. `public/404.html`) for errors of that status.
+    // `{{status}}`, `{{reason}}`, `{{message}}` and `{{request_id}}` are filled in.
+    // The templates are read once, here; restart to pick up changes.
+    pub fn error_pages<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.error_pages = load_error_pages(dir.as_ref());
+        self
+    }
+
+    // Handler for requests no route or mount matched, instead of a 404
+    pub fn fallback<F, R>(&mut self, handler: F) -> &mut Self
     where
-        F: Fn(&mut Request) -> Response + Send + Sync + 'static,
+        F: Fn(&mut Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        self.fallback = Some(into_handler(handler));
+        self
+    }
+
+    // Run `middleware` around every request under `prefix` ("/" for all of them);
+    // middleware added first sees the request first
+    pub fn middleware<M: Middleware + 'static>(&mut self, prefix: &str, middleware: M) -> &mut Self {
+        self.middleware.push((prefix.trim_end_matches('/').to_string(), Arc::new(middleware)));
+        self
+    }
+
+    pub fn add_route<F, R>(&mut self, method: &str, path: &str, handler: F)
+    where
+        F: Fn(&mut Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
     {
         let method = method.to_uppercase();
-        let routes = self.routes.entry(method).or_insert_with(Vec::new);
+        let routes = self.routes.entry(method).or_default();
         routes.push(Route::new(path, handler));
     }
 
-    pub fn get<F>(&mut self, path: &str, handler: F)
+    pub fn get<F, R>(&mut self, path: &str, handler: F)
     where
-        F: Fn(&mut Request) -> Response + Send + Sync + 'static,
+        F: Fn(&mut Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
     {
         self.add_route("GET", path, handler);
     }
 
-    pub fn post<F>(&mut self, path: &str, handler: F)
+    pub fn post<F, R>(&mut self, path: &str, handler: F)
     where
-        F: Fn(&mut Request) -> Response + Send + Sync + 'static,
+        F: Fn(&mut Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
     {
         self.add_route("POST", path, handler);
     }
 
-    pub fn put<F>(&mut self, path: &str, handler: F)
+    pub fn put<F, R>(&mut self, path: &str, handler: F)
     where
-        F: Fn(&mut Request) -> Response + Send + Sync + 'static,
+        F: Fn(&mut Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
     {
         self.add_route("PUT", path, handler);
     }
 
-    pub fn delete<F>(&mut self, path: &str, handler: F)
+    pub fn delete<F, R>(&mut self, path: &str, handler: F)
     where
-        F: Fn(&mut Request) -> Response + Send + Sync + 'static,
+        F: Fn(&mut Request) -> R + Send + Sync + 'static,
+        R: IntoResponse,
     {
         self.add_route("DELETE", path, handler);
     }
 
+    // Accept WebSocket connections at `path`; `handler` runs once the handshake is done.
+    // Only the tokio engine serves these; plain requests get 426 Upgrade Required.
+    pub fn websocket<F, Fut>(&mut self, path: &str, handler: F) -> &mut Self
+    where
+        F: Fn(Request, WebSocket) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.websocket_with(path, WebSocketConfig::new(), handler)
+    }
+
+    pub fn websocket_with<F, Fut>(&mut self, path: &str, config: WebSocketConfig, handler: F) -> &mut Self
+    where
+        F: Fn(Request, WebSocket) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let handler: WebSocketHandler = Arc::new(move |request, socket| Box::pin(handler(request, socket)));
+        self.get(path, move |request| websocket::accept(request, &config, &handler));
+        self
+    }
+
+    // Expose the Prometheus metrics at `path`, e.g. `/metrics`
+    pub fn metrics_endpoint(&mut self, path: &str) -> &mut Self {
+        self.get(path, |_req| {
+            Response::new()
+                .with_header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+                .with_body(&metrics::metrics().render())
+        });
+        self
+    }
+
     pub fn serve_static(&mut self, url_path: &str, dir_path: &str) -> &mut Self {
-        let static_dir = PathBuf::from(dir_path);
-        self.static_dir = Some(static_dir.clone());
-
-        // Add a route handler for the static file URL path
-        self.get(&format!("{}/*filepath", url_path.trim_end_matches('/')), move |req| {
-            // Extract the file path from the URL
-            if let Some(file_path_str) = req.params.get("filepath") {
-                let mut file_path = static_dir.clone();
-                // Use only the filepath parameter, not the entire path
-                for segment in file_path_str.split('/') {
-                    // Prevent directory traversal attacks
-                    if segment != ".." && segment != "." && !segment.is_empty() {
-                        file_path.push(segment);
-                    }
-                }
+        self.serve_static_with(url_path, dir_path, StaticConfig::default())
+    }
 
-                // Try to read the file
-                match fs::read(&file_path) {
-                    Ok(content) => {
-                        // Determine content type from file extension
-                        let content_type = get_content_type(&file_path);
-                        Response::new()
-                            .with_header("Content-Type", content_type)
-                            .with_body_bytes(content)
-                    },
-                    Err(e) => {
-                        println!("Error reading file {:?}: {}", file_path, e);
-                        match e.kind() {
-                            std::io::ErrorKind::NotFound => Response::not_found(),
-                            std::io::ErrorKind::PermissionDenied => {
-                                Response::new()
-                                    .with_status(403)
-                                    .with_body("403 Forbidden: Access to this resource is denied")
-                            },
-                            _ => Response::new()
-                                .with_status(500)
-                                .with_body("500 Internal Server Error")
-                        }
-                    }
-                }
-            } else {
-                Response::not_found()
+    pub fn serve_static_with(&mut self, url_path: &str, dir_path: &str, config: StaticConfig) -> &mut Self {
+        self.mounts.push(StaticMount::new(url_path, PathBuf::from(dir_path), config));
+        // Most specific mount wins when prefixes overlap
+        self.mounts.sort_by_key(|mount| std::cmp::Reverse(mount.url_prefix().len()));
+        self
+    }
+
+    // Route the request and apply response post-processing; misses become 404s
+    pub fn handle(&self, request: &mut Request) -> Response {
+        if request.id.is_empty() {
+            request.id = self.request_ids.resolve(request.header(self.request_ids.header()));
+        }
+        // Log lines written while handling carry the request ID
+        let _scope = RequestIdScope::enter(&request.id);
+
+        let path = request.path.split('?').next().unwrap_or("");
+        let chain: Vec<Arc<dyn Middleware>> = self
+            .middleware
+            .iter()
+            .filter(|(prefix, _)| {
+                path.strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+            .map(|(_, middleware)| Arc::clone(middleware))
+            .collect();
+
+        // A panicking handler costs one 500, not the worker thread or connection
+        let routed = panic::catch_unwind(AssertUnwindSafe(|| Next::new(self, &chain).run(request)));
+        let mut response = match routed {
+            Ok(response) => response,
+            Err(payload) => {
+                let route = request.route.as_deref().unwrap_or(metrics::UNMATCHED_ROUTE);
+                metrics::metrics().panics.inc(&["handler", route]);
+                log_error!(
+                    "handler for {} {} (route {}) panicked: {}",
+                    request.method,
+                    request.path,
+                    route,
+                    panic_message(payload.as_ref())
+                );
+                self.render_error(request, &HsError::Internal("handler panicked".to_string()))
             }
-        });
+        };
+        response
+            .headers
+            .insert(self.request_ids.header().to_string(), request.id.clone());
+
+        if let Some(config) = &self.compression {
+            compression::compress_response(request, &mut response, config);
+        }
+
+        response
+    }
+
+    // Route the request, rendering a handler error; the end of the middleware chain
+    pub(crate) fn dispatch(&self, request: &mut Request) -> Response {
+        self.route(request).unwrap_or_else(|e| {
+            if let HsError::Internal(message) = &e {
+                log_error!("{} {} failed: {}", request.method, request.path, message);
+            }
+            self.render_error(request, &e)
+        })
+    }
+
+    // on_error handler, then the status template (for non-JSON clients), then the renderer
+    pub fn render_error(&self, request: &Request, error: &HsError) -> Response {
+        let status = error.status();
+        if let Some(handler) = self.error_handlers.get(&status) {
+            return handler(request, error);
+        }
+        let wants_json = request.header("Accept").is_some_and(error::prefers_json);
+        if let (Some(template), false) = (self.error_pages.get(&status), wants_json) {
+            return error::render_template(template, request, error);
+        }
+        (self.error_renderer)(request, error)
+    }
+
+    // Header the request ID is read from and echoed in
+    pub fn request_id_header(&self) -> &str {
+        self.request_ids.header()
+    }
+
+    // Error response for a connection that never produced a routable request, e.g. a parse failure
+    pub fn reject(&self, error: HsError) -> Response {
+        let request = Request {
+            id: self.request_ids.resolve(None),
+            ..Request::default()
+        };
+        let _scope = RequestIdScope::enter(&request.id);
+        self.render_error(&request, &error)
+            .with_header(self.request_id_header(), &request.id)
+    }
 
+    // Serve assets compiled into the binary, e.g. `hs::embed::ASSETS`
+    pub fn serve_embedded(&mut self, url_path: &str, files: &'static [EmbeddedFile]) -> &mut Self {
+        self.embedded.push(EmbeddedMount::new(url_path, files));
         self
     }
 
-    pub fn route(&self, request: &mut Request) -> Option<Response> {
+    // Misses come back as `HsError::NotFound`
+    pub fn route(&self, request: &mut Request) -> HandlerResult {
         // First try to match defined routes
         if let Some(routes) = self.routes.get(&request.method) {
             for route in routes {
                 // Try to match the route
                 if route.matches(&request.path, &mut request.params) {
+                    request.route = Some(route.pattern.clone());
                     // Route matched, call the handler
-                    return Some((route.handler)(request));
+                    return (route.handler)(request);
                 }
             }
         }
 
-        // If no route matched and it's a GET request, try to serve a static file
-        if request.method == "GET" && self.static_dir.is_some() {
-            let path = request.path.trim_start_matches('/');
-            let mut file_path = self.static_dir.as_ref().unwrap().clone();
-
-            // Build the file path, preventing directory traversal
-            for segment in path.split('/') {
-                if segment != ".." && segment != "." && !segment.is_empty() {
-                    file_path.push(segment);
+        // If no route matched and it's a GET request, try the static mounts
+        if request.method == "GET" {
+            let ctx = ServeContext {
+                mime: &self.mime,
+                cache: self.cache.as_ref(),
+                compression: self.compression.as_ref(),
+            };
+            for mount in &self.mounts {
+                if let Some(response) = mount.serve(request, &ctx) {
+                    request.route = Some(format!("{}/*", mount.url_prefix()));
+                    return response;
                 }
             }
-
-            // Try to read the file
-            match fs::read(&file_path) {
-                Ok(content) => {
-                    // Determine content type from file extension
-                    let content_type = get_content_type(&file_path);
-                    return Some(Response::new()
-                        .with_header("Content-Type", content_type)
-                        .with_body_bytes(content));
-                },
-                Err(_) => {
-                    // File not found or other error, continue to return None
+            for mount in &self.embedded {
+                if let Some(response) = mount.serve(request, &self.mime) {
+                    request.route = Some(format!("{}/*", mount.url_prefix()));
+                    return response;
+                }
+            }
+            if let Some(response) = self.static_dir.as_ref().and_then(|mount| mount.serve(request, &ctx)) {
+                request.route = Some("/*".to_string());
+                return response;
+            }
+            // Only once no real file matched do single-page apps get their index
+            for mount in &self.mounts {
+                if let Some(response) = mount.serve_spa_fallback(request, &ctx) {
+                    request.route = Some(format!("{}/*", mount.url_prefix()));
+                    return response;
                 }
             }
         }
 
         // No route matched
-        None
-    }
-}
-
-fn get_content_type(path: &Path) -> &'static str {
-    match path.extension().and_then(|e| e.to_str()) {
-        Some("html") => "text/html",
-        Some("css") => "text/css",
-        Some("js") => "application/javascript",
-        Some("json") => "application/json",
-        Some("png") => "image/png",
-        Some("jpg") | Some("jpeg") => "image/jpeg",
-        Some("gif") => "image/gif",
-        Some("svg") => "image/svg+xml",
-        Some("pdf") => "application/pdf",
-        Some("txt") => "text/plain",
-        Some("xml") => "application/xml",
-        Some("ico") => "image/x-icon",
-        _ => "application/octet-stream",  // Default binary data
+        match &self.fallback {
+            Some(fallback) => {
+                request.route = Some("fallback".to_string());
+                fallback(request)
+            }
+            None => Err(HsError::NotFound),
+        }
     }
 }
 
@@ -249,6 +518,7 @@ pub fn parse_request(request_lines: &[String]) -> Option<Request> {
 
     let method = parts[0].to_string();
     let path = parts[1].to_string();
+    let version = parts.get(2).unwrap_or(&"HTTP/1.0").to_string();
 
     let mut headers = HashMap::new();
     for line in &request_lines[1..] {
@@ -260,32 +530,39 @@ pub fn parse_request(request_lines: &[String]) -> Option<Request> {
     }
 
     Some(Request {
+        id: String::new(),
         method,
         path,
+        version,
         headers,
         body: Vec::new(),  // We'll parse body later if needed
         params: HashMap::new(),
+        peer_addr: None,
+        route: None,
     })
 }
 
-pub fn response_to_string(response: &Response) -> String {
-    let status_text = match response.status {
-        200 => "OK",
-        201 => "Created",
-        204 => "No Content",
-        400 => "Bad Request",
-        401 => "Unauthorized",
-        403 => "Forbidden",
-        404 => "Not Found",
-        500 => "Internal Server Error",
-        _ => "Unknown",
-    };
+// Bytes of body that follow the head, from Content-Length
+pub fn body_length(request: &Request) -> Result<u64, HsError> {
+    if request.header("Transfer-Encoding").is_some() {
+        return Err(HsError::Status(501, "Chunked request bodies are not supported.".to_string()));
+    }
+    match request.header("Content-Length") {
+        None => Ok(0),
+        Some(value) => value
+            .trim()
+            .parse()
+            .map_err(|_| HsError::BadRequest("Invalid Content-Length header.".to_string())),
+    }
+}
 
-    let mut response_string = format!("HTTP/1.1 {} {}\r\n", response.status, status_text);
+// Status line and headers, including the blank line that ends them
+pub fn response_head(response: &Response) -> String {
+    let mut response_string = format!("HTTP/1.1 {} {}\r\n", response.status, reason_phrase(response.status));
 
-    // Add Content-Length header if not already present
-    if !response.headers.contains_key("Content-Length") {
-        response_string.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+    // Add Content-Length header if not already present; 1xx, 204 and 304 carry no body
+    if !response.headers.contains_key("Content-Length") && !matches!(response.status, 100..=199 | 204 | 304) {
+        response_string.push_str(&format!("Content-Length: {}\r\n", response.content_length()));
     }
 
     // Add all headers
@@ -295,14 +572,56 @@ pub fn response_to_string(response: &Response) -> String {
 
     // Add empty line to separate headers from body
     response_string.push_str("\r\n");
+    response_string
+}
 
-    // We need to convert the response to a byte array for sending
-    // For simplicity in this example, we'll convert binary data to a string
-    // In a real implementation, you would keep this as binary data
-    let mut result = response_string.into_bytes();
-    result.extend_from_slice(&response.body);
+// Full response as raw bytes; file bodies are read into memory
+pub fn response_to_bytes(response: &Response) -> std::io::Result<Vec<u8>> {
+    let mut result = response_head(response).into_bytes();
+    match &response.file {
+        Some(file) => crate::sendfile::copy_file(&mut result, &file.file, 0, file.len)?,
+        None => result.extend_from_slice(response.bytes()),
+    }
+    Ok(result)
+}
 
-    // This is a hack for the current implementation
-    // In a real implementation, you'd handle binary data properly
+pub fn response_to_string(response: &Response) -> String {
+    // This is a hack for callers that want text; binary bodies are mangled
+    // Connection writers should use response_head and send the body separately
+    let result = response_to_bytes(response).unwrap_or_default();
     String::from_utf8_lossy(&result).to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_pages_are_loaded_once() {
+        let dir = std::env::temp_dir().join(format!("hs-error-pages-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("404.html"), "<h1>{{status}} {{reason}}</h1><p>{{message}}</p>").unwrap();
+        fs::write(dir.join("index.html"), "not an error page").unwrap();
+        fs::write(dir.join("200.html"), "not an error status").unwrap();
+
+        let mut router = Router::new();
+        router.error_pages(&dir);
+        assert_eq!(router.error_pages.keys().copied().collect::<Vec<_>>(), vec![404]);
+
+        // Later edits on disk don't change what is served
+        fs::write(dir.join("404.html"), "changed").unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        let response = router.render_error(&Request::default(), &HsError::Status(404, "No <such> page.".to_string()));
+        assert_eq!(response.status, 404);
+        assert_eq!(
+            String::from_utf8(response.body).unwrap(),
+            "<h1>404 Not Found</h1><p>No &lt;such&gt; page.</p>"
+        );
+    }
+
+    #[test]
+    fn unknown_placeholders_are_found() {
+        assert_eq!(unknown_placeholders("{{status}} {{staus}} {{message}} {{ id }} {{oops"), vec!["staus", " id "]);
+        assert!(unknown_placeholders("{{status}}{{reason}}{{message}}{{request_id}}").is_empty());
+    }
+}
@@ -0,0 +1,120 @@
+//Zero-copy file transmission
+//Static files are sent with sendfile(2) on Linux so the bytes never pass through userspace.
+//Anything else (other platforms, sockets or files sendfile refuses) falls back to a buffered copy.
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+
+// Largest count a single sendfile(2) call will transfer on Linux
+#[cfg(target_os = "linux")]
+const MAX_SENDFILE_CHUNK: u64 = 0x7fff_f000;
+
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+// Send `len` bytes of `file` (from its start) over a blocking TCP stream
+#[cfg(target_os = "linux")]
+pub fn send_file(stream: &mut TcpStream, file: &File, len: u64) -> io::Result<()> {
+    let mut offset: libc::off_t = 0;
+    let mut remaining = len;
+
+    while remaining > 0 {
+        match sendfile_once(stream.as_raw_fd(), file.as_raw_fd(), &mut offset, remaining) {
+            Ok(0) => {
+                // The file shrank underneath us, nothing more to send
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file truncated during sendfile"));
+            }
+            Ok(n) => remaining -= n as u64,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) if is_unsupported(&e) => {
+                return copy_file(stream, file, offset as u64, remaining);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn send_file(stream: &mut TcpStream, file: &File, len: u64) -> io::Result<()> {
+    copy_file(stream, file, 0, len)
+}
+
+// Async variant for the tokio engine, waiting for writability between partial sends
+#[cfg(target_os = "linux")]
+pub async fn send_file_async(stream: &mut tokio::net::TcpStream, file: &File, len: u64) -> io::Result<()> {
+    let mut offset: libc::off_t = 0;
+    let mut remaining = len;
+
+    while remaining > 0 {
+        stream.writable().await?;
+        let result = stream.try_io(tokio::io::Interest::WRITABLE, || {
+            sendfile_once(stream.as_raw_fd(), file.as_raw_fd(), &mut offset, remaining)
+        });
+        match result {
+            Ok(0) => {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file truncated during sendfile"));
+            }
+            Ok(n) => remaining -= n as u64,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) if is_unsupported(&e) => {
+                return copy_file_async(stream, file, offset as u64, remaining).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn send_file_async(stream: &mut tokio::net::TcpStream, file: &File, len: u64) -> io::Result<()> {
+    copy_file_async(stream, file, 0, len).await
+}
+
+#[cfg(target_os = "linux")]
+fn sendfile_once(out_fd: i32, in_fd: i32, offset: &mut libc::off_t, remaining: u64) -> io::Result<usize> {
+    let count = remaining.min(MAX_SENDFILE_CHUNK) as usize;
+    let sent = unsafe { libc::sendfile(out_fd, in_fd, offset, count) };
+    if sent < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(sent as usize)
+    }
+}
+
+// sendfile(2) reports these when the file or socket type isn't supported
+#[cfg(target_os = "linux")]
+fn is_unsupported(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EINVAL) | Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP))
+}
+
+// Buffered fallback: copy `len` bytes starting at `offset` through userspace
+pub fn copy_file<W: Write>(writer: &mut W, file: &File, offset: u64, len: u64) -> io::Result<()> {
+    let mut file = file;
+    file.seek(SeekFrom::Start(offset))?;
+    let copied = io::copy(&mut io::BufReader::with_capacity(COPY_BUFFER_SIZE, file.take(len)), writer)?;
+    if copied < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file truncated during copy"));
+    }
+    Ok(())
+}
+
+pub async fn copy_file_async<W>(writer: &mut W, file: &File, offset: u64, len: u64) -> io::Result<()>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    let mut file = tokio::fs::File::from_std(file.try_clone()?);
+    file.seek(SeekFrom::Start(offset)).await?;
+    let copied = tokio::io::copy(&mut file.take(len), writer).await?;
+    if copied < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file truncated during copy"));
+    }
+    Ok(())
+}
@@ -0,0 +1,801 @@
+//Static file mounts
+//Each `serve_static` call registers a mount mapping a URL prefix onto a directory.
+//Directories resolve to an index file, redirect to a trailing slash, or list their contents.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::log_warn;
+use crate::cache::{entity_tag, format_http_date, CachedAsset, StaticCache};
+use crate::compression::{self, variant_tag, CompressionConfig, Encoding};
+use crate::error::{HandlerResult, HsError};
+use crate::mime::MimeRegistry;
+use crate::types::{not_modified, Request, Response};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ListingFormat {
+    Html,
+    Json,
+}
+
+#[derive(Clone, Debug)]
+pub struct StaticConfig {
+    index_files: Vec<String>,
+    listing: Option<ListingFormat>,
+    precompressed: bool,
+    symlinks: SymlinkPolicy,
+    dotfiles: bool,
+    spa_fallback: Option<String>,
+    spa_excludes: Vec<String>,
+}
+
+impl Default for StaticConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StaticConfig {
+    pub fn new() -> Self {
+        StaticConfig {
+            index_files: vec!["index.html".to_string()],
+            listing: None,
+            precompressed: true,
+            symlinks: SymlinkPolicy::WithinRoot,
+            dotfiles: false,
+            spa_fallback: None,
+            spa_excludes: Vec::new(),
+        }
+    }
+
+    // Index files are tried in the order they were added
+    pub fn with_index_file(mut self, name: &str) -> Self {
+        self.index_files.push(name.to_string());
+        self
+    }
+
+    pub fn without_index_files(mut self) -> Self {
+        self.index_files.clear();
+        self
+    }
+
+    // Generate a listing for directories that have no index file
+    pub fn with_listing(mut self, format: ListingFormat) -> Self {
+        self.listing = Some(format);
+        self
+    }
+
+    // Serve `app.js.br` / `app.js.gz` in place of `app.js` when the client accepts them
+    pub fn with_precompressed(mut self, enabled: bool) -> Self {
+        self.precompressed = enabled;
+        self
+    }
+
+    pub fn with_symlinks(mut self, policy: SymlinkPolicy) -> Self {
+        self.symlinks = policy;
+        self
+    }
+
+    // Dotfiles such as `.env` or `.git/` are hidden unless explicitly allowed
+    pub fn with_dotfiles(mut self, allowed: bool) -> Self {
+        self.dotfiles = allowed;
+        self
+    }
+
+    // Answer unmatched GET requests that accept text/html with this file (relative to the mount),
+    // so client-side routes like `/dashboard/settings` load the single-page app
+    pub fn with_spa_fallback(mut self, file: &str) -> Self {
+        self.spa_fallback = Some(file.trim_start_matches('/').to_string());
+        self
+    }
+
+    // URL prefixes such as `/api` that keep their 404s instead of falling back
+    pub fn with_spa_exclude(mut self, prefix: &str) -> Self {
+        self.spa_excludes.push(prefix.trim_end_matches('/').to_string());
+        self
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    // Never serve a path that passes through a symlink
+    Deny,
+    // Follow symlinks as long as the target stays inside the mount root
+    WithinRoot,
+    // Follow symlinks wherever they point
+    Follow,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ResolveError {
+    // Bad percent-encoding, NUL bytes or backslashes
+    Malformed,
+    // `..` segments or a resolved path outside the root
+    Traversal,
+    // A symlink the policy doesn't allow
+    Symlink,
+    // A dotfile while dotfiles are hidden
+    Hidden,
+    NotFound,
+}
+
+impl ResolveError {
+    // Hidden files are indistinguishable from missing ones
+    fn into_error(self) -> Option<HsError> {
+        match self {
+            ResolveError::Malformed => Some(HsError::BadRequest("Malformed path.".to_string())),
+            ResolveError::Traversal | ResolveError::Symlink => {
+                Some(HsError::Forbidden("Access to this resource is denied.".to_string()))
+            }
+            ResolveError::Hidden | ResolveError::NotFound => None,
+        }
+    }
+}
+
+// Map a URL path below a mount onto a canonical file system path inside `root`
+pub fn resolve_static_path(root: &Path, url_path: &str, config: &StaticConfig) -> Result<PathBuf, ResolveError> {
+    let decoded = percent_decode(url_path).ok_or(ResolveError::Malformed)?;
+    if decoded.contains('\\') || decoded.contains('\0') {
+        return Err(ResolveError::Malformed);
+    }
+
+    let mut relative = PathBuf::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => continue,
+            ".." => return Err(ResolveError::Traversal),
+            s if s.starts_with('.') && !config.dotfiles => return Err(ResolveError::Hidden),
+            s => relative.push(s),
+        }
+    }
+
+    let canonical_root = root.canonicalize().map_err(|_| ResolveError::NotFound)?;
+    let joined = canonical_root.join(&relative);
+
+    if config.symlinks == SymlinkPolicy::Deny {
+        let mut current = canonical_root.clone();
+        for component in relative.components() {
+            current.push(component);
+            match fs::symlink_metadata(&current) {
+                Ok(metadata) if metadata.file_type().is_symlink() => return Err(ResolveError::Symlink),
+                Ok(_) => {}
+                Err(_) => return Err(ResolveError::NotFound),
+            }
+        }
+    }
+
+    let canonical = joined.canonicalize().map_err(|_| ResolveError::NotFound)?;
+    if config.symlinks != SymlinkPolicy::Follow && !canonical.starts_with(&canonical_root) {
+        return Err(ResolveError::Symlink);
+    }
+
+    Ok(canonical)
+}
+
+// Decode %XX escapes; None for truncated escapes, bad hex or invalid UTF-8
+pub fn percent_decode(s: &str) -> Option<String> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// Router-wide state static mounts need while serving
+pub(crate) struct ServeContext<'a> {
+    pub(crate) mime: &'a MimeRegistry,
+    pub(crate) cache: Option<&'a StaticCache>,
+    pub(crate) compression: Option<&'a CompressionConfig>,
+}
+
+pub(crate) struct StaticMount {
+    url_prefix: String,
+    root: PathBuf,
+    config: StaticConfig,
+}
+
+impl StaticMount {
+    pub(crate) fn new(url_prefix: &str, root: PathBuf, config: StaticConfig) -> Self {
+        StaticMount {
+            url_prefix: url_prefix.trim_end_matches('/').to_string(),
+            root,
+            config,
+        }
+    }
+
+    pub(crate) fn url_prefix(&self) -> &str {
+        &self.url_prefix
+    }
+
+    // Path below the mount, or None when the request is outside it
+    fn relative_path<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let rest = path.strip_prefix(self.url_prefix.as_str())?;
+        if rest.is_empty() || rest.starts_with('/') {
+            Some(rest)
+        } else {
+            None
+        }
+    }
+
+    // None means the request isn't for this mount or the file doesn't exist
+    pub(crate) fn serve(&self, request: &Request, ctx: &ServeContext) -> Option<HandlerResult> {
+        let path = request.path.split('?').next().unwrap_or("");
+        let relative = self.relative_path(path)?;
+
+        let file_path = match resolve_static_path(&self.root, relative, &self.config) {
+            Ok(file_path) => file_path,
+            Err(e) => return e.into_error().map(Err),
+        };
+
+        let metadata = fs::metadata(&file_path).ok()?;
+        if !metadata.is_dir() {
+            return self.serve_file(request, relative, &file_path, ctx);
+        }
+
+        // Relative links inside a directory only work with a trailing slash
+        if !path.ends_with('/') {
+            let location = match request.path.split_once('?') {
+                Some((_, query)) => format!("{}/?{}", path, query),
+                None => format!("{}/", path),
+            };
+            return Some(Ok(redirect(&location)));
+        }
+
+        for index in &self.config.index_files {
+            let index_url = format!("{}/{}", relative.trim_end_matches('/'), index);
+            if let Ok(index_path) = resolve_static_path(&self.root, &index_url, &self.config) {
+                if index_path.is_file() {
+                    return self.serve_file(request, &index_url, &index_path, ctx);
+                }
+            }
+        }
+
+        match self.config.listing {
+            Some(format) => match read_listing(&file_path, self.config.dotfiles) {
+                Ok(entries) => Some(Ok(render_listing(path, relative.trim_matches('/').is_empty(), &entries, format))),
+                Err(e) => {
+                    log_warn!("Error listing directory {:?}: {}", file_path, e);
+                    None
+                }
+            },
+            None => None,
+        }
+    }
+
+    // Second chance for requests nothing else matched, when this mount is a single-page app
+    pub(crate) fn serve_spa_fallback(&self, request: &Request, ctx: &ServeContext) -> Option<HandlerResult> {
+        let fallback = self.config.spa_fallback.as_ref()?;
+        let path = request.path.split('?').next().unwrap_or("");
+        self.relative_path(path)?;
+
+        let excluded = self.config.spa_excludes.iter().any(|prefix| {
+            path.strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        });
+        let wants_html = request
+            .header("Accept")
+            .is_some_and(|accept| accept.split(',').any(|media| media.trim().starts_with("text/html")));
+        if excluded || !wants_html {
+            return None;
+        }
+
+        let file_path = resolve_static_path(&self.root, fallback, &self.config).ok()?;
+        self.serve_file(request, fallback, &file_path, ctx)
+    }
+
+    fn serve_file(&self, request: &Request, relative: &str, file_path: &Path, ctx: &ServeContext) -> Option<HandlerResult> {
+        if let Some(cache) = ctx.cache {
+            if let Some(asset) = cache.get(file_path, |path, body| ctx.mime.content_type_for_bytes(path, body)) {
+                let response = self.serve_cached(request, relative, file_path, &asset, cache, ctx);
+                return Some(Ok(not_modified(request, response)));
+            }
+        }
+
+        if self.config.precompressed {
+            if let Some(response) = self.serve_precompressed(request, relative, file_path, ctx.mime) {
+                return Some(Ok(not_modified(request, response)));
+            }
+        }
+
+        match open_static_file(file_path, ctx.mime) {
+            Ok(response) => Some(Ok(not_modified(request, response))),
+            Err(e) => {
+                log_warn!("Error reading file {:?}: {}", file_path, e);
+                match e.kind() {
+                    io::ErrorKind::NotFound => None,
+                    _ => Some(Err(HsError::from(e))),
+                }
+            }
+        }
+    }
+
+    // Build the response from memory, picking (and caching) a compressed variant when possible
+    fn serve_cached(
+        &self,
+        request: &Request,
+        relative: &str,
+        file_path: &Path,
+        asset: &CachedAsset,
+        cache: &StaticCache,
+        ctx: &ServeContext,
+    ) -> Response {
+        let compress = ctx
+            .compression
+            .filter(|config| compression::is_compressible(&asset.content_type) && asset.len() >= config.min_size());
+
+        let mut candidates: Vec<Encoding> = Encoding::ALL
+            .iter()
+            .copied()
+            .filter(|encoding| {
+                compress.is_some_and(|config| config.encodings().contains(encoding))
+                    || (self.config.precompressed && encoding.file_extension().is_some())
+            })
+            .collect();
+        let accept = request.header("Accept-Encoding").unwrap_or("");
+
+        let mut response = Response::new()
+            .with_header("Content-Type", &asset.content_type)
+            .with_header("Last-Modified", &asset.last_modified);
+        let mut encoded = false;
+
+        while let Some(encoding) = compression::negotiate(accept, &candidates) {
+            let variant = cache.variant(file_path, asset, encoding, || {
+                // A precompressed sibling beats compressing on the fly
+                let sibling = self
+                    .config
+                    .precompressed
+                    .then(|| encoding.file_extension())
+                    .flatten()
+                    .and_then(|ext| resolve_static_path(&self.root, &format!("{}.{}", relative, ext), &self.config).ok())
+                    .and_then(|path| fs::read(path).ok());
+                match sibling {
+                    Some(bytes) => Some(bytes),
+                    None if compress.is_some() => encoding.compress(&asset.body).ok(),
+                    None => None,
+                }
+            });
+            match variant {
+                Some(bytes) => {
+                    response = response
+                        .with_header("Content-Encoding", encoding.token())
+                        .with_header("ETag", &variant_tag(&asset.etag, encoding))
+                        .with_shared_body(bytes);
+                    encoded = true;
+                    break;
+                }
+                None => candidates.retain(|&e| e != encoding),
+            }
+        }
+
+        if !encoded {
+            response = response
+                .with_header("ETag", &asset.etag)
+                .with_shared_body(Arc::clone(&asset.body));
+        }
+        if encoded || compress.is_some() {
+            compression::add_vary(&mut response);
+        }
+        response
+    }
+
+    // Serve the best precompressed sibling the client accepts; every response for a path
+    // with siblings varies on Accept-Encoding, including the identity one
+    fn serve_precompressed(&self, request: &Request, relative: &str, file_path: &Path, mime: &MimeRegistry) -> Option<Response> {
+        let siblings: Vec<(Encoding, PathBuf)> = Encoding::ALL
+            .iter()
+            .filter_map(|&encoding| {
+                // Siblings go through the same resolution so they can't escape the root either
+                let sibling = format!("{}.{}", relative, encoding.file_extension()?);
+                let path = resolve_static_path(&self.root, &sibling, &self.config).ok()?;
+                path.is_file().then_some((encoding, path))
+            })
+            .collect();
+        if siblings.is_empty() {
+            return None;
+        }
+
+        let available: Vec<Encoding> = siblings.iter().map(|(encoding, _)| *encoding).collect();
+        let chosen = request
+            .header("Accept-Encoding")
+            .and_then(|accept| compression::negotiate(accept, &available));
+
+        let mut response = match chosen {
+            Some(encoding) => {
+                let (_, path) = siblings.iter().find(|(e, _)| *e == encoding)?;
+                let file = fs::File::open(path).ok()?;
+                let metadata = file.metadata().ok()?;
+                Response::new()
+                    .with_header("Content-Type", &mime.content_type(file_path))
+                    .with_header("Content-Encoding", encoding.token())
+                    .with_header("ETag", &variant_tag(&entity_tag(metadata.modified().ok(), metadata.len()), encoding))
+                    .with_header("Last-Modified", &metadata.modified().map(format_http_date).unwrap_or_default())
+                    .with_file(file, metadata.len())
+            }
+            None => open_static_file(file_path, mime).ok()?,
+        };
+        compression::add_vary(&mut response);
+        Some(response)
+    }
+}
+
+// Build a response that streams the file from disk instead of buffering it
+pub(crate) fn open_static_file(file_path: &Path, mime: &MimeRegistry) -> io::Result<Response> {
+    let file = fs::File::open(file_path)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "not a regular file"));
+    }
+
+    // Determine content type from file extension, or the contents if it has none
+    let content_type = mime.content_type_for_file(file_path, &file);
+    let modified = metadata.modified().ok();
+    Ok(Response::new()
+        .with_header("Content-Type", &content_type)
+        .with_header("ETag", &entity_tag(modified, metadata.len()))
+        .with_header("Last-Modified", &modified.map(format_http_date).unwrap_or_default())
+        .with_file(file, metadata.len()))
+}
+
+fn redirect(location: &str) -> Response {
+    Response::new()
+        .with_status(301)
+        .with_header("Location", location)
+        .with_header("Content-Type", "text/html")
+        .with_body(&format!(
+            "<html><body><p>Moved to <a href=\"{0}\">{0}</a></p></body></html>",
+            escape_html(location)
+        ))
+}
+
+struct ListingEntry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+fn read_listing(dir: &Path, dotfiles: bool) -> io::Result<Vec<ListingEntry>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !dotfiles && entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        entries.push(ListingEntry {
+            name: entry.file_name().to_string_lossy().to_string(),
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().ok(),
+        });
+    }
+
+    // Directories first, then files, each alphabetically
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+    Ok(entries)
+}
+
+fn render_listing(url_path: &str, at_mount_root: bool, entries: &[ListingEntry], format: ListingFormat) -> Response {
+    match format {
+        ListingFormat::Html => {
+            let mut html = format!(
+                "<html><head><title>Index of {0}</title></head><body><h1>Index of {0}</h1>\
+                 <table><tr><th>Name</th><th>Size</th><th>Last modified</th></tr>",
+                escape_html(url_path)
+            );
+            if !at_mount_root {
+                html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>");
+            }
+            for entry in entries {
+                let suffix = if entry.is_dir { "/" } else { "" };
+                html.push_str(&format!(
+                    "<tr><td><a href=\"{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>",
+                    percent_encode(&entry.name),
+                    suffix,
+                    escape_html(&entry.name),
+                    suffix,
+                    if entry.is_dir { "-".to_string() } else { entry.size.to_string() },
+                    entry.modified.map(format_timestamp).unwrap_or_default()
+                ));
+            }
+            html.push_str("</table></body></html>");
+            Response::html(&html)
+        }
+        ListingFormat::Json => {
+            let items: Vec<String> = entries
+                .iter()
+                .map(|entry| {
+                    format!(
+                        "{{\"name\":\"{}\",\"type\":\"{}\",\"size\":{},\"modified\":{}}}",
+                        escape_json(&entry.name),
+                        if entry.is_dir { "directory" } else { "file" },
+                        entry.size,
+                        entry
+                            .modified
+                            .map(|m| format!("\"{}\"", format_timestamp(m)))
+                            .unwrap_or_else(|| "null".to_string())
+                    )
+                })
+                .collect();
+            Response::json(format!(
+                "{{\"path\":\"{}\",\"entries\":[{}]}}",
+                escape_json(url_path),
+                items.join(",")
+            ))
+        }
+    }
+}
+
+pub(crate) fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn escape_json(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => out.push(byte as char),
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+// RFC 3339 UTC timestamp, e.g. 2024-03-01T12:00:00Z
+pub(crate) fn format_timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let rem = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60
+    )
+}
+
+// Days since 1970-01-01 to (year, month, day), after Howard Hinnant's algorithm
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use std::os::unix::fs::symlink;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // A scratch tree: `root` is the mount, `outside.txt` and `outside/` sit next to it.
+    // The symlinks into and out of the root are only made on Unix.
+    struct Tree {
+        base: PathBuf,
+        root: PathBuf,
+    }
+
+    impl Tree {
+        fn new() -> Tree {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let base = std::env::temp_dir().join(format!(
+                "hs-static-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::SeqCst)
+            ));
+            let root = base.join("root");
+            fs::create_dir_all(root.join("sub")).unwrap();
+            fs::create_dir_all(base.join("outside")).unwrap();
+            fs::write(root.join("index.html"), "index").unwrap();
+            fs::write(root.join("sub/a.txt"), "a").unwrap();
+            fs::write(root.join(".secret"), "secret").unwrap();
+            fs::write(base.join("outside.txt"), "outside").unwrap();
+            fs::write(base.join("outside/b.txt"), "b").unwrap();
+            #[cfg(unix)]
+            {
+                symlink("sub/a.txt", root.join("inside-link")).unwrap();
+                symlink("../outside.txt", root.join("outside-link")).unwrap();
+                symlink("../outside", root.join("outside-dir")).unwrap();
+            }
+            Tree { base, root }
+        }
+
+        fn resolve(&self, url_path: &str, config: &StaticConfig) -> Result<PathBuf, ResolveError> {
+            resolve_static_path(&self.root, url_path, config)
+        }
+    }
+
+    impl Drop for Tree {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.base);
+        }
+    }
+
+    #[test]
+    fn plain_paths_resolve_inside_the_root() {
+        let tree = Tree::new();
+        let config = StaticConfig::new();
+        let root = tree.root.canonicalize().unwrap();
+        assert_eq!(tree.resolve("/index.html", &config), Ok(root.join("index.html")));
+        assert_eq!(tree.resolve("/sub//./a.txt", &config), Ok(root.join("sub/a.txt")));
+        assert_eq!(tree.resolve("/missing", &config), Err(ResolveError::NotFound));
+    }
+
+    #[test]
+    fn encoded_dot_segments_are_traversal() {
+        let tree = Tree::new();
+        let config = StaticConfig::new();
+        for path in [
+            "/../outside.txt",
+            "/%2e%2e/outside.txt",
+            "/%2E%2e/outside.txt",
+            "/.%2E/outside.txt",
+            "/..%2foutside.txt",
+            "/..%2Foutside.txt",
+            "/sub/../../outside.txt",
+            "/sub/%2e%2e%2f%2e%2e%2foutside.txt",
+        ] {
+            assert_eq!(tree.resolve(path, &config), Err(ResolveError::Traversal), "{}", path);
+        }
+    }
+
+    #[test]
+    fn double_encoding_is_decoded_once() {
+        let tree = Tree::new();
+        let config = StaticConfig::new();
+        // `%252e%252e` is the literal name `%2e%2e`, not a parent directory
+        assert_eq!(tree.resolve("/%252e%252e/outside.txt", &config), Err(ResolveError::NotFound));
+        assert_eq!(tree.resolve("/%252E%252E%252Foutside.txt", &config), Err(ResolveError::NotFound));
+    }
+
+    #[test]
+    fn backslashes_nul_and_bad_escapes_are_malformed() {
+        let tree = Tree::new();
+        let config = StaticConfig::new();
+        for path in ["/..\\outside.txt", "/%5c..%5coutside.txt", "/index.html%00.txt", "/%", "/%2", "/%zz", "/%ff"] {
+            assert_eq!(tree.resolve(path, &config), Err(ResolveError::Malformed), "{}", path);
+        }
+    }
+
+    #[test]
+    fn dotfiles_are_hidden_unless_allowed() {
+        let tree = Tree::new();
+        assert_eq!(tree.resolve("/.secret", &StaticConfig::new()), Err(ResolveError::Hidden));
+        assert_eq!(tree.resolve("/%2esecret", &StaticConfig::new()), Err(ResolveError::Hidden));
+        let allowed = StaticConfig::new().with_dotfiles(true);
+        assert_eq!(tree.resolve("/.secret", &allowed), Ok(tree.root.canonicalize().unwrap().join(".secret")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_follow_the_policy() {
+        let tree = Tree::new();
+        let root = tree.root.canonicalize().unwrap();
+        let base = tree.base.canonicalize().unwrap();
+
+        let deny = StaticConfig::new().with_symlinks(SymlinkPolicy::Deny);
+        assert_eq!(tree.resolve("/inside-link", &deny), Err(ResolveError::Symlink));
+        assert_eq!(tree.resolve("/outside-link", &deny), Err(ResolveError::Symlink));
+        assert_eq!(tree.resolve("/outside-dir/b.txt", &deny), Err(ResolveError::Symlink));
+        assert_eq!(tree.resolve("/sub/a.txt", &deny), Ok(root.join("sub/a.txt")));
+
+        let within = StaticConfig::new().with_symlinks(SymlinkPolicy::WithinRoot);
+        assert_eq!(tree.resolve("/inside-link", &within), Ok(root.join("sub/a.txt")));
+        assert_eq!(tree.resolve("/outside-link", &within), Err(ResolveError::Symlink));
+        assert_eq!(tree.resolve("/outside-dir/b.txt", &within), Err(ResolveError::Symlink));
+
+        let follow = StaticConfig::new().with_symlinks(SymlinkPolicy::Follow);
+        assert_eq!(tree.resolve("/inside-link", &follow), Ok(root.join("sub/a.txt")));
+        assert_eq!(tree.resolve("/outside-link", &follow), Ok(base.join("outside.txt")));
+        assert_eq!(tree.resolve("/outside-dir/b.txt", &follow), Ok(base.join("outside/b.txt")));
+    }
+
+    #[test]
+    fn directory_redirect_keeps_the_query() {
+        let tree = Tree::new();
+        let mount = StaticMount::new("/files", tree.root.clone(), StaticConfig::new());
+        let mime = MimeRegistry::new();
+        let ctx = ServeContext { mime: &mime, cache: None, compression: None };
+        for (path, location) in [("/files/sub", "/files/sub/"), ("/files/sub?sort=name&dir=asc", "/files/sub/?sort=name&dir=asc")] {
+            let request = Request { method: "GET".to_string(), path: path.to_string(), ..Request::default() };
+            let response = match mount.serve(&request, &ctx) {
+                Some(Ok(response)) => response,
+                _ => panic!("no redirect for {}", path),
+            };
+            assert_eq!(response.status, 301, "{}", path);
+            assert_eq!(response.headers.get("Location").map(String::as_str), Some(location));
+        }
+    }
+
+    #[test]
+    fn generated_paths_never_escape_the_root() {
+        let tree = Tree::new();
+        let root = tree.root.canonicalize().unwrap();
+        let segments = [
+            "", ".", "..", "%2e", "%2e%2e", "%2E%2E", ".%2e", "..%2f", "%2e%2e%2f", "%252e%252e", "%5c", "..%5c",
+            "%00", "sub", "index.html", ".secret", "inside-link", "outside-link", "outside-dir", "outside.txt",
+        ];
+        let configs = [
+            StaticConfig::new().with_symlinks(SymlinkPolicy::Deny),
+            StaticConfig::new().with_symlinks(SymlinkPolicy::WithinRoot),
+            StaticConfig::new().with_symlinks(SymlinkPolicy::WithinRoot).with_dotfiles(true),
+        ];
+        let mut checked = 0;
+        for a in segments {
+            for b in segments {
+                for c in segments {
+                    let path = format!("/{}/{}/{}", a, b, c);
+                    for config in &configs {
+                        if let Ok(resolved) = tree.resolve(&path, config) {
+                            assert!(resolved.starts_with(&root), "{} resolved to {}", path, resolved.display());
+                            checked += 1;
+                        }
+                    }
+                }
+            }
+        }
+        // Make sure the loop exercised real resolutions, not just rejections
+        assert!(checked > 0);
+    }
+
+    #[test]
+    fn cache_hits_share_the_cached_body() {
+        let tree = Tree::new();
+        let mut router = crate::route::Router::new();
+        router.set_static_cache(StaticCache::new(1024));
+        router.serve_static("/files", tree.root.to_str().unwrap());
+        let get = || {
+            let mut request = Request {
+                method: "GET".to_string(),
+                path: "/files/sub/a.txt".to_string(),
+                version: "HTTP/1.1".to_string(),
+                ..Request::default()
+            };
+            router.handle(&mut request)
+        };
+        let (first, second) = (get(), get());
+        assert_eq!(first.bytes(), b"a");
+        assert!(Arc::ptr_eq(first.shared.as_ref().unwrap(), second.shared.as_ref().unwrap()));
+    }
+}
@@ -0,0 +1,219 @@
+//Connection streams
+//What the engines read requests from and write responses to: a plain TCP socket or,
+//with the `tls` feature, a TLS session running over one.
+use std::fs::File;
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+#[cfg(feature = "tls")]
+use tokio::io::AsyncWriteExt;
+
+use crate::sendfile;
+#[cfg(feature = "tls")]
+use crate::tls::{AsyncTlsStream, TlsStream};
+
+// A threaded-engine connection
+pub enum Stream {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+}
+
+impl Stream {
+    // The socket underneath, for timeouts and addresses
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(stream) => stream,
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => &stream.sock,
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().peer_addr()
+    }
+}
+
+// What the threaded engine's HTTP/1 loop needs from a connection besides reads and writes
+pub trait Transport: Read + Write {
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    // Bound each blocking read or write; None blocks for as long as it takes
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    // Send `len` bytes of `file`
+    fn send_file(&mut self, file: &File, len: u64) -> io::Result<()>;
+}
+
+impl Transport for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn send_file(&mut self, file: &File, len: u64) -> io::Result<()> {
+        sendfile::send_file(self, file, len)
+    }
+}
+
+impl Transport for Stream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().peer_addr()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.tcp().set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.tcp().set_write_timeout(timeout)
+    }
+
+    // Only a plain socket can use sendfile(2)
+    fn send_file(&mut self, file: &File, len: u64) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => sendfile::send_file(stream, file, len),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => sendfile::copy_file(stream, file, 0, len),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+// A tokio-engine connection
+pub enum AsyncStream {
+    Plain(tokio::net::TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<AsyncTlsStream>),
+}
+
+impl AsyncStream {
+    pub fn tcp(&self) -> &tokio::net::TcpStream {
+        match self {
+            AsyncStream::Plain(stream) => stream,
+            #[cfg(feature = "tls")]
+            AsyncStream::Tls(stream) => stream.get_ref().0,
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().peer_addr()
+    }
+
+    // The protocol agreed on through ALPN, e.g. `h2`; None for plain connections
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        match self {
+            AsyncStream::Plain(_) => None,
+            #[cfg(feature = "tls")]
+            AsyncStream::Tls(stream) => stream.get_ref().1.alpn_protocol(),
+        }
+    }
+}
+
+// `Transport` for the tokio engine; timeouts are applied around the futures instead
+pub trait AsyncTransport: AsyncRead + AsyncWrite + Send + Unpin + 'static {
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    // h2c is for cleartext connections only; over TLS, HTTP/2 comes through ALPN
+    fn is_tls(&self) -> bool;
+
+    fn send_file(&mut self, file: &File, len: u64) -> impl Future<Output = io::Result<()>> + Send;
+}
+
+impl AsyncTransport for AsyncStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().peer_addr()
+    }
+
+    fn is_tls(&self) -> bool {
+        !matches!(self, AsyncStream::Plain(_))
+    }
+
+    async fn send_file(&mut self, file: &File, len: u64) -> io::Result<()> {
+        match self {
+            AsyncStream::Plain(stream) => sendfile::send_file_async(stream, file, len).await,
+            #[cfg(feature = "tls")]
+            AsyncStream::Tls(stream) => {
+                sendfile::copy_file_async(stream, file, 0, len).await?;
+                // TLS records may still be buffered
+                stream.flush().await
+            }
+        }
+    }
+}
+
+impl AsyncRead for AsyncStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            AsyncStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for AsyncStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            AsyncStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            AsyncStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            AsyncStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            AsyncStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -2,68 +2,1305 @@
 //we need to execute multiple tasks concurrently
 //creating a new thread for each task is expensive
 //We want to limit the maximum number of threads running simultaneously
+//and the number of tasks allowed to wait for one
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    any::Any,
+    collections::{HashMap, HashSet},
+    fmt,
+    marker::PhantomData,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock,
+    },
     thread,
+    time::{Duration, Instant},
 };
-type Job = Box<dyn FnOnce() + Send + 'static>;
-pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+use crossbeam_deque::{Injector, Steal, Stealer, Worker as Deque};
+
+use crate::log_error;
+use crate::metrics;
+
+// Times `DropOldest` looks for a queued task to shed before refusing the new one
+const DROP_OLDEST_RETRIES: u32 = 64;
+
+// How often `join_workers` re-checks its threads while waiting for them
+const JOIN_POLL: Duration = Duration::from_millis(100);
+
+// What `execute` does when the queue is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueuePolicy {
+    // Wait for space, pushing back on whoever submits (e.g. the accept loop)
+    Block,
+    // Refuse the new task
+    Reject,
+    // Shed the task that has waited longest to make room
+    DropOldest,
+}
+
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    min_workers: usize,
+    max_workers: usize,
+    keep_alive: Duration,
+    queue_capacity: usize,
+    policy: QueuePolicy,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PoolConfig {
+    pub fn new() -> Self {
+        PoolConfig {
+            min_workers: 4,
+            max_workers: 4,
+            keep_alive: Duration::from_secs(60),
+            queue_capacity: 1024,
+            policy: QueuePolicy::Block,
+        }
+    }
+
+    // Fixed-size pool
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.min_workers = workers.max(1);
+        self.max_workers = self.min_workers;
+        self
+    }
+
+    // Workers kept alive even when idle
+    pub fn with_min_workers(mut self, workers: usize) -> Self {
+        self.min_workers = workers.max(1);
+        self.max_workers = self.max_workers.max(self.min_workers);
+        self
+    }
+
+    // Upper bound the pool grows to while tasks are queueing
+    pub fn with_max_workers(mut self, workers: usize) -> Self {
+        self.max_workers = workers.max(1);
+        self.min_workers = self.min_workers.min(self.max_workers);
+        self
+    }
+
+    // How long a worker above the minimum may sit idle before it retires
+    pub fn with_keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    // Tasks allowed to wait for a worker before the policy kicks in
+    pub fn with_queue_capacity(mut self, capacity: usize) -> Self {
+        self.queue_capacity = capacity.max(1);
+        self
+    }
+
+    pub fn with_policy(mut self, policy: QueuePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PoolError {
+    // Rejected by `QueuePolicy::Reject`; the task's `reject` has already run
+    QueueFull,
+    // `shutdown` has been called; the task's `reject` has already run
+    ShutDown,
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolError::QueueFull => write!(f, "thread pool queue is full"),
+            PoolError::ShutDown => write!(f, "thread pool is shut down"),
+        }
+    }
+}
+
+impl std::error::Error for PoolError {}
+
+// Workers still busy when `shutdown` gave up waiting; they are left detached
+#[derive(Debug, PartialEq, Eq)]
+pub struct ShutdownTimeout {
+    pub unfinished: Vec<String>,
+}
+
+impl fmt::Display for ShutdownTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "thread pool workers did not finish in time: {}", self.unfinished.join(", "))
+    }
+}
+
+impl std::error::Error for ShutdownTimeout {}
+
+// A unit of work; `reject` runs instead of `run` when the queue sheds it
+pub trait Task: Send + 'static {
+    fn run(self: Box<Self>);
+
+    fn reject(self: Box<Self>) {}
+}
+
+impl<F: FnOnce() + Send + 'static> Task for F {
+    fn run(self: Box<Self>) {
+        (*self)()
+    }
+}
+
+struct Queued {
+    task: Box<dyn Task>,
+    enqueued: Instant,
+}
+
+// Pool bookkeeping that changes rarely enough to sit behind a lock
+struct State {
+    next_id: usize,
+    // Workers that have exited after the pool closed, waiting to be joined
+    exited: HashSet<usize>,
+    // Notifications sent whose worker hasn't woken up yet
+    wakeups: usize,
+}
+
+// Why a worker stopped asking for tasks
+enum Exit {
+    // Idle past the keep-alive while above the minimum
+    Retired,
+    Closed,
+}
+
+// Submitters push to a shared injector; each worker pulls batches into its own
+// FIFO deque and steals from the others once both run dry, so dequeueing never
+// serializes on a lock while there is work around. Idle workers park on a condvar.
+struct Shared {
+    injector: Injector<Queued>,
+    stealers: RwLock<Vec<(usize, Stealer<Queued>)>>,
+    // Queue slots taken, counting tasks that are still being pushed
+    reserved: AtomicUsize,
+    // Tasks sitting in the injector or a worker deque
+    queued: AtomicUsize,
+    // Tasks a worker has taken and not yet finished
+    running: AtomicUsize,
+    workers: AtomicUsize,
+    // Workers parked waiting for a task and not yet notified; changed under `state`
+    sleeping: AtomicUsize,
+    // Workers just woken or looking for work to steal; while any are, submitters
+    // leave the sleepers alone
+    searching: AtomicUsize,
+    // Set under `state` once `shutdown` starts; no new tasks are accepted
+    closed: AtomicBool,
+    state: Mutex<State>,
+    // Signalled when a task is queued or the pool closes
+    available: Condvar,
+    // Signalled when a full queue gets space
+    space: Condvar,
+    // Signalled when the last running task finishes with nothing queued
+    idle: Condvar,
+    // Signalled when a worker exits after the pool closed
+    exited: Condvar,
+    config: PoolConfig,
+    threads: Mutex<HashMap<usize, thread::JoinHandle<()>>>,
+    completed: AtomicU64,
+}
+
+// Snapshot for diagnostics
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    pub workers: usize,
+    // Workers running a task
+    pub active: usize,
+    pub idle: usize,
+    pub queued: usize,
+    pub completed: u64,
+}
+
+impl Shared {
+    // A task that panicked while holding the lock must not take the pool down with it
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Claim a queue slot, or None when the queue is at capacity
+    fn reserve(&self) -> Option<()> {
+        let capacity = self.config.queue_capacity;
+        self.reserved
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (n < capacity).then_some(n + 1))
+            .ok()
+            .map(|_| ())
+    }
+
+    fn push(&self, task: Box<dyn Task>) {
+        self.injector.push(Queued {
+            task,
+            enqueued: Instant::now(),
+        });
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        metrics::metrics().pool_queue_depth.inc();
+        // Pairs with the re-check in `park`, so a worker can't sleep through this task
+        if self.searching.load(Ordering::SeqCst) == 0 {
+            self.wake_one();
+        }
+    }
+
+    // Wake a parked worker, which counts as searching until it finds a task.
+    // Claiming the sleeper stops a burst of submissions waking every worker.
+    fn wake_one(&self) {
+        if self.sleeping.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        let mut state = self.lock();
+        if self.sleeping.load(Ordering::SeqCst) > 0 {
+            self.sleeping.fetch_sub(1, Ordering::SeqCst);
+            self.searching.fetch_add(1, Ordering::SeqCst);
+            state.wakeups += 1;
+            self.available.notify_one();
+        }
+    }
+
+    // A searching worker found a task; if it was the last searcher and more work is
+    // queued, hand the search on so the backlog is spread over the sleepers
+    fn stop_searching(&self) {
+        if self.searching.fetch_sub(1, Ordering::SeqCst) == 1 && self.queued.load(Ordering::SeqCst) > 0 {
+            self.wake_one();
+        }
+    }
+
+    // The task that has waited longest, taking over its queue slot
+    fn steal_oldest(&self) -> Option<Queued> {
+        let stolen = steal_retrying(|| {
+            self.injector
+                .steal()
+                .or_else(|| self.stealers.read().unwrap_or_else(PoisonError::into_inner).iter().map(|(_, s)| s.steal()).collect())
+        });
+        if stolen.is_some() {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            metrics::metrics().pool_queue_depth.dec();
+        }
+        stolen
+    }
+
+    fn find_task(&self, local: &Deque<Queued>) -> Option<Queued> {
+        let found = local.pop().or_else(|| {
+            // Scanning every stealer is O(workers); skip it when there is nothing to find
+            if self.queued.load(Ordering::SeqCst) == 0 {
+                return None;
+            }
+            steal_retrying(|| {
+                self.injector.steal_batch_and_pop(local).or_else(|| {
+                    self.stealers
+                        .read()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .iter()
+                        .map(|(_, s)| s.steal())
+                        .collect()
+                })
+            })
+        })?;
+
+        metrics::metrics().pool_queue_depth.dec();
+        // Counted as running before its slot is freed, so `wait_idle` never sees a gap
+        self.running.fetch_add(1, Ordering::SeqCst);
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        let before = self.reserved.fetch_sub(1, Ordering::SeqCst);
+        if before >= self.config.queue_capacity && self.config.policy == QueuePolicy::Block {
+            let _state = self.lock();
+            self.space.notify_all();
+        }
+        Some(found)
+    }
+
+    // Sleep until work may be available; Err when the worker should exit.
+    // Ok(true) when a submitter woke us and already counted us as searching.
+    fn park(&self) -> Result<bool, Exit> {
+        let mut state = self.lock();
+        if self.queued.load(Ordering::SeqCst) > 0 {
+            // Counted but not found: another worker is mid-pop or mid-batch, let it finish
+            drop(state);
+            thread::yield_now();
+            return Ok(false);
+        }
+        if self.closed.load(Ordering::SeqCst) {
+            // A submitter that reserved before we closed is still pushing; wait for it
+            if self.reserved.load(Ordering::SeqCst) > 0 {
+                drop(state);
+                thread::yield_now();
+                return Ok(false);
+            }
+            self.retire_worker();
+            return Err(Exit::Closed);
+        }
+
+        self.sleeping.fetch_add(1, Ordering::SeqCst);
+        // A submitter that missed our `sleeping` increment left its task visible here
+        if self.queued.load(Ordering::SeqCst) > 0 {
+            self.sleeping.fetch_sub(1, Ordering::SeqCst);
+            return Ok(false);
+        }
+        let above_min = self.workers.load(Ordering::SeqCst) > self.config.min_workers;
+        if above_min {
+            let (guard, wait) = self
+                .available
+                .wait_timeout(state, self.config.keep_alive)
+                .unwrap_or_else(PoisonError::into_inner);
+            state = guard;
+            let claimed = self.woke(&mut state);
+            if wait.timed_out() && !self.closed.load(Ordering::SeqCst) && self.queued.load(Ordering::SeqCst) == 0 {
+                let min = self.config.min_workers;
+                let retired = self
+                    .workers
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (n > min).then(|| n - 1))
+                    .is_ok();
+                if retired {
+                    if claimed {
+                        self.searching.fetch_sub(1, Ordering::SeqCst);
+                    }
+                    metrics::metrics().pool_workers.set(self.workers.load(Ordering::SeqCst) as i64);
+                    return Err(Exit::Retired);
+                }
+            }
+            Ok(claimed)
+        } else {
+            state = self.available.wait(state).unwrap_or_else(PoisonError::into_inner);
+            Ok(self.woke(&mut state))
+        }
+    }
+
+    // Undo a worker's `sleeping` count, unless a submitter already claimed it
+    fn woke(&self, state: &mut State) -> bool {
+        if state.wakeups > 0 {
+            state.wakeups -= 1;
+            true
+        } else {
+            self.sleeping.fetch_sub(1, Ordering::SeqCst);
+            false
+        }
+    }
+
+    fn finish_task(&self) {
+        self.completed.fetch_add(1, Ordering::Relaxed);
+        if self.running.fetch_sub(1, Ordering::SeqCst) == 1 && self.reserved.load(Ordering::SeqCst) == 0 {
+            let _state = self.lock();
+            self.idle.notify_all();
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.reserved.load(Ordering::SeqCst) == 0 && self.running.load(Ordering::SeqCst) == 0
+    }
+
+    fn retire_worker(&self) {
+        let workers = self.workers.fetch_sub(1, Ordering::SeqCst) - 1;
+        metrics::metrics().pool_workers.set(workers as i64);
+    }
+
+    // Start another worker when more tasks are queued than workers are parked
+    fn maybe_grow(self: &Arc<Self>) {
+        if self.queued.load(Ordering::SeqCst) <= self.sleeping.load(Ordering::SeqCst) {
+            return;
+        }
+        let max = self.config.max_workers;
+        if self
+            .workers
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (n < max).then_some(n + 1))
+            .is_err()
+        {
+            return;
+        }
+        let id = {
+            let mut state = self.lock();
+            state.next_id += 1;
+            state.next_id - 1
+        };
+        metrics::metrics().pool_workers.set(self.workers.load(Ordering::SeqCst) as i64);
+        if let Err(e) = spawn_worker(id, Arc::clone(self)) {
+            log_error!("failed to grow thread pool: {}", e);
+            self.retire_worker();
+        }
+    }
 }
 
-pub struct Worker {
-    _id: usize,
-    _thread: Option<thread::JoinHandle<()>>,
+fn steal_retrying<T>(mut attempt: impl FnMut() -> Steal<T>) -> Option<T> {
+    std::iter::repeat_with(&mut attempt)
+        .find(|steal| !steal.is_retry())
+        .and_then(Steal::success)
+}
+
+pub struct ThreadPool {
+    shared: Arc<Shared>,
 }
 
 impl ThreadPool {
     pub fn new(size: usize) -> ThreadPool {
-        let (sender, reciever) = mpsc::channel();
-        let reciever = Arc::new(Mutex::new(reciever));
-        let mut workers = Vec::with_capacity(size);
+        Self::with_config(PoolConfig::new().with_workers(size))
+    }
+
+    pub fn with_config(config: PoolConfig) -> ThreadPool {
+        let size = config.min_workers;
+        let shared = Arc::new(Shared {
+            injector: Injector::new(),
+            stealers: RwLock::new(Vec::with_capacity(config.max_workers)),
+            reserved: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            running: AtomicUsize::new(0),
+            workers: AtomicUsize::new(size),
+            sleeping: AtomicUsize::new(0),
+            searching: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            state: Mutex::new(State {
+                next_id: size,
+                exited: HashSet::new(),
+                wakeups: 0,
+            }),
+            available: Condvar::new(),
+            space: Condvar::new(),
+            idle: Condvar::new(),
+            exited: Condvar::new(),
+            config,
+            threads: Mutex::new(HashMap::new()),
+            completed: AtomicU64::new(0),
+        });
+        metrics::metrics().pool_workers.set(size as i64);
 
         for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&reciever)));
-        }
-        ThreadPool {
-            workers,
-            sender: Some(sender),
+            spawn_worker(id, Arc::clone(&shared)).expect("failed to spawn worker thread");
         }
+        ThreadPool { shared }
     }
     //excute the function
 
-    pub fn execute<F>(&self, f: F)
+    pub fn execute<F>(&self, f: F) -> Result<(), PoolError>
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
-        self.sender.as_ref().unwrap().send(job).unwrap();
+        self.execute_task(Box::new(f))
     }
-}
 
-impl Worker {
-    pub fn new(id: usize, recevier: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let message = recevier.lock().unwrap().recv();
-            match message {
-                Ok(job) => {
-                    job();
+    // Like `execute`, but the task gets to respond when it is shed
+    pub fn execute_task(&self, task: Box<dyn Task>) -> Result<(), PoolError> {
+        let metrics = metrics::metrics();
+        let shared = &self.shared;
+        let mut shed = None;
+        let mut attempts = 0;
+
+        while shared.reserve().is_none() {
+            match shared.config.policy {
+                QueuePolicy::Block => {
+                    let mut state = shared.lock();
+                    while shared.reserved.load(Ordering::SeqCst) >= shared.config.queue_capacity
+                        && !shared.closed.load(Ordering::SeqCst)
+                    {
+                        state = shared.space.wait(state).unwrap_or_else(PoisonError::into_inner);
+                    }
+                    if shared.closed.load(Ordering::SeqCst) {
+                        task.reject();
+                        return Err(PoolError::ShutDown);
+                    }
                 }
-                Err(_) => {
-                    break;
+                QueuePolicy::Reject => {
+                    metrics.pool_rejected.inc(&["rejected"]);
+                    task.reject();
+                    return Err(PoolError::QueueFull);
                 }
+                QueuePolicy::DropOldest => {
+                    // The new task inherits the shed task's slot
+                    if let Some(oldest) = shared.steal_oldest() {
+                        metrics.pool_rejected.inc(&["dropped"]);
+                        shed = Some(oldest);
+                        break;
+                    }
+                    // Every slot is held by a submitter mid-push or a task mid-pop;
+                    // give them a moment, then treat the queue as full
+                    attempts += 1;
+                    if attempts > DROP_OLDEST_RETRIES {
+                        metrics.pool_rejected.inc(&["rejected"]);
+                        task.reject();
+                        return Err(PoolError::QueueFull);
+                    }
+                    thread::yield_now();
+                }
+            }
+        }
+
+        // Checked after reserving: either `shutdown` sees our slot and waits for
+        // the push, or we see it closed and give the slot back
+        if shared.closed.load(Ordering::SeqCst) {
+            shared.reserved.fetch_sub(1, Ordering::SeqCst);
+            task.reject();
+            if let Some(queued) = shed {
+                queued.task.reject();
+            }
+            return Err(PoolError::ShutDown);
+        }
+
+        shared.push(task);
+        shared.maybe_grow();
+
+        // Rejecting may mean writing to a socket, so only after the new task is queued
+        if let Some(queued) = shed {
+            queued.task.reject();
+        }
+        Ok(())
+    }
+
+    // Tasks waiting for a worker
+    pub fn queued(&self) -> usize {
+        self.shared.queued.load(Ordering::SeqCst)
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let workers = self.shared.workers.load(Ordering::SeqCst);
+        let active = self.shared.running.load(Ordering::SeqCst).min(workers);
+        PoolStats {
+            workers,
+            active,
+            idle: workers - active,
+            queued: self.queued(),
+            completed: self.shared.completed.load(Ordering::Relaxed),
+        }
+    }
+
+    // Run `f` on the pool; the handle yields its return value or panic payload
+    pub fn spawn<F, T>(&self, f: F) -> Result<JoinHandle<T>, PoolError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let packet = Arc::new(Packet::new());
+        let spawned = Spawned::new(f, &packet, None);
+        self.execute(move || spawned.run())?;
+        Ok(JoinHandle { packet })
+    }
+
+    // Spawn tasks that borrow from the caller's stack; returns once all of them
+    // have finished, and panics if any of them did. As with `std::thread::scope`,
+    // the task's own payload goes to its JoinHandle rather than being re-raised.
+    // Calling this from a pool task can deadlock if every worker ends up waiting
+    // on a scope.
+    pub fn scope<'env, F, R>(&self, f: F) -> R
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> R,
+    {
+        let scope = Scope {
+            pool: self,
+            state: Arc::new(ScopeState::default()),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        // Borrowed data must outlive every task, even when `f` itself panicked
+        scope.state.wait();
+        match result {
+            Err(payload) => panic::resume_unwind(payload),
+            Ok(_) if scope.state.panicked.load(Ordering::SeqCst) => panic!("a scoped ThreadPool task panicked"),
+            Ok(value) => value,
+        }
+    }
+
+    // Block until nothing is queued or running; don't call from a pool task
+    pub fn wait_idle(&self) {
+        let mut state = self.shared.lock();
+        while !self.shared.is_idle() {
+            state = self.shared.idle.wait(state).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    // Stop accepting tasks, let the workers drain the queue, and join them.
+    // Workers still running a task at the deadline are reported and left detached.
+    pub fn shutdown(&self, timeout: Duration) -> Result<(), ShutdownTimeout> {
+        self.join_workers(Instant::now().checked_add(timeout))
+    }
+
+    // Join workers until `deadline`, or for as long as it takes without one
+    fn join_workers(&self, deadline: Option<Instant>) -> Result<(), ShutdownTimeout> {
+        let shared = &self.shared;
+        let mut state = shared.lock();
+        shared.closed.store(true, Ordering::SeqCst);
+        shared.available.notify_all();
+        shared.space.notify_all();
+
+        // A pool dropped from one of its own tasks can't wait for that worker.
+        // A thread that has already returned is done whatever `exited` says,
+        // so a missed notification can't keep Drop waiting forever
+        let current = thread::current().id();
+        let done = |state: &State, id: &usize, handle: &thread::JoinHandle<()>| {
+            state.exited.contains(id) || handle.is_finished() || handle.thread().id() == current
+        };
+        let pending = |state: &State| {
+            shared
+                .threads
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .iter()
+                .any(|(id, handle)| !done(state, id, handle))
+        };
+        while pending(&state) {
+            let left = deadline.map_or(JOIN_POLL, |d| d.saturating_duration_since(Instant::now()));
+            if left.is_zero() {
+                break;
+            }
+            state = shared
+                .exited
+                .wait_timeout(state, left.min(JOIN_POLL))
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+
+        let mut unfinished = Vec::new();
+        let threads: Vec<_> = shared.threads.lock().unwrap_or_else(PoisonError::into_inner).drain().collect();
+        for (id, handle) in threads {
+            let exited = state.exited.remove(&id);
+            if exited || handle.is_finished() {
+                // It has left its loop, so this only waits for the thread to return
+                let _ = handle.join();
+            } else if handle.thread().id() != current {
+                unfinished.push(handle.thread().name().unwrap_or("hs-worker").to_string());
+            }
+        }
+        if unfinished.is_empty() {
+            Ok(())
+        } else {
+            unfinished.sort();
+            Err(ShutdownTimeout { unfinished })
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum JoinError {
+    // The task panicked; this is the payload passed to `panic!`
+    Panicked(Box<dyn Any + Send>),
+    // The task was shed by the queue policy before it ran
+    Cancelled,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Panicked(payload) => write!(f, "task panicked: {}", panic_message(payload.as_ref())),
+            JoinError::Cancelled => write!(f, "task was cancelled before it ran"),
+        }
+    }
+}
+
+impl std::error::Error for JoinError {}
+
+// Where a spawned task leaves its result for the JoinHandle
+struct Packet<T> {
+    result: Mutex<Option<Result<T, JoinError>>>,
+    done: Condvar,
+}
+
+impl<T> Packet<T> {
+    fn new() -> Self {
+        Packet {
+            result: Mutex::new(None),
+            done: Condvar::new(),
+        }
+    }
+
+    fn set(&self, result: Result<T, JoinError>) {
+        let mut slot = self.result.lock().unwrap_or_else(PoisonError::into_inner);
+        if slot.is_none() {
+            *slot = Some(result);
+            self.done.notify_all();
+        }
+    }
+}
+
+pub struct JoinHandle<T> {
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn join(self) -> Result<T, JoinError> {
+        let mut slot = self.packet.result.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            if let Some(result) = slot.take() {
+                return result;
             }
+            slot = self.packet.done.wait(slot).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.packet.result.lock().unwrap_or_else(PoisonError::into_inner).is_some()
+    }
+}
+
+// A spawned closure and where its result goes. Dropped unrun (shed by the queue
+// policy) it reports `Cancelled`. Either way the closure and our hold on the
+// result are gone before the scope, if any, is released.
+struct Spawned<F, T> {
+    f: Option<F>,
+    packet: Option<Arc<Packet<T>>>,
+    scope: Option<Arc<ScopeState>>,
+}
+
+impl<F: FnOnce() -> T, T> Spawned<F, T> {
+    fn new(f: F, packet: &Arc<Packet<T>>, scope: Option<Arc<ScopeState>>) -> Self {
+        Spawned {
+            f: Some(f),
+            packet: Some(Arc::clone(packet)),
+            scope,
+        }
+    }
+
+    fn run(mut self) {
+        let Some(f) = self.f.take() else { return };
+        let result = panic::catch_unwind(AssertUnwindSafe(f)).map_err(|payload| {
+            if let Some(scope) = &self.scope {
+                scope.panicked.store(true, Ordering::SeqCst);
+            }
+            JoinError::Panicked(payload)
         });
-        Worker {
-            _id: id,
-            _thread: Some(thread),
+        if let Some(packet) = &self.packet {
+            packet.set(result);
+        }
+    }
+}
+
+impl<F, T> Drop for Spawned<F, T> {
+    fn drop(&mut self) {
+        drop(self.f.take());
+        if let Some(packet) = self.packet.take() {
+            packet.set(Err(JoinError::Cancelled));
+        }
+        if let Some(scope) = self.scope.take() {
+            scope.finish();
+        }
+    }
+}
+
+#[derive(Default)]
+struct ScopeState {
+    pending: Mutex<usize>,
+    done: Condvar,
+    panicked: AtomicBool,
+}
+
+impl ScopeState {
+    fn finish(&self) {
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        *pending -= 1;
+        if *pending == 0 {
+            self.done.notify_all();
+        }
+    }
+
+    fn wait(&self) {
+        let mut pending = self.pending.lock().unwrap_or_else(PoisonError::into_inner);
+        while *pending > 0 {
+            pending = self.done.wait(pending).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'scope ThreadPool,
+    state: Arc<ScopeState>,
+    // Invariant lifetimes, as in `std::thread::Scope`
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+impl<'scope> Scope<'scope, '_> {
+    pub fn spawn<F, T>(&'scope self, f: F) -> Result<JoinHandle<T>, PoolError>
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        let packet = Arc::new(Packet::new());
+        *self.state.pending.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+        let spawned = Spawned::new(f, &packet, Some(Arc::clone(&self.state)));
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || spawned.run());
+        // SAFETY: `ThreadPool::scope` doesn't return until `pending` drops back to
+        // zero, and `Spawned` only decrements it after the closure and its share
+        // of the result are gone, so nothing borrowed for 'scope is touched by
+        // the pool after the scope ends.
+        let job: Box<dyn FnOnce() + Send + 'static> = unsafe { std::mem::transmute(job) };
+        self.pool.execute(job)?;
+        Ok(JoinHandle { packet })
+    }
+}
+
+fn spawn_worker(id: usize, shared: Arc<Shared>) -> std::io::Result<()> {
+    let worker_shared = Arc::clone(&shared);
+    // Held across the spawn, so a worker that retires at once still finds its
+    // handle to remove instead of leaving it behind for `join_workers`
+    let mut threads = shared.threads.lock().unwrap_or_else(PoisonError::into_inner);
+    let handle = thread::Builder::new()
+        .name(format!("hs-worker-{}", id))
+        .spawn(move || run_worker(id, worker_shared))?;
+    threads.insert(id, handle);
+    Ok(())
+}
+
+fn run_worker(id: usize, shared: Arc<Shared>) {
+    let sentinel = Sentinel::new(id, Arc::clone(&shared));
+    let mut searching = false;
+    let exit = loop {
+        let queued = match shared.find_task(&sentinel.local) {
+            Some(queued) => {
+                if searching {
+                    searching = false;
+                    shared.stop_searching();
+                }
+                queued
+            }
+            None => {
+                if std::mem::take(&mut searching) {
+                    shared.searching.fetch_sub(1, Ordering::SeqCst);
+                }
+                match shared.park() {
+                    Ok(claimed) => {
+                        if !claimed {
+                            shared.searching.fetch_add(1, Ordering::SeqCst);
+                        }
+                        searching = true;
+                        continue;
+                    }
+                    Err(exit) => break exit,
+                }
+            }
+        };
+        let metrics = metrics::metrics();
+        metrics.pool_queue_wait.observe(queued.enqueued.elapsed().as_secs_f64());
+        metrics.pool_busy_workers.inc();
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| queued.task.run())) {
+            metrics.panics.inc(&["worker", "-"]);
+            log_error!("worker {} job panicked: {}", id, panic_message(payload.as_ref()));
+        }
+        metrics.pool_busy_workers.dec();
+        shared.finish_task();
+    };
+    drop(sentinel);
+    match exit {
+        // Nobody will join a retired worker, so detach it
+        Exit::Retired => {
+            shared.threads.lock().unwrap_or_else(PoisonError::into_inner).remove(&id);
+        }
+        Exit::Closed => {
+            let mut state = shared.lock();
+            state.exited.insert(id);
+            shared.exited.notify_all();
+        }
+    }
+}
+
+// Owns a worker's deque; hands its tasks back and replaces the worker if the
+// thread unwinds outside of a job
+struct Sentinel {
+    id: usize,
+    shared: Arc<Shared>,
+    local: Deque<Queued>,
+}
+
+impl Sentinel {
+    fn new(id: usize, shared: Arc<Shared>) -> Self {
+        let local = Deque::new_fifo();
+        shared
+            .stealers
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((id, local.stealer()));
+        Sentinel { id, shared, local }
+    }
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        self.shared
+            .stealers
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|(id, _)| *id != self.id);
+        while let Some(queued) = self.local.pop() {
+            self.shared.injector.push(queued);
+        }
+
+        if thread::panicking() {
+            log_error!("worker {} died, respawning", self.id);
+            if let Err(e) = spawn_worker(self.id, Arc::clone(&self.shared)) {
+                log_error!("failed to respawn worker {}: {}", self.id, e);
+                self.shared.retire_worker();
+            }
         }
     }
 }
 
-impl Drop for ThreadPool{
+// The message passed to `panic!`, when it was a string
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("non-string panic payload")
+}
+
+impl Drop for ThreadPool {
     fn drop(&mut self) {
-        drop(self.sender.take());
+        // Workers finish what is queued, then exit; a no-op after `shutdown`
+        let _ = self.join_workers(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    // Run `f` on its own thread and fail instead of hanging the test run
+    fn within<F: FnOnce() + Send + 'static>(limit: Duration, f: F) {
+        let (done, finished) = mpsc::channel();
+        thread::spawn(move || {
+            f();
+            let _ = done.send(());
+        });
+        finished.recv_timeout(limit).expect("timed out, probably deadlocked");
+    }
+
+    fn elastic() -> PoolConfig {
+        PoolConfig::new()
+            .with_min_workers(1)
+            .with_max_workers(8)
+            .with_keep_alive(Duration::ZERO)
+    }
+
+    #[test]
+    fn grows_under_load_and_retires_when_idle() {
+        let pool = ThreadPool::with_config(elastic().with_keep_alive(Duration::from_millis(50)));
+        let (started, running) = mpsc::channel();
+        let release = Arc::new((Mutex::new(false), Condvar::new()));
+        for _ in 0..4 {
+            let (started, release) = (started.clone(), Arc::clone(&release));
+            pool.execute(move || {
+                started.send(()).unwrap();
+                let (open, cvar) = &*release;
+                let mut open = open.lock().unwrap();
+                while !*open {
+                    open = cvar.wait(open).unwrap();
+                }
+            })
+            .unwrap();
+        }
+        // Four tasks block at once, so the pool must have grown to at least four
+        for _ in 0..4 {
+            running.recv_timeout(Duration::from_secs(5)).unwrap();
+        }
+        assert!(pool.stats().workers >= 4);
+
+        *release.0.lock().unwrap() = true;
+        release.1.notify_all();
+        pool.wait_idle();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.stats().workers > 1 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(pool.stats().workers, 1);
+    }
+
+    #[test]
+    fn drop_joins_workers_that_retire_immediately() {
+        within(Duration::from_secs(60), || {
+            for _ in 0..50 {
+                let pool = ThreadPool::with_config(elastic());
+                for _ in 0..50 {
+                    pool.execute(|| {}).unwrap();
+                }
+                drop(pool);
+            }
+        });
+    }
+
+    #[test]
+    fn shutdown_after_workers_retire_reports_nothing() {
+        within(Duration::from_secs(60), || {
+            for _ in 0..50 {
+                let pool = ThreadPool::with_config(elastic());
+                for _ in 0..50 {
+                    pool.execute(|| {}).unwrap();
+                }
+                assert_eq!(pool.shutdown(Duration::from_secs(2)), Ok(()));
+            }
+        });
+    }
+
+    #[test]
+    fn shutdown_drains_the_queue_and_joins_workers() {
+        let pool = ThreadPool::new(2);
+        let counter = Arc::new(AtomicUsize::new(0));
+        for _ in 0..100 {
+            let counter = Arc::clone(&counter);
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(1));
+                counter.fetch_add(1, Ordering::SeqCst);
+            })
+            .unwrap();
+        }
+        assert_eq!(pool.shutdown(Duration::from_secs(10)), Ok(()));
+        assert_eq!(counter.load(Ordering::SeqCst), 100);
+        assert!(pool.shared.threads.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn shutdown_reports_workers_still_running() {
+        let pool = ThreadPool::new(1);
+        let (release, blocked) = mpsc::channel::<()>();
+        pool.execute(move || {
+            let _ = blocked.recv();
+        })
+        .unwrap();
+        let result = pool.shutdown(Duration::from_millis(50));
+        assert_eq!(result, Err(ShutdownTimeout { unfinished: vec!["hs-worker-0".to_string()] }));
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn execute_after_shutdown_is_refused() {
+        let pool = ThreadPool::new(1);
+        assert_eq!(pool.shutdown(Duration::from_secs(1)), Ok(()));
+        assert_eq!(pool.execute(|| {}), Err(PoolError::ShutDown));
+    }
+
+    #[test]
+    fn drop_does_not_wait_on_a_lost_exit_notification() {
+        within(Duration::from_secs(10), || {
+            let pool = ThreadPool::new(1);
+            pool.execute(|| {}).unwrap();
+            pool.wait_idle();
+            // A finished thread that never recorded its exit must not hang Drop
+            let phantom = thread::Builder::new().name("hs-worker-99".to_string()).spawn(|| {}).unwrap();
+            pool.shared.threads.lock().unwrap().insert(99, phantom);
+            drop(pool);
+        });
+    }
+
+    #[test]
+    fn scope_borrows_from_the_stack() {
+        let pool = ThreadPool::new(4);
+        let mut data: Vec<u64> = (0..1000).collect();
+        let total = AtomicU64::new(0);
+        pool.scope(|scope| {
+            for chunk in data.chunks_mut(100) {
+                let total = &total;
+                scope
+                    .spawn(move || {
+                        for value in chunk.iter_mut() {
+                            *value *= 2;
+                        }
+                        total.fetch_add(chunk.iter().sum(), Ordering::SeqCst);
+                    })
+                    .unwrap();
+            }
+        });
+        assert_eq!(total.load(Ordering::SeqCst), 999 * 1000);
+        assert!(data.iter().enumerate().all(|(i, &value)| value == 2 * i as u64));
+    }
+
+    #[test]
+    fn scope_panics_only_after_every_task_finishes() {
+        let pool = ThreadPool::new(2);
+        let sibling_done = AtomicBool::new(false);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|scope| {
+                scope.spawn(|| panic!("boom")).unwrap();
+                scope
+                    .spawn(|| {
+                        thread::sleep(Duration::from_millis(100));
+                        sibling_done.store(true, Ordering::SeqCst);
+                    })
+                    .unwrap();
+            })
+        }));
+        let payload = result.unwrap_err();
+        assert_eq!(panic_message(payload.as_ref()), "a scoped ThreadPool task panicked");
+        assert!(sibling_done.load(Ordering::SeqCst));
+        // The pool survives its tasks' panics
+        assert_eq!(pool.spawn(|| 7).unwrap().join().unwrap(), 7);
+    }
+
+    #[test]
+    fn scope_spawn_after_shutdown_fails_without_hanging() {
+        within(Duration::from_secs(10), || {
+            let pool = ThreadPool::new(1);
+            assert_eq!(pool.shutdown(Duration::from_secs(1)), Ok(()));
+            let ran = AtomicBool::new(false);
+            pool.scope(|scope| {
+                let spawned = scope.spawn(|| ran.store(true, Ordering::SeqCst));
+                assert_eq!(spawned.err(), Some(PoolError::ShutDown));
+            });
+            assert!(!ran.load(Ordering::SeqCst));
+        });
+    }
+
+    #[test]
+    fn join_returns_the_value_or_the_panic_payload() {
+        let pool = ThreadPool::new(2);
+        let value = pool.spawn(|| "done".to_string()).unwrap();
+        let panicked = pool.spawn(|| -> u32 { panic!("task failed: {}", 42) }).unwrap();
+        assert_eq!(value.join().unwrap(), "done");
+        match panicked.join() {
+            Err(JoinError::Panicked(payload)) => assert_eq!(panic_message(payload.as_ref()), "task failed: 42"),
+            other => panic!("expected a panic, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn wait_idle_returns_once_tasks_drain() {
+        within(Duration::from_secs(10), || {
+            let pool = ThreadPool::new(2);
+            let counter = Arc::new(AtomicUsize::new(0));
+            for _ in 0..20 {
+                let counter = Arc::clone(&counter);
+                pool.execute(move || {
+                    thread::sleep(Duration::from_millis(5));
+                    counter.fetch_add(1, Ordering::SeqCst);
+                })
+                .unwrap();
+            }
+            pool.wait_idle();
+            assert_eq!(counter.load(Ordering::SeqCst), 20);
+            assert_eq!(pool.stats().queued, 0);
+            assert_eq!(pool.stats().active, 0);
+            // And straight away when there is nothing to wait for
+            pool.wait_idle();
+        });
+    }
+
+    // Counts whether it was run or rejected
+    struct Probe {
+        id: usize,
+        ran: Arc<Mutex<Vec<usize>>>,
+        rejected: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl Task for Probe {
+        fn run(self: Box<Self>) {
+            self.ran.lock().unwrap().push(self.id);
+        }
+
+        fn reject(self: Box<Self>) {
+            self.rejected.lock().unwrap().push(self.id);
+        }
+    }
+
+    // One worker, held by a task until the returned sender is dropped, and room for
+    // `capacity` more tasks in the queue
+    fn busy_pool(policy: QueuePolicy, capacity: usize) -> (ThreadPool, mpsc::Sender<()>) {
+        let pool = ThreadPool::with_config(
+            PoolConfig::new()
+                .with_min_workers(1)
+                .with_max_workers(1)
+                .with_queue_capacity(capacity)
+                .with_policy(policy),
+        );
+        let (started, running) = mpsc::channel();
+        let (release, blocked) = mpsc::channel::<()>();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = blocked.recv();
+        })
+        .unwrap();
+        running.recv_timeout(Duration::from_secs(5)).unwrap();
+        (pool, release)
+    }
+
+    #[test]
+    fn reject_policy_refuses_and_rejects_the_new_task() {
+        let (pool, release) = busy_pool(QueuePolicy::Reject, 2);
+        let (ran, rejected) = (Arc::new(Mutex::new(Vec::new())), Arc::new(Mutex::new(Vec::new())));
+        let probe = |id| Box::new(Probe { id, ran: Arc::clone(&ran), rejected: Arc::clone(&rejected) });
+        assert_eq!(pool.execute_task(probe(1)), Ok(()));
+        assert_eq!(pool.execute_task(probe(2)), Ok(()));
+        assert_eq!(pool.execute_task(probe(3)), Err(PoolError::QueueFull));
+        assert_eq!(*rejected.lock().unwrap(), vec![3]);
+
+        drop(release);
+        pool.wait_idle();
+        assert_eq!(*ran.lock().unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn drop_oldest_policy_sheds_the_longest_waiting_task() {
+        let (pool, release) = busy_pool(QueuePolicy::DropOldest, 2);
+        let (ran, rejected) = (Arc::new(Mutex::new(Vec::new())), Arc::new(Mutex::new(Vec::new())));
+        let probe = |id| Box::new(Probe { id, ran: Arc::clone(&ran), rejected: Arc::clone(&rejected) });
+        for id in 1..=4 {
+            assert_eq!(pool.execute_task(probe(id)), Ok(()));
+        }
+        assert_eq!(*rejected.lock().unwrap(), vec![1, 2]);
+
+        drop(release);
+        pool.wait_idle();
+        assert_eq!(*ran.lock().unwrap(), vec![3, 4]);
+    }
+
+    #[test]
+    fn drop_oldest_gives_up_when_nothing_can_be_shed() {
+        let pool = ThreadPool::with_config(
+            PoolConfig::new()
+                .with_min_workers(1)
+                .with_max_workers(1)
+                .with_queue_capacity(1)
+                .with_policy(QueuePolicy::DropOldest),
+        );
+        // A slot held without a queued task, as by a submitter between reserving and pushing
+        pool.shared.reserve().unwrap();
+        within(Duration::from_secs(10), move || {
+            let (ran, rejected) = (Arc::new(Mutex::new(Vec::new())), Arc::new(Mutex::new(Vec::new())));
+            let probe = Box::new(Probe { id: 1, ran, rejected: Arc::clone(&rejected) });
+            assert_eq!(pool.execute_task(probe), Err(PoolError::QueueFull));
+            assert_eq!(*rejected.lock().unwrap(), vec![1]);
+            pool.shared.reserved.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+
+    #[test]
+    fn block_policy_waits_for_space() {
+        let (pool, release) = busy_pool(QueuePolicy::Block, 1);
+        let pool = Arc::new(pool);
+        let (ran, rejected) = (Arc::new(Mutex::new(Vec::new())), Arc::new(Mutex::new(Vec::new())));
+        let probe = |id| Box::new(Probe { id, ran: Arc::clone(&ran), rejected: Arc::clone(&rejected) });
+        assert_eq!(pool.execute_task(probe(1)), Ok(()));
+
+        let (submitted, done) = mpsc::channel();
+        let blocked = {
+            let (pool, task) = (Arc::clone(&pool), probe(2));
+            thread::spawn(move || submitted.send(pool.execute_task(task)).unwrap())
+        };
+        // Still waiting while the queue is full
+        assert!(done.recv_timeout(Duration::from_millis(100)).is_err());
+
+        drop(release);
+        assert_eq!(done.recv_timeout(Duration::from_secs(5)).unwrap(), Ok(()));
+        blocked.join().unwrap();
+        pool.wait_idle();
+        assert_eq!(*ran.lock().unwrap(), vec![1, 2]);
+        assert!(rejected.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn block_policy_rejects_when_shut_down_while_waiting() {
+        let (pool, release) = busy_pool(QueuePolicy::Block, 1);
+        let pool = Arc::new(pool);
+        let (ran, rejected) = (Arc::new(Mutex::new(Vec::new())), Arc::new(Mutex::new(Vec::new())));
+        let probe = |id| Box::new(Probe { id, ran: Arc::clone(&ran), rejected: Arc::clone(&rejected) });
+        assert_eq!(pool.execute_task(probe(1)), Ok(()));
+
+        let blocked = {
+            let (pool, task) = (Arc::clone(&pool), probe(2));
+            thread::spawn(move || pool.execute_task(task))
+        };
+        thread::sleep(Duration::from_millis(50));
+        let shutdown = {
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || pool.shutdown(Duration::from_secs(5)))
+        };
+        assert_eq!(blocked.join().unwrap(), Err(PoolError::ShutDown));
+        assert_eq!(*rejected.lock().unwrap(), vec![2]);
+        drop(release);
+        assert_eq!(shutdown.join().unwrap(), Ok(()));
+        assert_eq!(*ran.lock().unwrap(), vec![1]);
     }
 }
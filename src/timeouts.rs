@@ -0,0 +1,72 @@
+//Connection timeouts
+//How long a client may take to send a request head or body, how long a response
+//write may block, and how long an idle keep-alive connection is kept open. A zero
+//timeout means no limit, except for keep-alive, where zero turns keep-alive off.
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    header_read: Duration,
+    body_read: Duration,
+    write: Duration,
+    keep_alive_idle: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timeouts {
+    pub fn new() -> Self {
+        Timeouts {
+            header_read: Duration::from_secs(10),
+            body_read: Duration::from_secs(30),
+            write: Duration::from_secs(30),
+            keep_alive_idle: Duration::from_secs(5),
+        }
+    }
+
+    // The whole request head, from its first byte (or the accept) to the blank line;
+    // zero waits as long as it takes
+    pub fn with_header_read(mut self, timeout: Duration) -> Self {
+        self.header_read = timeout;
+        self
+    }
+
+    // The whole request body; zero waits as long as it takes
+    pub fn with_body_read(mut self, timeout: Duration) -> Self {
+        self.body_read = timeout;
+        self
+    }
+
+    // Writing the response; zero waits as long as it takes
+    pub fn with_write(mut self, timeout: Duration) -> Self {
+        self.write = timeout;
+        self
+    }
+
+    // Waiting for the next request on a kept-alive connection; zero disables
+    // keep-alive, closing the connection after each response
+    pub fn with_keep_alive_idle(mut self, timeout: Duration) -> Self {
+        self.keep_alive_idle = timeout;
+        self
+    }
+
+    pub fn header_read(&self) -> Duration {
+        self.header_read
+    }
+
+    pub fn body_read(&self) -> Duration {
+        self.body_read
+    }
+
+    pub fn write(&self) -> Duration {
+        self.write
+    }
+
+    pub fn keep_alive_idle(&self) -> Duration {
+        self.keep_alive_idle
+    }
+}
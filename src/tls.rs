@@ -0,0 +1,511 @@
+//TLS termination with rustls
+//Certificates come from PEM files: a default pair, plus optional pairs picked by the
+//SNI hostname the client asks for. The files are polled for changes and reloaded
+//in place; a reload that fails is logged and the previous certificates stay in use.
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use rustls::crypto::ring::{default_provider, sign::any_supported_type};
+use rustls::pki_types::CertificateDer;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{InconsistentKeys, ServerConfig, ServerConnection, StreamOwned};
+
+use crate::error::HsError;
+use crate::route::Router;
+use crate::types::Response;
+use crate::{log_error, log_info};
+
+// A TLS connection on the threaded engine; the handshake runs on first read or write
+pub type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+// A TLS connection on the tokio engine
+pub type AsyncTlsStream = tokio_rustls::server::TlsStream<tokio::net::TcpStream>;
+
+#[derive(Clone, Debug)]
+struct CertPaths {
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    default: CertPaths,
+    // Hostname (or `*.example.com`) and its certificate
+    sni: Vec<(String, CertPaths)>,
+    alpn: Vec<Vec<u8>>,
+    reload_interval: Option<Duration>,
+}
+
+impl TlsConfig {
+    // Certificate chain and private key served when no SNI certificate matches
+    pub fn new<P: AsRef<Path>, K: AsRef<Path>>(cert: P, key: K) -> Self {
+        TlsConfig {
+            default: CertPaths {
+                cert: cert.as_ref().to_path_buf(),
+                key: key.as_ref().to_path_buf(),
+            },
+            sni: Vec::new(),
+            alpn: vec![b"http/1.1".to_vec()],
+            reload_interval: Some(Duration::from_secs(30)),
+        }
+    }
+
+    // Serve this certificate to clients asking for `hostname`
+    pub fn with_sni_cert<P: AsRef<Path>, K: AsRef<Path>>(mut self, hostname: &str, cert: P, key: K) -> Self {
+        let paths = CertPaths {
+            cert: cert.as_ref().to_path_buf(),
+            key: key.as_ref().to_path_buf(),
+        };
+        self.sni.push((hostname.to_ascii_lowercase(), paths));
+        self
+    }
+
+    // Protocols offered in ALPN, most preferred first
+    pub fn with_alpn(mut self, protocols: &[&str]) -> Self {
+        self.alpn = protocols.iter().map(|p| p.as_bytes().to_vec()).collect();
+        self
+    }
+
+    // How often the PEM files are checked for changes; None turns reloading off
+    pub fn with_reload_interval(mut self, interval: Option<Duration>) -> Self {
+        self.reload_interval = interval;
+        self
+    }
+
+    // Load the certificates and start watching them
+    pub fn build(self) -> Result<Tls, TlsError> {
+        let resolver = Arc::new(CertResolver {
+            certs: RwLock::new(load_certs(&self)?),
+        });
+        let mut config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(TlsError::Rustls)?
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+        config.alpn_protocols = self.alpn.clone();
+
+        if let Some(interval) = self.reload_interval {
+            let watched = Arc::downgrade(&resolver);
+            thread::Builder::new()
+                .name("hs-tls-reload".to_string())
+                .spawn(move || watch(self, watched, interval))
+                .map_err(|e| TlsError::Io(PathBuf::new(), e))?;
+        }
+        Ok(Tls {
+            config: Arc::new(config),
+        })
+    }
+
+    fn files(&self) -> impl Iterator<Item = &Path> {
+        std::iter::once(&self.default)
+            .chain(self.sni.iter().map(|(_, paths)| paths))
+            .flat_map(|paths| [paths.cert.as_path(), paths.key.as_path()])
+    }
+}
+
+#[derive(Debug)]
+pub enum TlsError {
+    Io(PathBuf, io::Error),
+    NoCertificates(PathBuf),
+    NoPrivateKey(PathBuf),
+    // The key doesn't belong to the certificate, or isn't a supported type
+    InvalidKey(PathBuf, rustls::Error),
+    Rustls(rustls::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            TlsError::NoCertificates(path) => write!(f, "{}: no certificates found", path.display()),
+            TlsError::NoPrivateKey(path) => write!(f, "{}: no private key found", path.display()),
+            TlsError::InvalidKey(path, e) => write!(f, "{}: {}", path.display(), e),
+            TlsError::Rustls(e) => write!(f, "TLS configuration error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+// The server side of TLS, shared by every listener using the same certificates
+pub struct Tls {
+    config: Arc<ServerConfig>,
+}
+
+impl Tls {
+    pub fn server_config(&self) -> Arc<ServerConfig> {
+        Arc::clone(&self.config)
+    }
+
+    // Wrap an accepted connection for the threaded engine
+    pub fn accept(&self, stream: TcpStream) -> io::Result<TlsStream> {
+        let connection = ServerConnection::new(self.server_config()).map_err(io::Error::other)?;
+        Ok(StreamOwned::new(connection, stream))
+    }
+
+    // Run the handshake on an accepted connection for the tokio engine
+    pub async fn accept_async(&self, stream: tokio::net::TcpStream) -> io::Result<AsyncTlsStream> {
+        tokio_rustls::TlsAcceptor::from(self.server_config()).accept(stream).await
+    }
+}
+
+#[derive(Debug)]
+struct Certs {
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+#[derive(Debug)]
+struct CertResolver {
+    certs: RwLock<Certs>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certs = self.certs.read().unwrap_or_else(PoisonError::into_inner);
+        let by_name = hello.server_name().and_then(|name| {
+            let name = name.to_ascii_lowercase();
+            certs.by_name.get(&name).or_else(|| {
+                let (_, parent) = name.split_once('.')?;
+                certs.by_name.get(&format!("*.{}", parent))
+            })
+        });
+        Some(Arc::clone(by_name.unwrap_or(&certs.default)))
+    }
+}
+
+fn load_certs(config: &TlsConfig) -> Result<Certs, TlsError> {
+    let mut by_name = HashMap::new();
+    for (hostname, paths) in &config.sni {
+        by_name.insert(hostname.clone(), load_key_pair(paths)?);
+    }
+    Ok(Certs {
+        default: load_key_pair(&config.default)?,
+        by_name,
+    })
+}
+
+fn load_key_pair(paths: &CertPaths) -> Result<Arc<CertifiedKey>, TlsError> {
+    let open = |path: &Path| File::open(path).map(BufReader::new).map_err(|e| TlsError::Io(path.to_path_buf(), e));
+
+    let chain = rustls_pemfile::certs(&mut open(&paths.cert)?)
+        .collect::<Result<Vec<CertificateDer<'static>>, _>>()
+        .map_err(|e| TlsError::Io(paths.cert.clone(), e))?;
+    if chain.is_empty() {
+        return Err(TlsError::NoCertificates(paths.cert.clone()));
+    }
+    let key = rustls_pemfile::private_key(&mut open(&paths.key)?)
+        .map_err(|e| TlsError::Io(paths.key.clone(), e))?
+        .ok_or_else(|| TlsError::NoPrivateKey(paths.key.clone()))?;
+    let signing_key = any_supported_type(&key).map_err(|e| TlsError::InvalidKey(paths.key.clone(), e))?;
+
+    let certified = CertifiedKey::new(chain, signing_key);
+    // Unknown means the key type can't tell; only a definite mismatch is fatal
+    if let Err(e @ rustls::Error::InconsistentKeys(InconsistentKeys::KeyMismatch)) = certified.keys_match() {
+        return Err(TlsError::InvalidKey(paths.key.clone(), e));
+    }
+    Ok(Arc::new(certified))
+}
+
+// Reload the certificates whenever one of the files changes, until the server goes away
+fn watch(config: TlsConfig, resolver: Weak<CertResolver>, interval: Duration) {
+    let modified = |config: &TlsConfig| -> Vec<Option<SystemTime>> {
+        config
+            .files()
+            .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+            .collect()
+    };
+    let mut seen = modified(&config);
+    loop {
+        thread::sleep(interval);
+        let Some(resolver) = resolver.upgrade() else { return };
+        let current = modified(&config);
+        if current == seen {
+            continue;
+        }
+        // Remember the new times even on failure, so a broken file is reported once
+        seen = current;
+        match load_certs(&config) {
+            Ok(certs) => {
+                *resolver.certs.write().unwrap_or_else(PoisonError::into_inner) = certs;
+                log_info!("Reloaded TLS certificates");
+            }
+            Err(e) => log_error!("Keeping the current TLS certificates, reload failed: {}", e),
+        }
+    }
+}
+
+// A router that only redirects to the same path over HTTPS, for a plain HTTP
+// listener in front of the TLS one. The Host header is kept when it names one of
+// `hosts` (which may hold wildcards such as `*.example.com`, as SNI names do) and
+// replaced with the first of them otherwise, so a forged Host can't send clients
+// anywhere else
+pub fn redirect_router(hosts: &[&str], https_port: u16) -> Router {
+    let hosts: Vec<String> = hosts.iter().map(|host| host.to_ascii_lowercase()).collect();
+    let mut router = Router::new();
+    router.fallback(move |request| -> Result<Response, HsError> {
+        let host = request.header("Host").unwrap_or_default().to_ascii_lowercase();
+        // Drop any port, keeping IPv6 literals such as [::1] intact
+        let host = match host.rsplit_once(':') {
+            Some((name, port)) if !port.contains(']') => name,
+            _ => &host,
+        };
+        let host = match hosts.iter().find(|&known| host_matches(known, host)) {
+            // A wildcard entry stands for the name the client asked for
+            Some(known) if known.starts_with("*.") => host,
+            Some(known) => known.as_str(),
+            None => hosts.first().map(String::as_str).ok_or_else(|| HsError::BadRequest("Unknown host.".to_string()))?,
+        };
+        let authority = match https_port {
+            443 => host.to_string(),
+            port => format!("{}:{}", host, port),
+        };
+        // 308 keeps the method and body, unlike 301
+        Ok(Response::new()
+            .with_status(308)
+            .with_header("Location", &format!("https://{}{}", authority, request.path)))
+    });
+    router
+}
+
+// Whether `host` is `pattern`, or one label under a `*.` wildcard pattern
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(parent) => host.split_once('.').is_some_and(|(label, rest)| {
+            !label.is_empty() && rest == parent && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        }),
+        None => pattern == host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore};
+
+    use crate::types::Request;
+
+    // A self-signed certificate for `names`, written out as PEM files
+    struct TestCert {
+        dir: PathBuf,
+        der: CertificateDer<'static>,
+    }
+
+    impl TestCert {
+        fn new(names: &[&str]) -> TestCert {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let dir = std::env::temp_dir().join(format!(
+                "hs-tls-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::SeqCst)
+            ));
+            fs::create_dir_all(&dir).unwrap();
+            let names = names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+            let generated = rcgen::generate_simple_self_signed(names).unwrap();
+            fs::write(dir.join("cert.pem"), generated.cert.pem()).unwrap();
+            fs::write(dir.join("key.pem"), generated.key_pair.serialize_pem()).unwrap();
+            TestCert {
+                dir,
+                der: generated.cert.der().clone(),
+            }
+        }
+
+        fn cert(&self) -> PathBuf {
+            self.dir.join("cert.pem")
+        }
+
+        fn key(&self) -> PathBuf {
+            self.dir.join("key.pem")
+        }
+    }
+
+    impl Drop for TestCert {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn client_config(trusted: &TestCert, alpn: &[&str]) -> Arc<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        roots.add(trusted.der.clone()).unwrap();
+        let mut config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+        Arc::new(config)
+    }
+
+    // Handshake with a threaded-engine server, echo one line, and return the
+    // protocol both sides agreed on
+    fn handshake(tls: Tls, client: Arc<ClientConfig>, server_name: &str) -> io::Result<Option<Vec<u8>>> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || -> io::Result<Option<Vec<u8>>> {
+            let (stream, _) = listener.accept()?;
+            let mut stream = tls.accept(stream)?;
+            let mut line = [0; 5];
+            stream.read_exact(&mut line)?;
+            stream.write_all(&line)?;
+            stream.flush()?;
+            Ok(stream.conn.alpn_protocol().map(<[u8]>::to_vec))
+        });
+
+        let name = ServerName::try_from(server_name.to_string()).unwrap();
+        let connection = ClientConnection::new(client, name).map_err(io::Error::other)?;
+        let mut stream = StreamOwned::new(connection, TcpStream::connect(addr)?);
+        let sent = stream.write_all(b"ping\n").and_then(|_| {
+            let mut echoed = [0; 5];
+            stream.read_exact(&mut echoed)?;
+            assert_eq!(&echoed, b"ping\n");
+            Ok(())
+        });
+        let client_alpn = stream.conn.alpn_protocol().map(<[u8]>::to_vec);
+        drop(stream);
+        let server_alpn = server.join().unwrap();
+        sent?;
+        assert_eq!(server_alpn.as_ref().ok(), Some(&client_alpn));
+        server_alpn
+    }
+
+    #[test]
+    fn handshake_with_a_self_signed_certificate() {
+        let cert = TestCert::new(&["localhost"]);
+        let tls = TlsConfig::new(cert.cert(), cert.key()).with_reload_interval(None).build().unwrap();
+        let alpn = handshake(tls, client_config(&cert, &[]), "localhost").unwrap();
+        assert_eq!(alpn, None);
+    }
+
+    #[test]
+    fn untrusted_certificate_fails_the_handshake() {
+        let cert = TestCert::new(&["localhost"]);
+        let other = TestCert::new(&["localhost"]);
+        let tls = TlsConfig::new(cert.cert(), cert.key()).with_reload_interval(None).build().unwrap();
+        assert!(handshake(tls, client_config(&other, &[]), "localhost").is_err());
+    }
+
+    #[test]
+    fn alpn_defaults_to_http1() {
+        let cert = TestCert::new(&["localhost"]);
+        let tls = TlsConfig::new(cert.cert(), cert.key()).with_reload_interval(None).build().unwrap();
+        let alpn = handshake(tls, client_config(&cert, &["h2", "http/1.1"]), "localhost").unwrap();
+        assert_eq!(alpn.as_deref(), Some(&b"http/1.1"[..]));
+    }
+
+    #[test]
+    fn alpn_picks_the_servers_preference() {
+        let cert = TestCert::new(&["localhost"]);
+        let tls = TlsConfig::new(cert.cert(), cert.key())
+            .with_alpn(&["h2", "http/1.1"])
+            .with_reload_interval(None)
+            .build()
+            .unwrap();
+        let alpn = handshake(tls, client_config(&cert, &["http/1.1", "h2"]), "localhost").unwrap();
+        assert_eq!(alpn.as_deref(), Some(&b"h2"[..]));
+    }
+
+    #[test]
+    fn alpn_without_a_common_protocol_fails() {
+        let cert = TestCert::new(&["localhost"]);
+        let tls = TlsConfig::new(cert.cert(), cert.key()).with_reload_interval(None).build().unwrap();
+        assert!(handshake(tls, client_config(&cert, &["h2"]), "localhost").is_err());
+    }
+
+    #[test]
+    fn sni_selects_the_matching_certificate() {
+        let default = TestCert::new(&["localhost"]);
+        let wildcard = TestCert::new(&["*.example.com"]);
+        let config = TlsConfig::new(default.cert(), default.key())
+            .with_sni_cert("*.example.com", wildcard.cert(), wildcard.key())
+            .with_reload_interval(None);
+        // The client only trusts the wildcard certificate, so this proves which one was sent
+        let tls = config.clone().build().unwrap();
+        assert!(handshake(tls, client_config(&wildcard, &[]), "api.example.com").is_ok());
+        let tls = config.build().unwrap();
+        assert!(handshake(tls, client_config(&wildcard, &[]), "localhost").is_err());
+    }
+
+    #[test]
+    fn mismatched_key_is_refused() {
+        let cert = TestCert::new(&["localhost"]);
+        let other = TestCert::new(&["localhost"]);
+        let result = TlsConfig::new(cert.cert(), other.key()).with_reload_interval(None).build();
+        assert!(matches!(result, Err(TlsError::InvalidKey(..))));
+    }
+
+    #[tokio::test]
+    async fn async_handshake_negotiates_alpn() {
+        let cert = TestCert::new(&["localhost"]);
+        let tls = TlsConfig::new(cert.cert(), cert.key())
+            .with_alpn(&["h2", "http/1.1"])
+            .with_reload_interval(None)
+            .build()
+            .unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = tls.accept_async(stream).await.unwrap();
+            stream.get_ref().1.alpn_protocol().map(<[u8]>::to_vec)
+        });
+
+        let connector = tokio_rustls::TlsConnector::from(client_config(&cert, &["h2"]));
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let name = ServerName::try_from("localhost").unwrap();
+        let client = connector.connect(name, stream).await.unwrap();
+        assert_eq!(client.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+        assert_eq!(server.await.unwrap().as_deref(), Some(&b"h2"[..]));
+    }
+
+    fn redirect(router: &Router, host: Option<&str>) -> Response {
+        let mut request = Request {
+            method: "GET".to_string(),
+            path: "/docs?page=2".to_string(),
+            version: "HTTP/1.1".to_string(),
+            ..Request::default()
+        };
+        if let Some(host) = host {
+            request.headers.insert("Host".to_string(), host.to_string());
+        }
+        router.handle(&mut request)
+    }
+
+    fn location(response: &Response) -> Option<&str> {
+        response.headers.get("Location").map(String::as_str)
+    }
+
+    #[test]
+    fn redirect_keeps_known_hosts() {
+        let router = redirect_router(&["example.com", "www.example.com", "*.apps.example.com"], 8443);
+        let response = redirect(&router, Some("WWW.example.com:8080"));
+        assert_eq!(response.status, 308);
+        assert_eq!(location(&response), Some("https://www.example.com:8443/docs?page=2"));
+        assert_eq!(location(&redirect(&router, Some("shop.apps.example.com"))), Some("https://shop.apps.example.com:8443/docs?page=2"));
+
+        let router = redirect_router(&["[::1]"], 443);
+        assert_eq!(location(&redirect(&router, Some("[::1]:80"))), Some("https://[::1]/docs?page=2"));
+    }
+
+    #[test]
+    fn redirect_replaces_unknown_hosts() {
+        let router = redirect_router(&["example.com", "*.apps.example.com"], 443);
+        for host in [Some("evil.test"), Some("example.com.evil.test"), Some("a.b.apps.example.com"), Some("evil.test/x"), None] {
+            assert_eq!(location(&redirect(&router, host)), Some("https://example.com/docs?page=2"), "{:?}", host);
+        }
+        assert_eq!(redirect(&redirect_router(&[], 443), Some("example.com")).status, 400);
+    }
+}
@@ -1,17 +1,83 @@
 use std::collections::HashMap;
+use std::fs::File;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
 
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::static_files::escape_html;
+
+#[derive(Clone, Default)]
 pub struct Request {
+    // Assigned by the router before any handler runs
+    pub id: String,
     pub method: String,
     pub path: String,
+    pub version: String,
     pub headers: HashMap<String, String>,
     pub body: Vec<u8>,
     pub params: HashMap<String, String>,
+    // Filled in by the server once the request has been parsed
+    pub peer_addr: Option<SocketAddr>,
+    // Pattern of the route or mount that matched, e.g. `/user/:id`
+    pub route: Option<String>,
+}
+
+impl Request {
+    // Header names are case-insensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Whether the client wants the connection kept open after this exchange
+    pub fn keep_alive(&self) -> bool {
+        let has_token = |value: &str, token: &str| value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token));
+        match self.header("Connection") {
+            Some(value) if has_token(value, "close") => false,
+            Some(value) if has_token(value, "keep-alive") => true,
+            // Persistent by default from HTTP/1.1 on
+            _ => self.version.eq_ignore_ascii_case("HTTP/1.1"),
+        }
+    }
 }
 
 pub struct Response {
     pub status: u16,
     pub headers: HashMap<String, String>,
     pub body: Vec<u8>,
+    // When set, the body is streamed straight from this file instead of `body`
+    pub file: Option<FileBody>,
+    // When set, the body is this buffer, shared with the static cache, instead of `body`
+    pub shared: Option<Arc<[u8]>>,
+    // Set on a 101 (e.g. a WebSocket handshake); the engine runs this once the head is sent
+    pub upgrade: Option<Box<dyn Upgrade>>,
+}
+
+// The connection handed over after a 101 response
+pub trait UpgradedIo: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> UpgradedIo for T {}
+
+// Takes over a connection once the 101 response to its request has been written
+pub trait Upgrade: Send + Sync {
+    fn run(self: Box<Self>, io: Box<dyn UpgradedIo>) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+}
+
+// An unmodified file on disk that the connection writer can hand to sendfile(2)
+pub struct FileBody {
+    pub file: File,
+    pub len: u64,
+}
+
+impl Default for Response {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Response {
@@ -20,6 +86,9 @@ impl Response {
             status: 200,
             headers: HashMap::new(),
             body: Vec::new(),
+            file: None,
+            shared: None,
+            upgrade: None,
         }
     }
 
@@ -35,14 +104,44 @@ impl Response {
 
     pub fn with_body(mut self, body: &str) -> Self {
         self.body = body.as_bytes().to_vec();
+        self.file = None;
+        self.shared = None;
         self
     }
 
     pub fn with_body_bytes(mut self, body: Vec<u8>) -> Self {
         self.body = body;
+        self.file = None;
+        self.shared = None;
+        self
+    }
+
+    pub fn with_shared_body(mut self, body: Arc<[u8]>) -> Self {
+        self.body = Vec::new();
+        self.file = None;
+        self.shared = Some(body);
+        self
+    }
+
+    pub fn with_file(mut self, file: File, len: u64) -> Self {
+        self.body = Vec::new();
+        self.file = Some(FileBody { file, len });
+        self.shared = None;
         self
     }
 
+    pub fn content_length(&self) -> u64 {
+        match &self.file {
+            Some(file) => file.len,
+            None => self.bytes().len() as u64,
+        }
+    }
+
+    // The in-memory body, from whichever of `shared` and `body` holds it
+    pub fn bytes(&self) -> &[u8] {
+        self.shared.as_deref().unwrap_or(&self.body)
+    }
+
     pub fn html(content: &str) -> Self {
         Response::new()
             .with_header("Content-Type", "text/html")
@@ -61,6 +160,20 @@ impl Response {
             .with_body(&content)
     }
 
+    // Server-generated error page that quotes the request ID for support requests
+    pub fn error_page(status: u16, message: &str, request_id: &str) -> Self {
+        Response::new()
+            .with_status(status)
+            .with_header("Content-Type", "text/html")
+            .with_body(&format!(
+                "<html><body><h1>{} {}</h1><p>{}</p><p>Request ID: {}</p></body></html>",
+                status,
+                reason_phrase(status),
+                escape_html(message),
+                escape_html(request_id)
+            ))
+    }
+
     pub fn not_found() -> Self {
         Response::new()
             .with_status(404)
@@ -68,3 +181,93 @@ impl Response {
             .with_body("<html><body><h1>404 Not Found</h1><p>The requested resource could not be found.</p></body></html>")
     }
 }
+
+// Answer 304 Not Modified when the client already holds the current representation
+pub(crate) fn not_modified(request: &Request, response: Response) -> Response {
+    let etag = match response.headers.get("ETag") {
+        Some(etag) => etag,
+        None => return response,
+    };
+    if !if_none_match(request, etag) {
+        return response;
+    }
+
+    let mut not_modified = Response::new().with_status(304);
+    for name in ["ETag", "Last-Modified", "Vary", "Cache-Control"] {
+        if let Some(value) = response.headers.get(name) {
+            not_modified = not_modified.with_header(name, value);
+        }
+    }
+    not_modified
+}
+
+// Whether If-None-Match lists `etag` (compared weakly) or is `*`
+pub(crate) fn if_none_match(request: &Request, etag: &str) -> bool {
+    request.header("If-None-Match").is_some_and(|header| {
+        header.trim() == "*"
+            || header
+                .split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == etag)
+    })
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        426 => "Upgrade Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(version: &str, connection: Option<&str>) -> Request {
+        let mut request = Request { version: version.to_string(), ..Request::default() };
+        if let Some(connection) = connection {
+            request.headers.insert("connection".to_string(), connection.to_string());
+        }
+        request
+    }
+
+    #[test]
+    fn keep_alive_follows_version_and_connection_header() {
+        assert!(request("HTTP/1.1", None).keep_alive());
+        assert!(!request("HTTP/1.0", None).keep_alive());
+        assert!(!request("HTTP/1.1", Some("close")).keep_alive());
+        assert!(!request("HTTP/1.1", Some("Upgrade, Close")).keep_alive());
+        assert!(request("HTTP/1.0", Some("Keep-Alive")).keep_alive());
+        assert!(request("http/1.1", Some("upgrade")).keep_alive());
+        // close wins when a client sends both
+        assert!(!request("HTTP/1.0", Some("keep-alive, close")).keep_alive());
+    }
+}
@@ -0,0 +1,780 @@
+//WebSockets (RFC 6455)
+//`Router::websocket` routes answer the opening handshake with 101 Switching Protocols,
+//and the tokio engine then hands the connection to the handler as a `WebSocket`. It
+//deals in whole messages: fragments are reassembled, pings answered, limits enforced
+//and the closing handshake carried out. The threaded engine answers these routes with 501.
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::error::{HandlerResult, HsError};
+use crate::log_error;
+use crate::metrics;
+use crate::thread_pool::panic_message;
+use crate::types::{Request, Response, Upgrade, UpgradedIo};
+
+// Appended to the client's key to prove the server understood the handshake
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const VERSION: &str = "13";
+
+// Close codes
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_UNSUPPORTED: u16 = 1003;
+pub const CLOSE_INVALID_DATA: u16 = 1007;
+pub const CLOSE_POLICY_VIOLATION: u16 = 1008;
+pub const CLOSE_TOO_LARGE: u16 = 1009;
+pub const CLOSE_INTERNAL_ERROR: u16 = 1011;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+// Control frames carry at most this much
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+#[derive(Clone, Debug)]
+pub struct WebSocketConfig {
+    protocols: Vec<String>,
+    max_frame_size: usize,
+    max_message_size: usize,
+    close_timeout: Duration,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WebSocketConfig {
+    pub fn new() -> Self {
+        WebSocketConfig {
+            protocols: Vec::new(),
+            max_frame_size: 1024 * 1024,
+            max_message_size: 16 * 1024 * 1024,
+            close_timeout: Duration::from_secs(5),
+        }
+    }
+
+    // Subprotocols the handler speaks; the first one the client offers is chosen
+    pub fn with_protocols(mut self, protocols: &[&str]) -> Self {
+        self.protocols = protocols.iter().map(|p| p.to_string()).collect();
+        self
+    }
+
+    // Larger incoming frames close the connection with 1009; outgoing messages are
+    // split into frames of at most this size
+    pub fn with_max_frame_size(mut self, bytes: usize) -> Self {
+        self.max_frame_size = bytes.max(1);
+        self
+    }
+
+    // Largest message once its fragments are put together
+    pub fn with_max_message_size(mut self, bytes: usize) -> Self {
+        self.max_message_size = bytes;
+        self
+    }
+
+    // How long `close` waits for the client to answer the closing handshake
+    pub fn with_close_timeout(mut self, timeout: Duration) -> Self {
+        self.close_timeout = timeout;
+        self
+    }
+}
+
+// Runs a WebSocket connection; it ends when the returned future does
+pub type WebSocketHandler = Arc<dyn Fn(Request, WebSocket) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+// The handler half of a 101 response, for the engine to run once the response is written
+pub struct WebSocketUpgrade {
+    request: Request,
+    protocol: Option<String>,
+    config: WebSocketConfig,
+    handler: WebSocketHandler,
+}
+
+impl WebSocketUpgrade {
+    pub async fn run<S>(self, io: S)
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        self.serve(Box::new(io)).await
+    }
+
+    async fn serve(self, io: Box<dyn UpgradedIo>) {
+        let route = self.request.route.clone().unwrap_or_default();
+        let socket = WebSocket {
+            io: Some(io),
+            protocol: self.protocol,
+            config: self.config,
+            partial: None,
+            close_sent: false,
+            closed: false,
+        };
+        // On its own task, so a panicking handler costs only its own connection
+        if let Err(e) = tokio::spawn((self.handler)(self.request, socket)).await {
+            if e.is_panic() {
+                metrics::metrics().panics.inc(&["websocket", &route]);
+                log_error!("websocket handler for {} panicked: {}", route, panic_message(e.into_panic().as_ref()));
+            }
+        }
+    }
+}
+
+impl Upgrade for WebSocketUpgrade {
+    fn run(self: Box<Self>, io: Box<dyn UpgradedIo>) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(self.serve(io))
+    }
+}
+
+// Answer the opening handshake for a `Router::websocket` route
+pub(crate) fn accept(request: &Request, config: &WebSocketConfig, handler: &WebSocketHandler) -> HandlerResult {
+    let has_token = |value: &str, token: &str| value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token));
+    let upgrading = request.version.eq_ignore_ascii_case("HTTP/1.1")
+        && request.header("Upgrade").is_some_and(|value| has_token(value, "websocket"))
+        && request.header("Connection").is_some_and(|value| has_token(value, "upgrade"));
+    // Plain requests (and HTTP/2, which can't upgrade) are told what this endpoint wants
+    if !upgrading || request.header("Sec-WebSocket-Version") != Some(VERSION) {
+        return Ok(Response::text("This endpoint only accepts WebSocket connections.")
+            .with_status(426)
+            .with_header("Upgrade", "websocket")
+            .with_header("Sec-WebSocket-Version", VERSION));
+    }
+
+    let key = request.header("Sec-WebSocket-Key").unwrap_or("").trim();
+    if BASE64.decode(key).map_or(true, |nonce| nonce.len() != 16) {
+        return Err(HsError::BadRequest("Invalid Sec-WebSocket-Key header.".to_string()));
+    }
+    let protocol = request.header("Sec-WebSocket-Protocol").and_then(|offered| {
+        offered
+            .split(',')
+            .map(str::trim)
+            .find(|offer| config.protocols.iter().any(|p| p == offer))
+            .map(str::to_string)
+    });
+
+    let mut response = Response::new()
+        .with_status(101)
+        .with_header("Upgrade", "websocket")
+        .with_header("Connection", "Upgrade")
+        .with_header("Sec-WebSocket-Accept", &accept_key(key));
+    if let Some(protocol) = &protocol {
+        response = response.with_header("Sec-WebSocket-Protocol", protocol);
+    }
+    response.upgrade = Some(Box::new(WebSocketUpgrade {
+        request: request.clone(),
+        protocol,
+        config: config.clone(),
+        handler: Arc::clone(handler),
+    }));
+    Ok(response)
+}
+
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(ACCEPT_GUID.as_bytes());
+    BASE64.encode(sha1.finalize())
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    // Already answered with a pong by the time the handler sees it
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    // The client closed the connection; None when it gave no code
+    Close(Option<CloseFrame>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: u16,
+    pub reason: String,
+}
+
+#[derive(Debug)]
+pub enum WebSocketError {
+    Io(io::Error),
+    // The client broke the protocol, e.g. sent an unmasked frame
+    Protocol(&'static str),
+    InvalidUtf8,
+    FrameTooLarge,
+    MessageTooLarge,
+    // The closing handshake has started; nothing more can be sent
+    Closed,
+}
+
+impl WebSocketError {
+    // The code the connection is closed with; None when it can't be told
+    fn close_code(&self) -> Option<u16> {
+        match self {
+            WebSocketError::Protocol(_) => Some(CLOSE_PROTOCOL_ERROR),
+            WebSocketError::InvalidUtf8 => Some(CLOSE_INVALID_DATA),
+            WebSocketError::FrameTooLarge | WebSocketError::MessageTooLarge => Some(CLOSE_TOO_LARGE),
+            WebSocketError::Io(_) | WebSocketError::Closed => None,
+        }
+    }
+}
+
+impl fmt::Display for WebSocketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WebSocketError::Io(e) => write!(f, "{}", e),
+            WebSocketError::Protocol(message) => write!(f, "protocol error: {}", message),
+            WebSocketError::InvalidUtf8 => write!(f, "text is not valid UTF-8"),
+            WebSocketError::FrameTooLarge => write!(f, "frame too large"),
+            WebSocketError::MessageTooLarge => write!(f, "message too large"),
+            WebSocketError::Closed => write!(f, "connection is closing"),
+        }
+    }
+}
+
+impl std::error::Error for WebSocketError {}
+
+impl From<io::Error> for WebSocketError {
+    fn from(e: io::Error) -> Self {
+        WebSocketError::Io(e)
+    }
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// A server-side WebSocket connection
+pub struct WebSocket {
+    // None once the connection has been shut down
+    io: Option<Box<dyn UpgradedIo>>,
+    protocol: Option<String>,
+    config: WebSocketConfig,
+    // Opcode and data so far of a fragmented message
+    partial: Option<(u8, Vec<u8>)>,
+    close_sent: bool,
+    // Nothing more will be received
+    closed: bool,
+}
+
+impl WebSocket {
+    // The subprotocol agreed on in the handshake
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    // The next message; None once the connection is closed. A client that breaks the
+    // protocol or a limit gets a close frame with the matching code and an error here.
+    pub async fn recv(&mut self) -> Option<Result<Message, WebSocketError>> {
+        if self.closed {
+            return None;
+        }
+        match self.next_message().await {
+            Ok(message) => Some(Ok(message)),
+            Err(e) => {
+                if let (Some(code), false) = (e.close_code(), self.close_sent) {
+                    let _ = self.send_close(Some(code), &e.to_string()).await;
+                }
+                self.closed = true;
+                self.shutdown().await;
+                Some(Err(e))
+            }
+        }
+    }
+
+    // Text and binary messages larger than the frame size go out in fragments
+    pub async fn send(&mut self, message: Message) -> Result<(), WebSocketError> {
+        if self.close_sent {
+            return Err(WebSocketError::Closed);
+        }
+        match message {
+            Message::Text(text) => self.send_data(OP_TEXT, text.as_bytes()).await,
+            Message::Binary(data) => self.send_data(OP_BINARY, &data).await,
+            Message::Ping(data) => self.send_control(OP_PING, &data).await,
+            Message::Pong(data) => self.send_control(OP_PONG, &data).await,
+            Message::Close(frame) => match frame {
+                Some(frame) => self.send_close(Some(frame.code), &frame.reason).await,
+                None => self.send_close(None, "").await,
+            },
+        }
+    }
+
+    // Start the closing handshake and wait (up to the close timeout) for the client
+    // to finish it; messages still arriving in the meantime are dropped
+    pub async fn close(&mut self, code: u16, reason: &str) -> Result<(), WebSocketError> {
+        if !self.close_sent {
+            self.send_close(Some(code), reason).await?;
+        }
+        let timeout = self.config.close_timeout;
+        let answered = async {
+            while let Some(Ok(message)) = self.recv().await {
+                if let Message::Close(_) = message {
+                    break;
+                }
+            }
+        };
+        let _ = tokio::time::timeout(timeout, answered).await;
+        self.closed = true;
+        self.shutdown().await;
+        Ok(())
+    }
+
+    async fn next_message(&mut self) -> Result<Message, WebSocketError> {
+        loop {
+            let frame = self.read_frame().await?;
+            match frame.opcode {
+                OP_CLOSE => {
+                    let close = parse_close(&frame.payload)?;
+                    // Echo the code back, as the closing handshake asks
+                    if !self.close_sent {
+                        self.send_close(close.as_ref().map(|c| c.code), "").await?;
+                    }
+                    self.closed = true;
+                    self.shutdown().await;
+                    return Ok(Message::Close(close));
+                }
+                OP_PING => {
+                    if !self.close_sent {
+                        self.write_frame(true, OP_PONG, &frame.payload).await?;
+                    }
+                    return Ok(Message::Ping(frame.payload));
+                }
+                OP_PONG => return Ok(Message::Pong(frame.payload)),
+                OP_CONTINUATION => {
+                    let Some((_, data)) = self.partial.as_mut() else {
+                        return Err(WebSocketError::Protocol("continuation frame with no message to continue"));
+                    };
+                    if data.len() + frame.payload.len() > self.config.max_message_size {
+                        return Err(WebSocketError::MessageTooLarge);
+                    }
+                    data.extend_from_slice(&frame.payload);
+                    if frame.fin {
+                        let (opcode, data) = self.partial.take().unwrap_or_default();
+                        return into_message(opcode, data);
+                    }
+                }
+                OP_TEXT | OP_BINARY => {
+                    if self.partial.is_some() {
+                        return Err(WebSocketError::Protocol("new message before the previous one was finished"));
+                    }
+                    if frame.payload.len() > self.config.max_message_size {
+                        return Err(WebSocketError::MessageTooLarge);
+                    }
+                    if frame.fin {
+                        return into_message(frame.opcode, frame.payload);
+                    }
+                    self.partial = Some((frame.opcode, frame.payload));
+                }
+                _ => return Err(WebSocketError::Protocol("unknown opcode")),
+            }
+        }
+    }
+
+    async fn read_frame(&mut self) -> Result<Frame, WebSocketError> {
+        let max_frame_size = self.config.max_frame_size as u64;
+        let io = self.io.as_mut().ok_or(WebSocketError::Closed)?;
+        let mut head = [0; 2];
+        io.read_exact(&mut head).await?;
+        let (fin, opcode) = (head[0] & 0x80 != 0, head[0] & 0x0F);
+        // No extensions are negotiated, so no reserved bit may be set
+        if head[0] & 0x70 != 0 {
+            return Err(WebSocketError::Protocol("reserved bits set"));
+        }
+        if head[1] & 0x80 == 0 {
+            return Err(WebSocketError::Protocol("client frames must be masked"));
+        }
+        let len = match head[1] & 0x7F {
+            126 => u64::from(io.read_u16().await?),
+            127 => io.read_u64().await?,
+            len => u64::from(len),
+        };
+        if opcode >= OP_CLOSE && (!fin || len > MAX_CONTROL_PAYLOAD as u64) {
+            return Err(WebSocketError::Protocol("control frames must be whole and at most 125 bytes"));
+        }
+        if len > max_frame_size {
+            return Err(WebSocketError::FrameTooLarge);
+        }
+
+        let mut mask = [0; 4];
+        io.read_exact(&mut mask).await?;
+        let mut payload = vec![0; len as usize];
+        io.read_exact(&mut payload).await?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok(Frame { fin, opcode, payload })
+    }
+
+    async fn send_data(&mut self, opcode: u8, data: &[u8]) -> Result<(), WebSocketError> {
+        if data.is_empty() {
+            return self.write_frame(true, opcode, data).await;
+        }
+        let mut chunks = data.chunks(self.config.max_frame_size).peekable();
+        let mut opcode = opcode;
+        while let Some(chunk) = chunks.next() {
+            self.write_frame(chunks.peek().is_none(), opcode, chunk).await?;
+            opcode = OP_CONTINUATION;
+        }
+        Ok(())
+    }
+
+    async fn send_control(&mut self, opcode: u8, data: &[u8]) -> Result<(), WebSocketError> {
+        if data.len() > MAX_CONTROL_PAYLOAD {
+            return Err(WebSocketError::Protocol("control frame payload over 125 bytes"));
+        }
+        self.write_frame(true, opcode, data).await
+    }
+
+    async fn send_close(&mut self, code: Option<u16>, reason: &str) -> Result<(), WebSocketError> {
+        self.close_sent = true;
+        let payload = code.map_or_else(Vec::new, |code| close_payload(code, reason));
+        self.write_frame(true, OP_CLOSE, &payload).await
+    }
+
+    async fn write_frame(&mut self, fin: bool, opcode: u8, payload: &[u8]) -> Result<(), WebSocketError> {
+        let io = self.io.as_mut().ok_or(WebSocketError::Closed)?;
+        io.write_all(&encode_frame(fin, opcode, payload)).await?;
+        io.flush().await?;
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) {
+        if let Some(mut io) = self.io.take() {
+            let _ = io.shutdown().await;
+        }
+    }
+}
+
+impl Drop for WebSocket {
+    // A handler that returns without closing still closes cleanly, as far as that can
+    // be done without waiting for the client
+    fn drop(&mut self) {
+        let (Some(mut io), false) = (self.io.take(), self.close_sent) else { return };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else { return };
+        runtime.spawn(async move {
+            let frame = encode_frame(true, OP_CLOSE, &CLOSE_NORMAL.to_be_bytes());
+            let _ = tokio::time::timeout(Duration::from_secs(1), async {
+                io.write_all(&frame).await?;
+                io.shutdown().await
+            })
+            .await;
+        });
+    }
+}
+
+// Server frames go out unmasked
+fn encode_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(if fin { 0x80 } else { 0 } | opcode);
+    match payload.len() {
+        len if len <= MAX_CONTROL_PAYLOAD => frame.push(len as u8),
+        len if len <= usize::from(u16::MAX) => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn into_message(opcode: u8, data: Vec<u8>) -> Result<Message, WebSocketError> {
+    match opcode {
+        OP_TEXT => String::from_utf8(data).map(Message::Text).map_err(|_| WebSocketError::InvalidUtf8),
+        _ => Ok(Message::Binary(data)),
+    }
+}
+
+fn parse_close(payload: &[u8]) -> Result<Option<CloseFrame>, WebSocketError> {
+    let (code, reason) = match payload {
+        [] => return Ok(None),
+        [high, low, reason @ ..] => (u16::from_be_bytes([*high, *low]), reason),
+        [_] => return Err(WebSocketError::Protocol("truncated close code")),
+    };
+    // Codes a client may send: the defined ones in 1000-1011, plus the registered
+    // (3000-3999) and private (4000-4999) ranges
+    if !matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999) {
+        return Err(WebSocketError::Protocol("invalid close code"));
+    }
+    let reason = String::from_utf8(reason.to_vec()).map_err(|_| WebSocketError::InvalidUtf8)?;
+    Ok(Some(CloseFrame { code, reason }))
+}
+
+fn close_payload(code: u16, reason: &str) -> Vec<u8> {
+    // The reason shares the 125 control bytes with the code; cut it on a character boundary
+    let mut end = reason.len().min(MAX_CONTROL_PAYLOAD - 2);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(&reason.as_bytes()[..end]);
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+
+    fn handler() -> WebSocketHandler {
+        Arc::new(|_request, _socket| Box::pin(async {}))
+    }
+
+    fn handshake(headers: &[(&str, &str)]) -> Request {
+        Request {
+            method: "GET".to_string(),
+            path: "/ws".to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers: headers.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            ..Request::default()
+        }
+    }
+
+    const UPGRADE: &[(&str, &str)] = &[
+        ("Upgrade", "websocket"),
+        ("Connection", "keep-alive, Upgrade"),
+        ("Sec-WebSocket-Version", "13"),
+        ("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="),
+    ];
+
+    // A server socket and the client's end of the connection
+    fn socket(config: WebSocketConfig) -> (WebSocket, DuplexStream) {
+        let (client, server) = duplex(1 << 20);
+        let socket = WebSocket {
+            io: Some(Box::new(server)),
+            protocol: None,
+            config,
+            partial: None,
+            close_sent: false,
+            closed: false,
+        };
+        (socket, client)
+    }
+
+    // A masked client frame, with the length in the form `len_form` asks for
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8], len_form: u8) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![if fin { 0x80 } else { 0 } | opcode];
+        match len_form {
+            126 => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+            }
+            127 => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+            }
+            _ => frame.push(0x80 | payload.len() as u8),
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        frame
+    }
+
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        client_frame(fin, opcode, payload, 0)
+    }
+
+    // The next frame the server sent: (fin, opcode, payload)
+    async fn server_frame(client: &mut DuplexStream) -> (bool, u8, Vec<u8>) {
+        let mut head = [0; 2];
+        client.read_exact(&mut head).await.unwrap();
+        assert_eq!(head[1] & 0x80, 0, "server frames are unmasked");
+        let len = match head[1] & 0x7F {
+            126 => u64::from(client.read_u16().await.unwrap()),
+            127 => client.read_u64().await.unwrap(),
+            len => u64::from(len),
+        };
+        let mut payload = vec![0; len as usize];
+        client.read_exact(&mut payload).await.unwrap();
+        (head[0] & 0x80 != 0, head[0] & 0x0F, payload)
+    }
+
+    async fn expect_close(client: &mut DuplexStream, code: u16) {
+        let (fin, opcode, payload) = server_frame(client).await;
+        assert!(fin);
+        assert_eq!(opcode, OP_CLOSE);
+        assert_eq!(payload[..2], code.to_be_bytes());
+    }
+
+    #[test]
+    fn accept_key_matches_the_rfc_sample() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn plain_requests_get_426() {
+        let response = accept(&handshake(&[]), &WebSocketConfig::new(), &handler()).unwrap();
+        assert_eq!(response.status, 426);
+        assert_eq!(response.headers.get("Upgrade").map(String::as_str), Some("websocket"));
+        assert_eq!(response.headers.get("Sec-WebSocket-Version").map(String::as_str), Some("13"));
+        assert!(response.upgrade.is_none());
+
+        let mut old_version = UPGRADE.to_vec();
+        old_version[2] = ("Sec-WebSocket-Version", "8");
+        let response = accept(&handshake(&old_version), &WebSocketConfig::new(), &handler()).unwrap();
+        assert_eq!(response.status, 426);
+    }
+
+    #[test]
+    fn bad_keys_get_400() {
+        for key in ["", "not base64!", "c2hvcnQ="] {
+            let mut headers = UPGRADE.to_vec();
+            headers[3] = ("Sec-WebSocket-Key", key);
+            let result = accept(&handshake(&headers), &WebSocketConfig::new(), &handler());
+            assert!(matches!(result, Err(HsError::BadRequest(_))), "{:?}", key);
+        }
+    }
+
+    #[test]
+    fn handshake_switches_protocols() {
+        let mut headers = UPGRADE.to_vec();
+        headers.push(("Sec-WebSocket-Protocol", "soap, superchat, chat"));
+        let config = WebSocketConfig::new().with_protocols(&["chat", "superchat"]);
+        let response = accept(&handshake(&headers), &config, &handler()).unwrap();
+        assert_eq!(response.status, 101);
+        assert_eq!(
+            response.headers.get("Sec-WebSocket-Accept").map(String::as_str),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+        );
+        // The client's first offer that the handler speaks
+        assert_eq!(response.headers.get("Sec-WebSocket-Protocol").map(String::as_str), Some("superchat"));
+        assert!(response.upgrade.is_some());
+
+        let response = accept(&handshake(UPGRADE), &config, &handler()).unwrap();
+        assert!(!response.headers.contains_key("Sec-WebSocket-Protocol"));
+    }
+
+    #[tokio::test]
+    async fn unmasked_frames_close_with_1002() {
+        let (mut socket, mut client) = socket(WebSocketConfig::new());
+        client.write_all(&[0x81, 0x02, b'h', b'i']).await.unwrap();
+        assert!(matches!(socket.recv().await, Some(Err(WebSocketError::Protocol(_)))));
+        expect_close(&mut client, CLOSE_PROTOCOL_ERROR).await;
+        assert!(socket.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn reserved_bits_close_with_1002() {
+        let (mut socket, mut client) = socket(WebSocketConfig::new());
+        let mut bad = frame(true, OP_TEXT, b"hi");
+        bad[0] |= 0x40;
+        client.write_all(&bad).await.unwrap();
+        assert!(matches!(socket.recv().await, Some(Err(WebSocketError::Protocol(_)))));
+        expect_close(&mut client, CLOSE_PROTOCOL_ERROR).await;
+    }
+
+    #[tokio::test]
+    async fn extended_lengths_are_decoded() {
+        let (mut socket, mut client) = socket(WebSocketConfig::new());
+        let medium = vec![7; 300];
+        let large = vec![9; 70_000];
+        client.write_all(&client_frame(true, OP_BINARY, &medium, 126)).await.unwrap();
+        client.write_all(&client_frame(true, OP_BINARY, &large, 127)).await.unwrap();
+        assert_eq!(socket.recv().await.unwrap().unwrap(), Message::Binary(medium));
+        assert_eq!(socket.recv().await.unwrap().unwrap(), Message::Binary(large.clone()));
+
+        // And sent in the same forms
+        socket.send(Message::Binary(large.clone())).await.unwrap();
+        assert_eq!(server_frame(&mut client).await, (true, OP_BINARY, large));
+    }
+
+    #[tokio::test]
+    async fn fragments_are_reassembled_around_a_ping() {
+        let (mut socket, mut client) = socket(WebSocketConfig::new());
+        client.write_all(&frame(false, OP_TEXT, b"Hel")).await.unwrap();
+        client.write_all(&frame(true, OP_PING, b"are you there")).await.unwrap();
+        client.write_all(&frame(false, OP_CONTINUATION, b"lo, ")).await.unwrap();
+        client.write_all(&frame(true, OP_CONTINUATION, b"world")).await.unwrap();
+
+        assert_eq!(socket.recv().await.unwrap().unwrap(), Message::Ping(b"are you there".to_vec()));
+        assert_eq!(server_frame(&mut client).await, (true, OP_PONG, b"are you there".to_vec()));
+        assert_eq!(socket.recv().await.unwrap().unwrap(), Message::Text("Hello, world".to_string()));
+    }
+
+    #[tokio::test]
+    async fn stray_continuation_closes_with_1002() {
+        let (mut socket, mut client) = socket(WebSocketConfig::new());
+        client.write_all(&frame(true, OP_CONTINUATION, b"x")).await.unwrap();
+        assert!(matches!(socket.recv().await, Some(Err(WebSocketError::Protocol(_)))));
+        expect_close(&mut client, CLOSE_PROTOCOL_ERROR).await;
+    }
+
+    #[tokio::test]
+    async fn invalid_utf8_closes_with_1007() {
+        let (mut socket, mut client) = socket(WebSocketConfig::new());
+        client.write_all(&frame(true, OP_TEXT, &[0xff, 0xfe])).await.unwrap();
+        assert!(matches!(socket.recv().await, Some(Err(WebSocketError::InvalidUtf8))));
+        expect_close(&mut client, CLOSE_INVALID_DATA).await;
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_close_with_1009() {
+        let (mut socket, mut client) = socket(WebSocketConfig::new().with_max_frame_size(10));
+        client.write_all(&frame(true, OP_BINARY, &[0; 11])).await.unwrap();
+        assert!(matches!(socket.recv().await, Some(Err(WebSocketError::FrameTooLarge))));
+        expect_close(&mut client, CLOSE_TOO_LARGE).await;
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_close_with_1009() {
+        let (mut socket, mut client) = socket(WebSocketConfig::new().with_max_message_size(10));
+        client.write_all(&frame(false, OP_BINARY, &[0; 6])).await.unwrap();
+        client.write_all(&frame(true, OP_CONTINUATION, &[0; 6])).await.unwrap();
+        assert!(matches!(socket.recv().await, Some(Err(WebSocketError::MessageTooLarge))));
+        expect_close(&mut client, CLOSE_TOO_LARGE).await;
+    }
+
+    #[tokio::test]
+    async fn close_is_echoed() {
+        let (mut socket, mut client) = socket(WebSocketConfig::new());
+        let mut payload = 1001u16.to_be_bytes().to_vec();
+        payload.extend_from_slice(b"bye");
+        client.write_all(&frame(true, OP_CLOSE, &payload)).await.unwrap();
+        let close = CloseFrame { code: 1001, reason: "bye".to_string() };
+        assert_eq!(socket.recv().await.unwrap().unwrap(), Message::Close(Some(close)));
+        expect_close(&mut client, 1001).await;
+        assert!(socket.recv().await.is_none());
+        assert!(matches!(socket.send(Message::Text("late".to_string())).await, Err(WebSocketError::Closed)));
+    }
+
+    #[test]
+    fn close_codes_are_validated() {
+        assert_eq!(parse_close(&[]).unwrap(), None);
+        for code in [1000, 1003, 1007, 1011, 3000, 4999] {
+            let close = parse_close(&u16::to_be_bytes(code)).unwrap().unwrap();
+            assert_eq!(close.code, code);
+        }
+        // 1005 and 1006 are never sent on the wire; 1004 and 1012+ are reserved
+        for code in [0, 999, 1004, 1005, 1006, 1012, 2999, 5000] {
+            assert!(matches!(parse_close(&u16::to_be_bytes(code)), Err(WebSocketError::Protocol(_))), "{}", code);
+        }
+        assert!(matches!(parse_close(&[0x03]), Err(WebSocketError::Protocol(_))));
+        assert!(matches!(parse_close(&[0x03, 0xe8, 0xff]), Err(WebSocketError::InvalidUtf8)));
+    }
+
+    #[test]
+    fn close_reasons_are_cut_on_a_character_boundary() {
+        let payload = close_payload(CLOSE_NORMAL, &"é".repeat(100));
+        assert!(payload.len() <= MAX_CONTROL_PAYLOAD);
+        assert!(std::str::from_utf8(&payload[2..]).is_ok());
+    }
+}